@@ -0,0 +1,4 @@
+// the hardware names (CPU, PPU, NTSC...) are kept all caps
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod nes;
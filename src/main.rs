@@ -1,25 +1,186 @@
-pub mod nes;
+use sentiw::nes::NES;
+use sentiw::nes::{ debugger, gdb };
+use sentiw::nes::profiler::Profiler;
+use sentiw::nes::bus::FaultPolicy;
+use sentiw::nes::cpu::{ CPU, Variant };
+use sentiw::nes::ines::{ INesRom, Error };
+use sentiw::nes::harness::{ self, FlatRam };
+use std::fs::{ self, File };
+use std::io;
 
-use nes::NES;
-use nes::ines::{ INesRom, Error };
-use std::fs::{ File };
+const USAGE: &str = "\
+usage: sentiw [options]
+  --cpu 2a03|6502|65c02        the chip for --json-tests and --flat
+  --faults ignore|log|trap|error
+                               what to do when the game touches something
+                               that isn't there, log by default
+  --trace                      a nestest.log style line per instruction
+  --cdl file                   log code and data into file
+  --gdb [port]                 wait for gdb, on port 6502 by default
+  --debug                      the debugger
+  --profile [frames] [--budgets]
+                               run 600 frames or so and show where the
+                               time went
+  --json-tests file...         run per-opcode test files on a bare cpu
+  --flat image.bin start       run a 64K image from start (hex) until it traps";
 
-fn main()
+// what there is to do besides running the game
+enum Mode
 {
+    Run,
+    JsonTests(Vec<String>),
+    Flat(String, String),
+}
 
-    let mut romfile = File::open("tetris.nes")
-        .expect("failed to open rom file");
+// the whole command line, read once
+struct Options
+{
+    mode: Mode,
+    cpu: Variant, // the nes itself always gets a 2a03, this is for the bare cpu modes
+    faults: FaultPolicy,
+    trace: bool,
+    cdl: Option<String>,
+    gdb: Option<String>,
+    debug: bool,
+    profile: Option<u64>,
+    budgets: bool,
+}
 
-    let ines = match INesRom::new(&mut romfile)
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String>
+{
+    let mut options = Options {
+        mode: Mode::Run,
+        cpu: Variant::Ricoh2A03,
+        faults: FaultPolicy::Log,
+        trace: false,
+        cdl: None,
+        gdb: None,
+        debug: false,
+        profile: None,
+        budgets: false,
+    };
+
+    let mut args = args.peekable();
+    while let Some(arg) = args.next()
     {
-        Ok(rom) => rom,
-        Err(e) => match e
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str()
         {
-            Error::MapperNotSupported => panic!("This mapper is not supported yet"),
-            Error::HeaderNotFound => panic!("Header data was not found"),
-            other => panic!("{:?}", other), 
+            "--cpu" => options.cpu = match value()?.to_lowercase().as_str()
+            {
+                "2a03" => Variant::Ricoh2A03,
+                "6502" => Variant::Nmos6502,
+                "65c02" => Variant::Cmos65C02,
+                other => return Err(format!("unknown cpu '{}'", other)),
+            },
+            "--faults" => options.faults = match value()?.as_str()
+            {
+                "ignore" => FaultPolicy::Ignore,
+                "log" => FaultPolicy::Log,
+                "trap" => FaultPolicy::Trap,
+                "error" => FaultPolicy::Error,
+                other => return Err(format!("unknown fault policy '{}'", other)),
+            },
+            "--trace" => options.trace = true,
+            "--cdl" => options.cdl = Some(value()?),
+            "--gdb" =>
+            {
+                let port = args.next_if(|arg| !arg.starts_with("--"));
+                options.gdb = Some(port.unwrap_or(String::from("6502")));
+            }
+            "--debug" => options.debug = true,
+            "--profile" =>
+            {
+                let frames = args.next_if(|arg| arg.parse::<u64>().is_ok())
+                    .and_then(|n| n.parse().ok());
+                options.profile = Some(frames.unwrap_or(600));
+            }
+            "--budgets" => options.budgets = true,
+            "--json-tests" =>
+            {
+                let mut files = Vec::new();
+                while let Some(file) = args.next_if(|arg| !arg.starts_with("--"))
+                {
+                    files.push(file);
+                }
+                options.mode = Mode::JsonTests(files);
+            }
+            "--flat" =>
+            {
+                let image = value()?;
+                let start = args.next().ok_or("--flat needs an image and a start address")?;
+                options.mode = Mode::Flat(image, start);
+            }
+            other => return Err(format!("unknown option '{}'", other)),
         }
-    };
+    }
+    Ok(options)
+}
+
+// --json-tests file...
+// runs per-opcode test files (Tom Harte's format) on a bare cpu
+fn json_tests(files: &[String], variant: Variant) -> Result<(), String>
+{
+    for file in files
+    {
+        let text = fs::read_to_string(file)
+            .map_err(|e| format!("can't read {}: {}", file, e))?;
+        let report = harness::run_json_tests(&text, variant)
+            .map_err(|e| format!("{} isn't a test file: {}", file, e))?;
+
+        println!("{}: {} passed, {} failed",
+                 file, report.passed, report.failed.len());
+        for (name, reason) in report.failed.iter().take(5)
+        {
+            println!("  {}: {}", name, reason);
+        }
+    }
+    Ok(())
+}
+
+// --flat image.bin start
+// loads a 64K image and runs it from start (hex) until it traps
+fn flat(image: &str, start: &str, variant: Variant) -> Result<(), String>
+{
+    let image = fs::read(image)
+        .map_err(|e| format!("can't read {}: {}", image, e))?;
+    let start = u16::from_str_radix(start.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("start should be a hex address, not '{}'", start))?;
+
+    let mut ram = FlatRam::new();
+    ram.load(0x0000, &image);
+    let mut cpu = CPU::with_variant(variant);
+    cpu.pc = start;
+
+    let stop = harness::run_until_trap(&mut cpu, &mut ram, 1_000_000_000);
+    println!("{:?} after {} cycles", stop, cpu.cycles);
+    Ok(())
+}
+
+fn load_rom(path: &str) -> Result<INesRom, String>
+{
+    let mut romfile = File::open(path)
+        .map_err(|e| format!("can't open {}: {}", path, e))?;
+
+    INesRom::new(&mut romfile).map_err(|e| match e
+    {
+        Error::ReadError(e) => format!("can't read {}: {}", path, e),
+        Error::MapperNotSupported => format!("{} uses a mapper that isn't supported yet", path),
+        Error::HeaderNotFound => format!("{} has no iNES header", path),
+        other => format!("{} is broken: {:?}", path, other),
+    })
+}
+
+fn run(options: Options) -> Result<(), String>
+{
+    match options.mode
+    {
+        Mode::JsonTests(files) => return json_tests(&files, options.cpu),
+        Mode::Flat(image, start) => return flat(&image, &start, options.cpu),
+        Mode::Run => (),
+    }
+
+    let ines = load_rom("tetris.nes")?;
 
     println!("Rom loaded from file");
     println!("{:?}\n", ines);
@@ -27,6 +188,200 @@ fn main()
     let mut nes = NES::new();
     //nes.pre_setup();
     nes.load_cart(ines);
-    nes.run();
+    nes.set_fault_policy(options.faults);
+    if options.trace
+    {
+        nes.trace_to(Box::new(io::stdout()));
+    }
+
+    // --cdl file, marks code and data in there while running, on top
+    // of what an earlier run already found. written when the run ends
+    let cdl = options.cdl;
+    if let Some(path) = &cdl
+    {
+        nes.log_code_data();
+        if let (Ok(old), Some(log)) = (fs::read(path), nes.cdl_mut())
+        {
+            if !log.merge(&old)
+            {
+                println!("{} is for another rom, starting over", path);
+            }
+        }
+    }
+    let save_cdl = |nes: &NES|
+    {
+        match (&cdl, nes.cdl())
+        {
+            (Some(path), Some(log)) => log.save(path)
+                .map_err(|e| format!("can't write the code/data log to {}: {}", path, e)),
+            _ => Ok(()),
+        }
+    };
+    if let Some(port) = options.gdb
+    {
+        nes.reset();
+        println!("waiting for gdb on port {}", port);
+        return gdb::serve(&mut nes, format!("127.0.0.1:{}", port))
+            .map_err(|e| format!("gdb connection failed: {}", e));
+    }
+    if options.debug
+    {
+        // a snapshot every frame, 32M is minutes of history
+        nes.enable_rewind(1, 32 << 20);
+        nes.reset();
+        debugger::repl::run(&mut nes, io::stdin().lock(), io::stdout())
+            .map_err(|e| format!("debugger failed: {}", e))?;
+        return save_cdl(&nes);
+    }
+    // --profile frames [--budgets], runs that many frames and
+    // prints where the time went
+    if let Some(frames) = options.profile
+    {
+        let profiler = if options.budgets
+            { Profiler::with_budgets() }
+        else
+            { Profiler::new() };
+        nes.profile(profiler);
+        nes.reset();
+        while nes.frame() < frames
+        {
+            if let Err(fault) = nes.step()
+            {
+                println!("stopped at frame {}: {}", nes.frame(), fault);
+                break;
+            }
+        }
+        nes.profile_report(&mut io::stdout(), 20)
+            .map_err(|e| format!("can't write the report: {}", e))?;
+        return save_cdl(&nes);
+    }
+    nes.reset();
+    loop
+    {
+        if let Err(fault) = nes.step()
+        {
+            println!("{}\n{}", fault, nes.cpu());
+            break;
+        }
+        // the trace going nowhere, like when it's piped into head,
+        // is where a traced run ends
+        if let Some(e) = nes.trace_error()
+        {
+            eprintln!("trace stopped: {}", e);
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn main()
+{
+    let options = match parse_args(std::env::args().skip(1))
+    {
+        Ok(options) => options,
+        Err(e) =>
+        {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(options)
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn parse(line: &str) -> Result<Options, String>
+    {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn flags_in_any_order()
+    {
+        let options = parse("--profile 60 --budgets --faults trap --cpu 65C02 --gdb").unwrap();
+        assert_eq!(options.profile, Some(60));
+        assert!(options.budgets);
+        assert!(matches!(options.faults, FaultPolicy::Trap));
+        assert_eq!(options.cpu, Variant::Cmos65C02);
+        assert_eq!(options.gdb.as_deref(), Some("6502"));
+
+        let options = parse("--gdb 1234 --profile --cdl out.cdl").unwrap();
+        assert_eq!(options.gdb.as_deref(), Some("1234"));
+        assert_eq!(options.profile, Some(600));
+        assert_eq!(options.cdl.as_deref(), Some("out.cdl"));
+        assert!(matches!(options.mode, Mode::Run));
+    }
+
+    #[test]
+    fn modes()
+    {
+        let options = parse("--json-tests a.json b.json --cpu 6502").unwrap();
+        assert!(matches!(&options.mode, Mode::JsonTests(files) if files == &["a.json", "b.json"]));
+        assert_eq!(options.cpu, Variant::Nmos6502);
+
+        let options = parse("--cpu 6502 --flat image.bin 0400").unwrap();
+        assert!(matches!(&options.mode, Mode::Flat(image, start) if image == "image.bin" && start == "0400"));
+    }
+
+    #[test]
+    fn bad_command_lines()
+    {
+        assert_eq!(parse("--cpu bogus").err().unwrap(), "unknown cpu 'bogus'");
+        assert_eq!(parse("--faults loud").err().unwrap(), "unknown fault policy 'loud'");
+        assert_eq!(parse("--trace --cpu").err().unwrap(), "--cpu needs a value");
+        assert!(parse("--flat image.bin").is_err());
+        assert!(parse("--frobnicate").is_err());
+    }
+
+    // a file in the temp directory with contents, gone again when dropped
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile
+    {
+        fn new(name: &str, contents: &[u8]) -> TempFile
+        {
+            let path = std::env::temp_dir().join(format!("sentiw-{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str
+        {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile
+    {
+        fn drop(&mut self)
+        {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn bad_files_are_errors()
+    {
+        let missing = "/nonexistent/sentiw";
+        assert!(json_tests(&[missing.to_string()], Variant::Nmos6502).unwrap_err().starts_with("can't read"));
+        assert!(flat(missing, "0400", Variant::Nmos6502).unwrap_err().starts_with("can't read"));
+        assert!(load_rom(missing).unwrap_err().starts_with("can't open"));
+
+        let junk = TempFile::new("junk", b"not json, not a rom");
+        assert!(json_tests(&[junk.path().to_string()], Variant::Nmos6502).unwrap_err().contains("isn't a test file"));
+        assert!(flat(junk.path(), "zz", Variant::Nmos6502).unwrap_err().contains("hex address"));
+        assert!(load_rom(junk.path()).unwrap_err().contains("no iNES header"));
 
+        let short = TempFile::new("short", b"NES");
+        assert!(load_rom(short.path()).unwrap_err().contains("no iNES header"));
+        let mmc3 = TempFile::new("mmc3", b"NES\x1a\x01\x01\x40\0\0\0\0\0\0\0\0\0");
+        assert!(load_rom(mmc3.path()).unwrap_err().contains("mapper"));
+    }
 }
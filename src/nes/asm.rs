@@ -0,0 +1,684 @@
+// A small two pass 6502 assembler, so cpu tests and rom patches can be
+// written as text instead of hand encoded hex. It knows about labels,
+// constants (NAME = expr), .org, .byte and .word, and every addressing
+// mode the decode table has. Numbers are $hex, %binary or decimal and
+// expressions can add and subtract, take the <low or >high byte of
+// something, or use * for the current address.
+//
+//         .org $8000
+// reset:  ldx #$00
+// loop:   lda message,x
+//         beq done
+//         sta $0200,x
+//         inx
+//         bne loop
+// done:   jmp done
+// message: .byte $48, $49, 0
+
+use crate::nes::cpu::{ AddrMode, OPCODES };
+use crate::nes::bus::Bus;
+
+use std::collections::{ HashMap, HashSet };
+
+#[derive(Debug)]
+pub enum Error
+{
+    // all of these carry the line number (starting from 1)
+    UnknownMnemonic(usize, String),
+    UnknownDirective(usize, String),
+    BadOperand(usize, String),
+    BadNumber(usize, String),
+    UndefinedLabel(usize, String),
+    DuplicateLabel(usize, String),
+    BranchOutOfRange(usize, i32),
+    ByteOutOfRange(usize, u16), // a .byte that doesn't fit in one
+
+    // patching an address that isn't in the buffer
+    OutOfBounds(u16),
+}
+
+// a run of bytes that goes at addr
+#[derive(Debug)]
+pub struct Chunk
+{
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Assembly
+{
+    pub chunks: Vec<Chunk>,
+    pub labels: HashMap<String, u16>,
+}
+
+impl Assembly
+{
+    pub fn label(&self, name: &str) -> Option<u16>
+    {
+        self.labels.get(name).copied()
+    }
+
+    // writes every chunk to the address it was assembled for
+    pub fn load(&self, bus: &mut dyn Bus)
+    {
+        for chunk in &self.chunks
+        {
+            for (i, byte) in chunk.bytes.iter().enumerate()
+            {
+                bus.write(chunk.addr.wrapping_add(i as u16), *byte);
+            }
+        }
+    }
+
+    // copies everything into buf, which is seen by the cpu at base.
+    // e.g. INesRom::prg_bank_mut(1) for the bank mapped at $c000
+    pub fn patch(&self, buf: &mut [u8], base: u16) -> Result<(), Error>
+    {
+        for chunk in &self.chunks
+        {
+            for (i, byte) in chunk.bytes.iter().enumerate()
+            {
+                let addr = chunk.addr.wrapping_add(i as u16);
+                match buf.get_mut(addr.wrapping_sub(base) as usize)
+                {
+                    Some(b) => *b = *byte,
+                    None => return Err(Error::OutOfBounds(addr)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, Error>
+{
+    let mut asm = Assembler {
+        labels: HashMap::new(),
+        defined: HashSet::new(),
+        unresolved: 0,
+        modes: Vec::new(),
+        chunks: Vec::new(),
+        pc: 0,
+        line: 0,
+        final_pass: false,
+    };
+
+    // the first pass only figures out where the labels are. a constant
+    // made from a label further down only gets its value on the next
+    // one, and one made from that constant on the one after, so there
+    // are more passes for as long as that gets anywhere. the last one
+    // has everything and emits the bytes
+    asm.pass(source)?;
+    let mut unresolved = usize::MAX;
+    while asm.unresolved != 0 && asm.unresolved < unresolved
+    {
+        unresolved = asm.unresolved;
+        asm.pass(source)?;
+    }
+    asm.final_pass = true;
+    asm.pass(source)?;
+
+    Ok(Assembly {
+        chunks: asm.chunks,
+        labels: asm.labels,
+    })
+}
+
+
+struct Assembler
+{
+    labels: HashMap<String, u16>,
+    defined: HashSet<String>, // on this pass, for catching duplicates
+    unresolved: usize,        // constants left without a value this pass
+    // modes picked for each instruction on the first pass. a label
+    // that wasn't known yet can't get zero page, and the later passes
+    // have to make the same choice or every label after it moves
+    modes: Vec<AddrMode>,
+    chunks: Vec<Chunk>,
+    pc: u16,
+    line: usize,
+    final_pass: bool,
+}
+
+impl Assembler
+{
+    fn pass(&mut self, source: &str) -> Result<(), Error>
+    {
+        self.pc = 0;
+        self.chunks.clear();
+        self.defined.clear();
+        self.unresolved = 0;
+        let mut instr_count = 0;
+
+        for (i, line) in source.lines().enumerate()
+        {
+            self.line = i + 1;
+            let mut line = line.split(';').next().unwrap().trim();
+
+            // label:
+            if let Some(colon) = line.find(':')
+            {
+                let name = line[..colon].trim();
+                if is_ident(name)
+                {
+                    self.define(name, self.pc)?;
+                    line = line[colon+1..].trim();
+                }
+            }
+
+            if line.is_empty()
+            {
+                continue;
+            }
+
+            // NAME = expr
+            if let Some(eq) = line.find('=')
+            {
+                let name = line[..eq].trim();
+                if is_ident(name)
+                {
+                    // left out of labels until it has a value, so
+                    // it's sized as absolute like a forward label
+                    match self.eval(&line[eq+1..])?
+                    {
+                        Some(value) => self.define(name, value)?,
+                        None =>
+                        {
+                            self.check_duplicate(name)?;
+                            self.unresolved += 1;
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            let (word, rest) = match line.find(char::is_whitespace)
+            {
+                Some(space) => (&line[..space], line[space..].trim()),
+                None => (line, ""),
+            };
+
+            if word.starts_with('.')
+            {
+                self.directive(word, rest)?;
+            }
+            else
+            {
+                self.instruction(word, rest, instr_count)?;
+                instr_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), Error>
+    {
+        // every pass redefines everything with the values it got
+        self.check_duplicate(name)?;
+        self.labels.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn check_duplicate(&mut self, name: &str) -> Result<(), Error>
+    {
+        if !self.defined.insert(name.to_string())
+        {
+            return Err(Error::DuplicateLabel(self.line, name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8)
+    {
+        if self.chunks.is_empty()
+        {
+            self.chunks.push(Chunk { addr: self.pc, bytes: Vec::new() });
+        }
+        self.chunks.last_mut().unwrap().bytes.push(byte);
+        self.pc = self.pc.wrapping_add(1);
+    }
+
+    fn directive(&mut self, name: &str, args: &str) -> Result<(), Error>
+    {
+        match name.to_lowercase().as_str()
+        {
+            ".org" =>
+            {
+                self.pc = self.eval(args)?
+                    .ok_or_else(|| Error::UndefinedLabel(self.line, args.to_string()))?;
+                self.chunks.push(Chunk { addr: self.pc, bytes: Vec::new() });
+            }
+
+            ".byte" | ".db" =>
+                for arg in args.split(',')
+                {
+                    let value = self.eval(arg)?.unwrap_or(0);
+                    if self.final_pass && value > 0xff
+                    {
+                        return Err(Error::ByteOutOfRange(self.line, value));
+                    }
+                    self.emit(value as u8);
+                },
+
+            ".word" | ".dw" =>
+                for arg in args.split(',')
+                {
+                    let value = self.eval(arg)?.unwrap_or(0);
+                    self.emit(value as u8);
+                    self.emit((value >> 8) as u8);
+                },
+
+            _ => return Err(Error::UnknownDirective(self.line, name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operand: &str, index: usize)
+        -> Result<(), Error>
+    {
+        let mnemonic = mnemonic.to_uppercase();
+        if !OPCODES.iter().any(|op| op.mnemonic == mnemonic)
+        {
+            return Err(Error::UnknownMnemonic(self.line, mnemonic));
+        }
+
+        let operand: String = operand.chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let (mode, value) = self.operand(&mnemonic, &operand)?;
+
+        let mode = match self.modes.get(index)
+        {
+            Some(&first) => first,
+            None =>
+            {
+                self.modes.push(mode);
+                mode
+            }
+        };
+
+        let opcode = find_opcode(&mnemonic, mode)
+            .ok_or_else(|| Error::BadOperand(self.line, operand.clone()))?;
+        let value = value.unwrap_or(0);
+        if self.final_pass && mode != AddrMode::Rel
+            && mode.operand_len() == 1 && value > 0xff
+        {
+            return Err(Error::BadOperand(self.line, operand));
+        }
+
+        let start = self.pc;
+        self.emit(opcode);
+        match mode
+        {
+            AddrMode::Rel =>
+            {
+                let offset = value as i32 - (start as i32 + 2);
+                if self.final_pass && !(-128..=127).contains(&offset)
+                {
+                    return Err(Error::BranchOutOfRange(self.line, offset));
+                }
+                self.emit(offset as u8);
+            }
+
+            _ if mode.operand_len() == 1 =>
+                self.emit(value as u8),
+
+            _ if mode.operand_len() == 2 =>
+            {
+                self.emit(value as u8);
+                self.emit((value >> 8) as u8);
+            }
+
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // picks the addressing mode from how the operand is written
+    fn operand(&self, mnemonic: &str, text: &str)
+        -> Result<(AddrMode, Option<u16>), Error>
+    {
+        let upper = text.to_uppercase();
+
+        if text.is_empty()
+        {
+            let mode = if find_opcode(mnemonic, AddrMode::Imp).is_some()
+                { AddrMode::Imp }
+            else
+                { AddrMode::Acc };
+            return Ok((mode, None));
+        }
+
+        if upper == "A"
+        {
+            return Ok((AddrMode::Acc, None));
+        }
+
+        if let Some(expr) = text.strip_prefix('#')
+        {
+            return Ok((AddrMode::Imm, self.eval(expr)?));
+        }
+
+        if text.starts_with('(')
+        {
+            if let Some(expr) = upper.strip_suffix(",X)")
+            {
+                return Ok((AddrMode::IndX, self.eval(&text[1..expr.len()])?));
+            }
+            if let Some(expr) = upper.strip_suffix("),Y")
+            {
+                return Ok((AddrMode::IndY, self.eval(&text[1..expr.len()])?));
+            }
+            if text.ends_with(')')
+            {
+                return Ok((AddrMode::Ind, self.eval(&text[1..text.len()-1])?));
+            }
+            return Err(Error::BadOperand(self.line, text.to_string()));
+        }
+
+        if find_opcode(mnemonic, AddrMode::Rel).is_some()
+        {
+            return Ok((AddrMode::Rel, self.eval(text)?));
+        }
+
+        let (expr, zpg, abs) = if upper.ends_with(",X")
+            { (&text[..text.len()-2], AddrMode::ZpgX, AddrMode::AbsX) }
+        else if upper.ends_with(",Y")
+            { (&text[..text.len()-2], AddrMode::ZpgY, AddrMode::AbsY) }
+        else
+            { (text, AddrMode::Zpg, AddrMode::Abs) };
+
+        let value = self.eval(expr)?;
+        let fits_zero_page = matches!(value, Some(v) if v <= 0xff);
+
+        let mode = if fits_zero_page && find_opcode(mnemonic, zpg).is_some()
+            { zpg }
+        else if find_opcode(mnemonic, abs).is_some()
+            { abs }
+        else
+            { zpg };
+        Ok((mode, value))
+    }
+
+    // None if it uses a label we haven't seen yet, which is only
+    // allowed on the first pass
+    fn eval(&self, text: &str) -> Result<Option<u16>, Error>
+    {
+        let text = text.trim();
+        if let Some(rest) = text.strip_prefix('<')
+        {
+            return Ok(self.eval(rest)?.map(|v| v & 0xff));
+        }
+        if let Some(rest) = text.strip_prefix('>')
+        {
+            return Ok(self.eval(rest)?.map(|v| v >> 8));
+        }
+
+        // split into terms on + and -, keeping the signs
+        let mut result: Option<u16> = Some(0);
+        let mut negative = false;
+        let mut start = 0;
+        let bytes = text.as_bytes();
+        for i in 0..=bytes.len()
+        {
+            let at_end = i == bytes.len();
+            if at_end || ((bytes[i] == b'+' || bytes[i] == b'-') && i > start)
+            {
+                let term = self.term(text[start..i].trim())?;
+                result = match (result, term)
+                {
+                    (Some(r), Some(t)) if negative => Some(r.wrapping_sub(t)),
+                    (Some(r), Some(t)) => Some(r.wrapping_add(t)),
+                    _ => None,
+                };
+                if !at_end
+                {
+                    negative = bytes[i] == b'-';
+                    start = i + 1;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn term(&self, text: &str) -> Result<Option<u16>, Error>
+    {
+        let bad_number = || Error::BadNumber(self.line, text.to_string());
+
+        if text == "*"
+        {
+            Ok(Some(self.pc))
+        }
+        else if let Some(hex) = text.strip_prefix('$')
+        {
+            u16::from_str_radix(hex, 16).map(Some).map_err(|_| bad_number())
+        }
+        else if let Some(bin) = text.strip_prefix('%')
+        {
+            u16::from_str_radix(bin, 2).map(Some).map_err(|_| bad_number())
+        }
+        else if text.starts_with(|c: char| c.is_ascii_digit())
+        {
+            text.parse::<u16>().map(Some).map_err(|_| bad_number())
+        }
+        else if is_ident(text)
+        {
+            match self.labels.get(text)
+            {
+                Some(value) => Ok(Some(*value)),
+                None if !self.final_pass => Ok(None),
+                None => Err(Error::UndefinedLabel(self.line, text.to_string())),
+            }
+        }
+        else
+        {
+            Err(Error::BadOperand(self.line, text.to_string()))
+        }
+    }
+}
+
+fn is_ident(text: &str) -> bool
+{
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// official encodings win when an unofficial opcode does the same thing
+fn find_opcode(mnemonic: &str, mode: AddrMode) -> Option<u8>
+{
+    let mut unofficial = None;
+    for (i, op) in OPCODES.iter().enumerate()
+    {
+        if op.mnemonic == mnemonic && op.mode == mode
+        {
+            if op.official
+            {
+                return Some(i as u8);
+            }
+            unofficial.get_or_insert(i as u8);
+        }
+    }
+    unofficial
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // everything assembled, as one run of bytes from the first chunk
+    fn bytes(source: &str) -> Vec<u8>
+    {
+        let asm = assemble(source).unwrap();
+        asm.chunks.iter().flat_map(|c| c.bytes.iter().copied()).collect()
+    }
+
+    #[test]
+    fn addressing_modes()
+    {
+        let source = "
+            .org $8000
+            nop
+            asl a
+            lsr
+            lda #$12
+            lda $34
+            lda $34,x
+            ldx $34,y
+            lda $1234
+            lda $1234,x
+            lda $1234,y
+            jmp ($1234)
+            lda ($34,x)
+            lda ($34),y
+            bne *
+        ";
+        assert_eq!(bytes(source), [
+            0xea,
+            0x0a,
+            0x4a,
+            0xa9, 0x12,
+            0xa5, 0x34,
+            0xb5, 0x34,
+            0xb6, 0x34,
+            0xad, 0x34, 0x12,
+            0xbd, 0x34, 0x12,
+            0xb9, 0x34, 0x12,
+            0x6c, 0x34, 0x12,
+            0xa1, 0x34,
+            0xb1, 0x34,
+            0xd0, 0xfe,
+        ]);
+    }
+
+    #[test]
+    fn absolute_only_modes_stay_absolute()
+    {
+        // there's no zero page jmp or zero page,Y lda
+        assert_eq!(bytes("jmp $0012\nlda $12,y"), [0x4c, 0x12, 0x00, 0xb9, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn labels_and_branches()
+    {
+        let source = "
+                .org $c000
+        start:  ldx #0
+        loop:   dex
+                bne loop
+                beq done
+                nop
+        done:   jmp start
+        ";
+        let asm = assemble(source).unwrap();
+        assert_eq!(asm.label("start"), Some(0xc000));
+        assert_eq!(asm.label("loop"), Some(0xc002));
+        assert_eq!(asm.label("done"), Some(0xc008));
+        assert_eq!(bytes(source), [
+            0xa2, 0x00,
+            0xca,
+            0xd0, 0xfd,
+            0xf0, 0x01,
+            0xea,
+            0x4c, 0x00, 0xc0,
+        ]);
+    }
+
+    #[test]
+    fn org_byte_and_word()
+    {
+        let source = "
+                .org $fffa
+                .word nmi, $1234
+                .org $0200
+        nmi:    .byte 1, $02, %11, <nmi, >nmi
+                .dw *
+        ";
+        let asm = assemble(source).unwrap();
+        assert_eq!(asm.chunks.len(), 2);
+        assert_eq!(asm.chunks[0].addr, 0xfffa);
+        assert_eq!(asm.chunks[0].bytes, [0x00, 0x02, 0x34, 0x12]);
+        assert_eq!(asm.chunks[1].addr, 0x0200);
+        assert_eq!(asm.chunks[1].bytes, [1, 2, 3, 0x00, 0x02, 0x05, 0x02]);
+    }
+
+    #[test]
+    fn forward_references_are_absolute()
+    {
+        // ends up in zero page, but the size was picked before that
+        // was known
+        let source = "
+                .org $0010
+                lda data
+                lda data+1,x
+        data:   .byte $aa, $bb
+        ";
+        assert_eq!(bytes(source), [0xad, 0x16, 0x00, 0xbd, 0x17, 0x00, 0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn constants()
+    {
+        let source = "
+        ZP = $20
+        PAGE = $0300
+                .org $8000
+                sta ZP
+                sta PAGE+ZP
+        ";
+        assert_eq!(bytes(source), [0x85, 0x20, 0x8d, 0x20, 0x03]);
+    }
+
+    #[test]
+    fn constant_from_forward_label()
+    {
+        let source = "
+        PTR = data
+        PTR2 = PTR+1
+                .org $0010
+                lda PTR
+                lda PTR2
+        data:   .byte 1, 2
+        ";
+        assert_eq!(bytes(source), [0xad, 0x16, 0x00, 0xad, 0x17, 0x00, 1, 2]);
+    }
+
+    #[test]
+    fn constant_used_before_its_forward_label_chain()
+    {
+        // each one needs the one after it, a pass apiece
+        let source = "
+                lda P
+        P = Q
+        Q = R
+        R = data
+        data:   .byte 7
+        ";
+        assert_eq!(bytes(source), [0xad, 0x03, 0x00, 7]);
+    }
+
+    #[test]
+    fn errors()
+    {
+        assert!(matches!(assemble("foo"), Err(Error::UnknownMnemonic(1, _))));
+        assert!(matches!(assemble(".blah 1"), Err(Error::UnknownDirective(1, _))));
+        assert!(matches!(assemble("lda nowhere"), Err(Error::UndefinedLabel(1, _))));
+        assert!(matches!(assemble("a: nop\na: nop"), Err(Error::DuplicateLabel(2, _))));
+        assert!(matches!(assemble("lda #$123"), Err(Error::BadOperand(1, _))));
+        assert!(matches!(assemble("lda $12g"), Err(Error::BadNumber(1, _))));
+        assert!(matches!(assemble(".byte $1234"), Err(Error::ByteOutOfRange(1, 0x1234))));
+        assert!(matches!(assemble("x: .byte 0\n.org $1000\nbne x"),
+                         Err(Error::BranchOutOfRange(3, _))));
+    }
+
+    #[test]
+    fn patch_checks_bounds()
+    {
+        let asm = assemble(".org $c000\n.byte 1, 2").unwrap();
+        let mut buf = [0; 4];
+        asm.patch(&mut buf, 0xc000).unwrap();
+        assert_eq!(buf, [1, 2, 0, 0]);
+        assert!(matches!(asm.patch(&mut buf, 0xc001), Err(Error::OutOfBounds(0xc000))));
+    }
+}
@@ -0,0 +1,536 @@
+// Everything the cpu talks to goes through a Bus. The cpu doesn't own
+// one, it gets lent a bus for every step, so NES can keep ownership
+// of the ram, ppu and cart and only hand them out while the cpu runs.
+//
+// A bus never panics on an access it can't do, the cpu couldn't either.
+// Reads of nothing get open bus and writes to nothing or to rom are
+// dropped, and the bus keeps the fault for whoever runs the cpu to
+// deal with after the step, see FaultPolicy.
+//
+// The cpu also tells the bus about the end of every cycle, so the ppu
+// behind CpuBus runs along with it a cycle at a time instead of
+// catching up after the instruction. Games and tests that race
+// PPUSTATUS against vblank need it that close.
+
+use crate::nes::ppu::PPU;
+use crate::nes::ines::{ INesRom, Mirroring };
+use crate::nes::mapper::Mapper;
+
+use std::collections::VecDeque;
+use std::fmt::{ Display, Formatter };
+
+pub trait Bus
+{
+    // a real bus cycle, may have side effects on the device
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+
+    // looks at what read would return without touching anything,
+    // for the tracer, the disassembler and debuggers
+    fn peek(&self, addr: u16) -> u8;
+
+    // where addr is in rom, if that's what it is. things that never
+    // change can be cached by this, see cpu/cache.rs
+    fn rom_offset(&self, _addr: u16) -> Option<usize>
+    {
+        None
+    }
+
+    // goes up every time prg banks get switched, so whatever cached
+    // rom_offset knows to ask again
+    fn bank_switches(&self) -> u64
+    {
+        0
+    }
+
+    // the first access since the last call that the bus couldn't do
+    fn take_fault(&mut self) -> Option<Fault>
+    {
+        None
+    }
+
+    // the end of a cpu cycle, whatever else is on the bus gets to
+    // run for it. called for every cycle, with an access or without
+    fn cycle(&mut self)
+    {
+    }
+
+    // the NMI line as whatever's on the bus drives it, None if
+    // nothing does and it's left to CPU::set_nmi
+    fn nmi(&self) -> Option<bool>
+    {
+        None
+    }
+
+    // the page to copy to OAM, if the last write asked for that.
+    // the cpu stops while it's done, see CPU::oam_dma
+    fn take_dma(&mut self) -> Option<u8>
+    {
+        None
+    }
+}
+
+// one cycle worth of bus activity, as (address, data)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusAccess
+{
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// an access that had nothing on the other end to take it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault
+{
+    Unmapped(BusAccess), // a read has the open bus value it got
+    ReadOnly(u16, u8),   // a write to rom
+}
+
+impl Display for Fault
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        match self
+        {
+            Fault::Unmapped(BusAccess::Read(addr, _)) =>
+                write!(f, "read from unmapped ${:04X}", addr),
+            Fault::Unmapped(BusAccess::Write(addr, data)) =>
+                write!(f, "write of ${:02X} to unmapped ${:04X}", data, addr),
+            Fault::ReadOnly(addr, data) =>
+                write!(f, "write of ${:02X} to rom at ${:04X}", data, addr),
+        }
+    }
+}
+
+// what to do about a fault once the step is done
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultPolicy
+{
+    Ignore,
+    Log,   // kept in a FaultLog, and that's it
+    Trap,  // logged, and the debugger stops on it
+    Error, // logged, and step returns it as an error
+}
+
+// the last few faults, and how many there were in all
+#[derive(Debug, Default)]
+pub struct FaultLog
+{
+    recent: VecDeque<Fault>,
+    count: u64,
+}
+
+impl FaultLog
+{
+    const KEEP: usize = 32;
+
+    pub fn new() -> FaultLog
+    {
+        FaultLog::default()
+    }
+
+    pub fn push(&mut self, fault: Fault)
+    {
+        if self.recent.len() == Self::KEEP
+        {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(fault);
+        self.count += 1;
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.recent.clear();
+        self.count = 0;
+    }
+
+    // oldest first
+    pub fn recent(&self) -> &VecDeque<Fault>
+    {
+        &self.recent
+    }
+
+    pub fn count(&self) -> u64
+    {
+        self.count
+    }
+}
+
+// the apu, the controllers and OAM DMA's register
+const IO_START: u16 = 0x4000;
+const IO_END: u16 = 0x401f;
+
+// what the cpu sees of the console
+pub struct CpuBus<'a>
+{
+    pub ram: &'a mut [u8; 0x800],
+    pub prg_ram: &'a mut [u8; 0x2000],
+    pub ppu: &'a mut PPU,
+    pub vram: &'a mut [u8; 0x1000],
+    pub cart: Option<&'a INesRom>,
+    pub mapper: &'a mut Mapper,
+    pub fault: Option<Fault>,
+    pub chr_read: Option<usize>, // see PpuBus
+    pub drawn: Option<&'a mut Vec<usize>>,
+    dma: Option<u8>, // see take_dma
+    // how many of this cycle's three ppu dots have been run. two
+    // happen before the cycle's access and the last one after it,
+    // which is what lines $2002 reads up with vblank
+    dots: u8,
+}
+
+impl<'a> CpuBus<'a>
+{
+    // everything that isn't lent in starts out empty, like it is
+    // between steps
+    pub fn new(ram: &'a mut [u8; 0x800], prg_ram: &'a mut [u8; 0x2000], ppu: &'a mut PPU,
+               vram: &'a mut [u8; 0x1000], cart: Option<&'a INesRom>, mapper: &'a mut Mapper)
+        -> CpuBus<'a>
+    {
+        CpuBus {
+            ram,
+            prg_ram,
+            ppu,
+            vram,
+            cart,
+            mapper,
+            fault: None,
+            chr_read: None,
+            drawn: None,
+            dma: None,
+            dots: 0,
+        }
+    }
+
+    // where in the rom file the byte the cpu sees at addr comes from,
+    // with the banks the mapper has in right now
+    pub fn prg_file_offset(&self, addr: u16) -> Option<usize>
+    {
+        let cart = self.cart?;
+        if addr < 0x8000 || cart.prg_size() == 0
+        {
+            return None;
+        }
+        Some(cart.prg_offset + self.mapper.prg_offset(addr, cart.prg_size()))
+    }
+
+    fn prg(&self, addr: u16) -> Option<u8>
+    {
+        let cart = self.cart?;
+        cart.buffer.get(self.prg_file_offset(addr)?).copied()
+    }
+
+    // only the first one of a step is kept
+    fn fault(&mut self, fault: Fault)
+    {
+        self.fault.get_or_insert(fault);
+    }
+
+    // lends the ppu its side of things for a register access
+    fn ppu_reg(&mut self, addr: u16, write: Option<u8>) -> u8
+    {
+        let mut bus = PpuBus {
+            vram: self.vram,
+            cart: self.cart,
+            mapper: self.mapper,
+            chr_read: None,
+            drawn: None,
+        };
+        let reg = addr as usize % 8;
+        let data = match write
+        {
+            Some(data) =>
+            {
+                self.ppu.write(reg, data, &mut bus);
+                data
+            }
+            None => self.ppu.read(reg, &mut bus),
+        };
+        self.chr_read = bus.chr_read.or(self.chr_read);
+        data
+    }
+
+    // runs the ppu up to dot n of the current cycle
+    fn run_ppu(&mut self, n: u8)
+    {
+        if self.dots >= n
+        {
+            return;
+        }
+        let mut bus = PpuBus {
+            vram: self.vram,
+            cart: self.cart,
+            mapper: self.mapper,
+            chr_read: None,
+            drawn: self.drawn.as_deref_mut(),
+        };
+        while self.dots < n
+        {
+            self.ppu.tick(&mut bus);
+            self.dots += 1;
+        }
+    }
+}
+
+impl<'a> Bus for CpuBus<'a>
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        self.run_ppu(2);
+        match addr
+        {
+            0x0000..=0x1fff => self.ram[addr as usize % 0x800],
+            0x2000..=0x3fff => self.ppu_reg(addr, None),
+            // the apu and the controllers, which aren't there yet. they
+            // are on the bus though, so open bus and not a fault
+            IO_START..=IO_END => (addr >> 8) as u8,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000],
+            _ => match self.prg(addr)
+            {
+                Some(data) => data,
+                None =>
+                {
+                    // open bus, which mostly still has the high byte
+                    // of the address on it
+                    let data = (addr >> 8) as u8;
+                    self.fault(Fault::Unmapped(BusAccess::Read(addr, data)));
+                    data
+                }
+            },
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        self.run_ppu(2);
+        match addr
+        {
+            0x0000..=0x1fff => self.ram[addr as usize % 0x800] = data,
+            0x2000..=0x3fff => { self.ppu_reg(addr, Some(data)); }
+            0x4014 => self.dma = Some(data),
+            IO_START..=IO_END => (),
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000] = data,
+            0x8000..=0xffff =>
+            {
+                if !self.mapper.write(addr, data)
+                {
+                    self.fault(Fault::ReadOnly(addr, data));
+                }
+            }
+            _ => self.fault(Fault::Unmapped(BusAccess::Write(addr, data))),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        match addr
+        {
+            0x0000..=0x1fff => self.ram[addr as usize % 0x800],
+            0x2000..=0x3fff => self.ppu.peek(addr as usize % 8),
+            IO_START..=IO_END => (addr >> 8) as u8,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xffff => self.prg(addr).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn rom_offset(&self, addr: u16) -> Option<usize>
+    {
+        self.prg_file_offset(addr)
+    }
+
+    fn bank_switches(&self) -> u64
+    {
+        self.mapper.switches()
+    }
+
+    fn take_fault(&mut self) -> Option<Fault>
+    {
+        self.fault.take()
+    }
+
+    fn cycle(&mut self)
+    {
+        self.run_ppu(3);
+        self.dots = 0;
+    }
+
+    fn nmi(&self) -> Option<bool>
+    {
+        Some(self.ppu.nmi())
+    }
+
+    fn take_dma(&mut self) -> Option<u8>
+    {
+        self.dma.take()
+    }
+}
+
+// what the ppu sees: chr from the cart at $0000-$1FFF and the
+// nametables at $2000-$2FFF, mirrored up to $3FFF. the palette is
+// inside the ppu itself
+pub struct PpuBus<'a>
+{
+    pub vram: &'a mut [u8; 0x1000],
+    pub cart: Option<&'a INesRom>,
+    pub mapper: &'a Mapper,
+    // where in the rom file the last chr byte that was read came from,
+    // for the code/data logger
+    pub chr_read: Option<usize>,
+    // where in the rom file chr read while rendering came from, for
+    // the code/data logger to mark as drawn once the step is done
+    pub drawn: Option<&'a mut Vec<usize>>,
+}
+
+impl<'a> PpuBus<'a>
+{
+    pub fn chr_file_offset(&self, addr: u16) -> Option<usize>
+    {
+        let cart = self.cart?;
+        (addr < 0x2000 && (addr as usize) < cart.chr_size())
+            .then(|| cart.chr_offset + self.mapper.chr_offset(addr, cart.chr_size()))
+    }
+
+    // the console only has 2K for four nametables, the cart decides
+    // which pairs of them are the same
+    fn nametable(&self, addr: u16) -> usize
+    {
+        let table = (addr as usize >> 10) & 3;
+        let bank = match self.cart.map(|cart| self.mapper.mirroring(cart.mirroring))
+        {
+            Some(Mirroring::Vertical) => table & 1,
+            Some(Mirroring::FourScreen) => table,
+            Some(Mirroring::SingleLower) => 0,
+            Some(Mirroring::SingleUpper) => 1,
+            _ => table >> 1,
+        };
+        bank * 0x400 + (addr as usize & 0x3ff)
+    }
+}
+
+impl<'a> Bus for PpuBus<'a>
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000
+        {
+            return self.vram[self.nametable(addr)];
+        }
+        let offset = self.chr_file_offset(addr);
+        self.chr_read = offset.or(self.chr_read);
+        if let (Some(drawn), Some(offset)) = (&mut self.drawn, offset)
+        {
+            drawn.push(offset);
+        }
+        offset.and_then(|i| self.cart?.buffer.get(i).copied()).unwrap_or(0)
+    }
+
+    // chr is rom, writes to it go nowhere like they would on the cart
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000
+        {
+            self.vram[self.nametable(addr)] = data;
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000
+        {
+            return self.vram[self.nametable(addr)];
+        }
+        self.chr_file_offset(addr)
+            .and_then(|i| self.cart?.buffer.get(i).copied())
+            .unwrap_or(0)
+    }
+
+    fn rom_offset(&self, addr: u16) -> Option<usize>
+    {
+        self.chr_file_offset(addr & 0x3fff)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::cpu::CPU;
+
+    // runs code from $0100 in ram up to and including the DMA, how
+    // long the write to $4014 took and what ended up in OAM
+    fn dma(code: &[u8]) -> (u64, [u8; 0x100])
+    {
+        let mut ram = [0; 0x800];
+        let mut prg_ram = [0; 0x2000];
+        let mut vram = [0; 0x1000];
+        let mut ppu = PPU::new();
+        for i in 0..0x100
+        {
+            ram[0x300 + i] = i as u8;
+        }
+        ram[0x100..0x100 + code.len()].copy_from_slice(code);
+
+        let mut mapper = Mapper::new(0);
+        let mut bus = CpuBus::new(&mut ram, &mut prg_ram, &mut ppu, &mut vram, None, &mut mapper);
+        let mut cpu = CPU::new();
+        cpu.pc = 0x0100;
+        let mut before = 0;
+        while bus.ppu.oam()[0xff] == 0
+        {
+            before = cpu.cycles;
+            cpu.step(&mut bus);
+        }
+        assert!(bus.take_fault().is_none());
+        (cpu.cycles - before, *bus.ppu.oam())
+    }
+
+    #[test]
+    fn oam_dma()
+    {
+        // lda #$03, sta $4014
+        let (cycles, oam) = dma(&[0xa9, 0x03, 0x8d, 0x14, 0x40]);
+        assert_eq!(cycles, 4 + 514);
+        for (i, &byte) in oam.iter().enumerate()
+        {
+            // the attribute bytes don't have bits 2-4
+            let want = if i % 4 == 2 { i as u8 & 0xe3 } else { i as u8 };
+            assert_eq!(byte, want);
+        }
+
+        // a cycle less when it doesn't have to wait for a read cycle,
+        // lda $00 is one longer than lda #
+        let (cycles, _) = dma(&[0xa5, 0x00, 0xa9, 0x03, 0x8d, 0x14, 0x40]);
+        assert_eq!(cycles, 4 + 513);
+    }
+
+    #[test]
+    fn io_registers_are_not_faults()
+    {
+        let mut ram = [0; 0x800];
+        let mut prg_ram = [0; 0x2000];
+        let mut vram = [0; 0x1000];
+        let mut ppu = PPU::new();
+        let mut mapper = Mapper::new(0);
+        let mut bus = CpuBus::new(&mut ram, &mut prg_ram, &mut ppu, &mut vram, None, &mut mapper);
+
+        for addr in IO_START..=IO_END
+        {
+            bus.write(addr, 0x0f);
+            assert_eq!(bus.read(addr), 0x40);
+            bus.cycle();
+        }
+        assert_eq!(bus.take_fault(), None);
+        assert_eq!(bus.take_dma(), Some(0x0f));
+
+        // past them is nothing, and no cart means no rom either
+        bus.write(0x4020, 0x01);
+        assert_eq!(bus.take_fault(), Some(Fault::Unmapped(BusAccess::Write(0x4020, 0x01))));
+        assert_eq!(bus.read(0x8000), 0x80);
+        assert_eq!(bus.take_fault(), Some(Fault::Unmapped(BusAccess::Read(0x8000, 0x80))));
+    }
+}
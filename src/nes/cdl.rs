@@ -0,0 +1,321 @@
+// Code/data logging in the format FCEUX writes, so its .cdl files can
+// be fed to the disassemblers that understand them. The file is one
+// byte of flags per PRG byte followed by one per CHR byte, in rom file
+// order. Everything is marked by where it is in the file and not by
+// cpu address, so it still means something once banks get switched.
+//
+// The cpu doesn't say which reads are instruction fetches, so every
+// step is watched and sorted out afterwards: the bytes of the
+// instruction are code, the last other rom read it did is data. The
+// ones before that are dummy reads, from an index crossing a page and
+// the like. Branches, jumps and returns only do dummy reads in rom,
+// except for the pointer of an indirect JMP.
+
+use crate::nes::cpu::{ Opcode, AddrMode };
+use crate::nes::bus::{ Bus, Fault };
+use crate::nes::ines::INesRom;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// prg flags
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+// which 8K of $8000-$FFFF it was mapped to when read, in bits 2 and 3
+const BANK_SHIFT: u8 = 2;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20; // read through a (zp),y style pointer
+pub const PCM: u8 = 0x40;           // read by the DMC, once there is an apu
+
+// chr flags
+pub const DRAWN: u8 = 0x01;
+pub const READ: u8 = 0x02;
+
+const VECTORS: u16 = 0xfffa;
+
+#[derive(Debug)]
+pub struct CodeDataLogger
+{
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_offset: usize,
+    chr_offset: usize,
+
+    reads: Vec<u16>, // what the current step read
+    jumped: bool,    // the last instruction was an indirect jump or a return
+}
+
+impl CodeDataLogger
+{
+    pub fn new(cart: &INesRom) -> CodeDataLogger
+    {
+        CodeDataLogger {
+            prg: vec![0; cart.prg_size()],
+            chr: vec![0; cart.chr_size()],
+            prg_offset: cart.prg_offset,
+            chr_offset: cart.chr_offset,
+            reads: Vec::new(),
+            jumped: false,
+        }
+    }
+
+    pub fn prg(&self) -> &[u8]
+    {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8]
+    {
+        &self.chr
+    }
+
+    // the .cdl file contents
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        [self.prg.as_slice(), &self.chr].concat()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()>
+    {
+        fs::write(path, self.to_bytes())
+    }
+
+    // picks up the marks from an earlier session, like FCEUX does when
+    // a log is loaded. false if it was made for a rom of another size
+    pub fn merge(&mut self, data: &[u8]) -> bool
+    {
+        if data.len() != self.prg.len() + self.chr.len()
+        {
+            return false;
+        }
+        let (prg, chr) = data.split_at(self.prg.len());
+        self.prg.iter_mut().zip(prg).for_each(|(a, b)| *a |= b);
+        self.chr.iter_mut().zip(chr).for_each(|(a, b)| *a |= b);
+        true
+    }
+
+    // wraps the bus for a step so the reads get seen
+    pub fn watch<'a>(&'a mut self, bus: &'a mut dyn Bus) -> Logged<'a>
+    {
+        self.reads.clear();
+        Logged { bus, reads: &mut self.reads }
+    }
+
+    // sorts out the reads of a step that ran opcode from pc. file_offset
+    // says where a cpu address is in the rom file, if it's rom at all
+    pub fn log(&mut self, pc: u16, opcode: Opcode, file_offset: impl Fn(u16) -> Option<usize>)
+    {
+        let len = opcode.mode.operand_len() + 1;
+        let indirect_jump = opcode.mnemonic == "JMP"
+            && matches!(opcode.mode, AddrMode::Ind | AddrMode::IndAbsX);
+        let control = matches!(opcode.mode, AddrMode::Rel | AddrMode::ZpgRel)
+            || matches!(opcode.mnemonic, "JMP" | "JSR" | "RTS" | "RTI" | "BRK");
+
+        let indirect_code = if self.jumped { INDIRECT_CODE } else { 0 };
+        for i in 0..len
+        {
+            let flags = if i == 0 { CODE | indirect_code } else { CODE };
+            self.mark_prg(pc.wrapping_add(i), flags, &file_offset);
+        }
+
+        let reads = std::mem::take(&mut self.reads);
+        let rom = reads.iter().copied()
+            .filter(|&addr| addr >= 0x8000 && addr.wrapping_sub(pc) >= len);
+        if indirect_jump
+        {
+            // the pointer, nothing else is read
+            for addr in rom.clone().filter(|&addr| addr < VECTORS)
+            {
+                self.mark_prg(addr, DATA, &file_offset);
+            }
+        }
+        else if !control
+        {
+            // one byte instructions and interrupts read the next
+            // instruction for nothing
+            let data = rom.clone()
+                .rfind(|&addr| addr < VECTORS && addr != pc.wrapping_add(len));
+            if let Some(addr) = data
+            {
+                let indirect = matches!(opcode.mode, AddrMode::IndX | AddrMode::IndY | AddrMode::IndZpg);
+                let flags = if indirect { DATA | INDIRECT_DATA } else { DATA };
+                self.mark_prg(addr, flags, &file_offset);
+            }
+        }
+
+        // an interrupt taken at the end of the step reads its vector
+        for addr in rom.filter(|&addr| addr >= VECTORS)
+        {
+            self.mark_prg(addr, DATA, &file_offset);
+        }
+        self.reads = reads;
+
+        self.jumped = indirect_jump || matches!(opcode.mnemonic, "RTS" | "RTI");
+    }
+
+    fn mark_prg(&mut self, addr: u16, flags: u8, file_offset: &impl Fn(u16) -> Option<usize>)
+    {
+        let Some(offset) = file_offset(addr)
+        else { return };
+        let bank = ((addr >> 13) & 3) as u8;
+        if let Some(byte) = self.prg.get_mut(offset - self.prg_offset)
+        {
+            *byte |= flags | bank << BANK_SHIFT;
+        }
+    }
+
+    // for the ppu, offset is where in the file the chr byte came from
+    pub fn mark_chr(&mut self, offset: usize, flags: u8)
+    {
+        if let Some(byte) = offset.checked_sub(self.chr_offset)
+            .and_then(|i| self.chr.get_mut(i))
+        {
+            *byte |= flags;
+        }
+    }
+}
+
+// the bus a step gets while logging
+pub struct Logged<'a>
+{
+    bus: &'a mut dyn Bus,
+    reads: &'a mut Vec<u16>,
+}
+
+impl<'a> Bus for Logged<'a>
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        self.reads.push(addr);
+        self.bus.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        self.bus.write(addr, data);
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        self.bus.peek(addr)
+    }
+
+    fn rom_offset(&self, addr: u16) -> Option<usize>
+    {
+        self.bus.rom_offset(addr)
+    }
+
+    fn bank_switches(&self) -> u64
+    {
+        self.bus.bank_switches()
+    }
+
+    fn take_fault(&mut self) -> Option<Fault>
+    {
+        self.bus.take_fault()
+    }
+
+    fn cycle(&mut self)
+    {
+        self.bus.cycle();
+    }
+
+    fn nmi(&self) -> Option<bool>
+    {
+        self.bus.nmi()
+    }
+
+    fn take_dma(&mut self) -> Option<u8>
+    {
+        self.bus.take_dma()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::NES;
+    use crate::nes::harness;
+
+    // data read every way there is and one sprite drawn, the rest of
+    // OAM off the bottom of the screen
+    const MARKS: &str = "
+            .org $8000
+    reset:  ldx #$ff
+            txs
+            lda #$08        ; sprites from $1000
+            sta $2000
+            lda #$ff
+            ldx #0
+    hide:   sta $0200,x
+            inx
+            bne hide
+            lda #50
+            sta $0200
+            lda #3
+            sta $0201
+            lda #$40
+            sta $0203
+            lda #$02
+            sta $4014
+            lda #$01        ; a chr read through PPUDATA
+            sta $2006
+            lda #$00
+            sta $2006
+            lda $2007
+            lda table
+            lda #<pointed
+            sta $00
+            lda #>pointed
+            sta $01
+            ldy #1
+            lda ($00),y
+            jmp (vector)
+            .org $a000
+    table:  .byte $11
+    pointed: .byte $22, $33
+    vector: .word target
+            .org $c000
+    target: lda #$18
+            sta $2001
+    loop:   jmp loop
+            .org $fffc
+            .word reset
+    ";
+
+    #[test]
+    fn fceux_flags()
+    {
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(MARKS).unwrap());
+        assert!(nes.log_code_data());
+        nes.reset();
+        while nes.frame() < 3
+        {
+            nes.step().unwrap();
+        }
+        let cdl = nes.cdl().unwrap();
+        let (prg, chr) = (cdl.prg(), cdl.chr());
+
+        // code and data with the 8K bank they were read in, $A000 is 1
+        // and $C000 is 2
+        assert_eq!(prg[0x0000], CODE);
+        assert_eq!(prg[0x2000], DATA | 1 << BANK_SHIFT, "table");
+        assert_eq!(prg[0x2001], 0x00, "pointed at, but y is 1");
+        assert_eq!(prg[0x2002], DATA | INDIRECT_DATA | 1 << BANK_SHIFT);
+        assert_eq!(&prg[0x2003..0x2005], [DATA | 1 << BANK_SHIFT; 2], "vector");
+        assert_eq!(prg[0x4000], CODE | INDIRECT_CODE | 2 << BANK_SHIFT, "target");
+        assert_eq!(prg[0x4001], CODE | 2 << BANK_SHIFT);
+        assert_eq!(prg[0x4002], CODE | 2 << BANK_SHIFT);
+
+        // background tile 0, sprite tile 3, and what PPUDATA read. the
+        // empty sprite slots weren't drawn
+        assert_eq!(&chr[0x0000..0x0010], [DRAWN; 16]);
+        assert_eq!(&chr[0x1030..0x1040], [DRAWN; 16]);
+        assert_eq!(chr[0x0100], READ);
+        assert_eq!(&chr[0x1ff0..0x2000], [0; 16], "tile $FF");
+        assert_eq!(chr.iter().filter(|&&flags| flags != 0).count(), 33);
+    }
+}
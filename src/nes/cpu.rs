@@ -1,6 +1,17 @@
-use crate::nes::memory_map::MemoryMap;
+mod opcodes;
+mod calls;
+mod cache;
+#[cfg(test)]
+mod tests;
+
+use crate::nes::bus::Bus;
+use crate::nes::state::{ self, Writer, Reader };
 use std::fmt::{ Display, Formatter };
 
+pub use opcodes::{ Opcode, OPCODES, CMOS_OPCODES };
+pub use calls::{ CallStack, Frame, FrameKind, Diagnostic };
+pub use cache::{ DecodeCache, Decoded };
+
 const NMI_VECTOR: u16 = 0xfffa;
 const RST_VECTOR: u16 = 0xfffc;
 const IRQ_VECTOR: u16 = 0xfffe;
@@ -8,87 +19,347 @@ const IRQ_VECTOR: u16 = 0xfffe;
 
 const FLAG_N: u8 = 0b1000_0000;
 const FLAG_V: u8 = 0b0100_0000;
+const FLAG_U: u8 = 0b0010_0000; // unused, always reads back as 1
 const FLAG_B: u8 = 0b0001_0000;
 const FLAG_D: u8 = 0b0000_1000;
 const FLAG_I: u8 = 0b0000_0100;
 const FLAG_Z: u8 = 0b0000_0010;
 const FLAG_C: u8 = 0b0000_0001;
 
+// what the unstable opcodes (ANE, LXA) or the accumulator with. it
+// depends on the chip and even on temperature, this is the common one
+const MAGIC: u8 = 0xee;
+
+
+// which chip the core behaves like, picked when it's created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant
+{
+    Ricoh2A03, // the nes one, an NMOS 6502 with decimal mode cut out
+    Nmos6502,
+    Cmos65C02, // WDC's, with the bit instructions and WAI/STP
+}
+
+impl Variant
+{
+    fn from_u8(v: u8) -> Option<Variant>
+    {
+        match v
+        {
+            0 => Some(Variant::Ricoh2A03),
+            1 => Some(Variant::Nmos6502),
+            2 => Some(Variant::Cmos65C02),
+            _ => None,
+        }
+    }
+
+    // the decode table this chip uses
+    pub fn opcodes(&self) -> &'static [Opcode; 256]
+    {
+        match self
+        {
+            Variant::Cmos65C02 => &CMOS_OPCODES,
+            _ => &OPCODES,
+        }
+    }
+}
 
-enum AddrMode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddrMode
 {
     Acc,
     Imp,
     Imm,
+    Zpg,
+    ZpgX,
+    ZpgY,
+    Abs,
+    AbsX,
+    AbsY,
+    Ind,
+    IndX,
+    IndY,
+    Rel,
+
+    // 65C02 only
+    IndZpg,  // (zp)
+    IndAbsX, // (abs,X), only JMP has it
+    ZpgRel,  // zp,rel of BBR and BBS
+}
+
+use AddrMode::*;
+
+impl AddrMode
+{
+    // how many bytes follow the opcode
+    pub fn operand_len(&self) -> u16
+    {
+        match self
+        {
+            Acc | Imp => 0,
+            Imm | Zpg | ZpgX | ZpgY | IndX | IndY | Rel | IndZpg => 1,
+            Abs | AbsX | AbsY | Ind | IndAbsX | ZpgRel => 2,
+        }
+    }
+}
+
+// what the instruction does with its operand. indexed writes (and
+// read-modify-writes) can't skip the dummy read the way reads can
+#[derive(Clone, Copy, PartialEq)]
+enum Access
+{
+    Read,
+    Write,
 }
 
+// the cpu doesn't own any memory. whoever runs it lends it a bus
+// for each step, see the notes on why
 #[derive(Debug)]
-pub struct CPU<'a>
+pub struct CPU
 {
-    a: u8,
-    x: u8,
-    y: u8,
-    s: u8,
-    p: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
     pub pc: u16,
 
-    pub mem: MemoryMap<'a>,
-
     // non state
-    cycles: u64, 
+    pub cycles: u64,
+    variant: Variant,
+    halted: bool,  // stuck after a JAM or STP, only a reset gets it out
+    waiting: bool, // after a WAI, until an interrupt line is asserted
+    calls: CallStack,
+    // operand bytes the decode cache already had, read_next_byte
+    // takes these instead of going to the bus. 16 bits for each, the
+    // byte and then 1 to say it's there, first one in the low bits
+    prefetched: u32,
+
+    // interrupt lines, driven by whoever is on the other side
+    nmi_line: bool,
+    irq_line: bool,
+
+    // what the cpu has sampled from them. the lines are checked on
+    // every cycle, but whether an interrupt is taken after an
+    // instruction depends on what was seen one cycle before its end
+    nmi_last: bool,      // line level on the previous cycle, for the edge detector
+    nmi_pending: bool,   // an edge was seen and hasn't been serviced yet
+    irq_pending: bool,   // line is asserted and I is clear
+    take_nmi: bool,
+    take_irq: bool,
+}
+
+impl Default for CPU
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
 }
 
-impl<'a> CPU<'a>
+impl CPU
 {
-    pub fn new() -> CPU<'a>
+    pub fn new() -> CPU
+    {
+        CPU::with_variant(Variant::Ricoh2A03)
+    }
+
+    // s starts at 0 so reset leaves it at $FD, like the real thing
+    pub fn with_variant(variant: Variant) -> CPU
     {
         CPU {
-            a: 0, x: 0, y: 0, p: 0, s: 0xff, pc: 0,
-            cycles: 0,
-            mem: MemoryMap::new(), 
+            a: 0, x: 0, y: 0, p: FLAG_U, s: 0, pc: 0,
+            cycles: 0, variant, halted: false, waiting: false,
+            calls: CallStack::new(),
+            prefetched: 0,
+            nmi_line: false, irq_line: false,
+            nmi_last: false, nmi_pending: false, irq_pending: false,
+            take_nmi: false, take_irq: false,
         }
     }
 
-    pub fn reset(&mut self)
+    // goes through the same 7 cycles as an interrupt, except
+    // the stack writes are turned into reads
+    pub fn reset(&mut self, bus: &mut dyn Bus)
     {
-        self.pc = self.read_word(RST_VECTOR);
         self.cycles = 0;
+        self.halted = false;
+        self.waiting = false;
+        self.calls.clear();
+        self.nmi_pending = false;
+        self.take_nmi = false;
+        self.take_irq = false;
+        self.read(bus, self.pc);
+        self.read(bus, self.pc);
+        for _ in 0..3
+        {
+            self.read(bus, 0x0100 | self.s as u16);
+            self.s = self.s.wrapping_sub(1);
+        }
+        self.set_flags(FLAG_I);
+        if self.is_cmos()
+        {
+            self.clear_flags(FLAG_D);
+        }
+        self.pc = self.read_word(bus, RST_VECTOR);
     }
 
-    pub fn write(&mut self, addr: u16, data: u8)
+    pub fn variant(&self) -> Variant
     {
-        self.cycles += 1;
-        self.mem.write(addr, data)
-            .expect("cpu can't write to memory!");
+        self.variant
+    }
+
+    fn is_cmos(&self) -> bool
+    {
+        self.variant == Variant::Cmos65C02
+    }
+
+    // NMI is edge triggered, it fires once when the line is asserted
+    pub fn set_nmi(&mut self, asserted: bool)
+    {
+        self.nmi_line = asserted;
     }
 
-    pub fn read(&mut self, addr: u16) -> u8
+    // IRQ is level triggered, it keeps firing for as long as the
+    // line is held and I is clear
+    pub fn set_irq(&mut self, asserted: bool)
+    {
+        self.irq_line = asserted;
+    }
+
+    // every bus access is one cycle, and the interrupt lines are
+    // polled at the end of each one
+    fn tick(&mut self, bus: &mut dyn Bus)
     {
         self.cycles += 1;
-        self.mem.read(addr)
-            .expect("cpu can't read from memory!")
+        bus.cycle();
+        if let Some(asserted) = bus.nmi()
+        {
+            self.nmi_line = asserted;
+        }
+
+        self.take_nmi = self.nmi_pending;
+        if self.nmi_line && !self.nmi_last
+        {
+            self.nmi_pending = true;
+        }
+        self.nmi_last = self.nmi_line;
+
+        self.take_irq = self.irq_pending;
+        self.irq_pending = self.irq_line && !self.is_flag_set(FLAG_I);
+    }
+
+    pub fn write(&mut self, bus: &mut dyn Bus, addr: u16, data: u8)
+    {
+        bus.write(addr, data);
+        self.tick(bus);
+        if let Some(page) = bus.take_dma()
+        {
+            self.oam_dma(bus, page);
+        }
+    }
+
+    // the 2A03 stops the 6502 to copy a page to OAM through $2004. a
+    // cycle to stop, another if that leaves it on a write cycle, then
+    // a read and a write for each byte, 513 or 514 in all
+    fn oam_dma(&mut self, bus: &mut dyn Bus, page: u8)
+    {
+        self.tick(bus);
+        if self.cycles & 1 == 1
+        {
+            self.tick(bus);
+        }
+        for i in 0..0x100
+        {
+            let data = self.read(bus, (page as u16) << 8 | i);
+            bus.write(0x2004, data);
+            self.tick(bus);
+        }
+    }
+
+    pub fn read(&mut self, bus: &mut dyn Bus, addr: u16) -> u8
+    {
+        let data = bus.read(addr);
+        self.tick(bus);
+        data
     }
 
     // maybe this method should exist in the memory module
     // since we might also need it for ppu too
-    pub fn read_word(&mut self, addr: u16) -> u16
+    pub fn read_word(&mut self, bus: &mut dyn Bus, addr: u16) -> u16
     {
-        self.read(addr) as u16
-            | ((self.read(addr+1) as u16) << 8)
+        self.read(bus, addr) as u16
+            | ((self.read(bus, addr.wrapping_add(1)) as u16) << 8)
     }
 
-    pub fn read_next_byte(&mut self) -> u8
+    // reads a word without carrying into the high byte of the
+    // address, like the zero page pointers and JMP ($xxFF) do
+    fn read_word_wrapped(&mut self, bus: &mut dyn Bus, addr: u16) -> u16
     {
-        let result = self.read(self.pc);
-        self.pc += 1;
+        let hi_addr = (addr & 0xff00) | (addr as u8).wrapping_add(1) as u16;
+        self.read(bus, addr) as u16
+            | ((self.read(bus, hi_addr) as u16) << 8)
+    }
+
+    pub fn read_next_byte(&mut self, bus: &mut dyn Bus) -> u8
+    {
+        let result = if self.prefetched & 0x100 != 0
+        {
+            let byte = self.prefetched as u8;
+            self.prefetched >>= 16;
+            self.tick(bus);
+            byte
+        }
+        else
+        {
+            self.read(bus, self.pc)
+        };
+        self.pc = self.pc.wrapping_add(1);
         result
     }
 
-    pub fn read_next_word(&mut self) -> u16
+    pub fn read_next_word(&mut self, bus: &mut dyn Bus) -> u16
+    {
+        self.read_next_byte(bus) as u16
+            | ((self.read_next_byte(bus) as u16) << 8)
+    }
+
+    fn push(&mut self, bus: &mut dyn Bus, data: u8)
+    {
+        if self.s == 0x00
+        {
+            self.calls.overflow();
+        }
+        self.write(bus, 0x0100 | self.s as u16, data);
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    // the cycle spent incrementing s before a pull also reads the stack
+    fn peek_stack(&mut self, bus: &mut dyn Bus)
+    {
+        self.read(bus, 0x0100 | self.s as u16);
+    }
+
+    fn pull(&mut self, bus: &mut dyn Bus) -> u8
+    {
+        if self.s == 0xff
+        {
+            self.calls.underflow();
+        }
+        self.s = self.s.wrapping_add(1);
+        self.read(bus, 0x0100 | self.s as u16)
+    }
+
+    fn push_word(&mut self, bus: &mut dyn Bus, data: u16)
+    {
+        self.push(bus, (data >> 8) as u8);
+        self.push(bus, data as u8);
+    }
+
+    fn pull_word(&mut self, bus: &mut dyn Bus) -> u16
     {
-       let result = self.read_word(self.pc);
-       self.pc += 2;
-       result
+        self.pull(bus) as u16
+            | ((self.pull(bus) as u16) << 8)
     }
 
 
@@ -102,73 +373,1196 @@ impl<'a> CPU<'a>
         self.p &= !f;
     }
 
+    pub fn set_flags_if(&mut self, f: u8, cond: bool)
+    {
+        if cond
+            { self.set_flags(f) }
+        else
+            { self.clear_flags(f) }
+    }
+
     // can call with multiple flags or'd together
     pub fn is_flag_set(&self, f: u8) -> bool
     {
         self.p & f != 0
     }
 
-    pub fn step(&mut self)
+    fn set_zn(&mut self, data: u8)
     {
-        let opcode = self.read_next_byte();
+        self.set_flags_if(FLAG_Z, data == 0);
+        self.set_flags_if(FLAG_N, data & 0x80 != 0);
+    }
 
-        self.cycles += 1;
-        // TODO: replace this with an array of fn pointers
-        // also operand fetching and execution should be
-        // handled seperately so we don't end up writing
-        // a closure for every single opcode possible
-        match opcode
+
+    // operand fetching. these are shared by all the instructions
+    // so each instruction only has to say what it does with the data
+
+    // returns the effective address for the modes that point
+    // somewhere in memory, doing every bus access the real cpu
+    // does on the way there
+    fn operand_addr(&mut self, bus: &mut dyn Bus, mode: AddrMode,
+                    access: Access) -> u16
+    {
+        match mode
         {
-            0x10 =>
+            Zpg => self.read_next_byte(bus) as u16,
+            ZpgX => self.zero_page_indexed(bus, self.x),
+            ZpgY => self.zero_page_indexed(bus, self.y),
+            Abs => self.read_next_word(bus),
+
+            AbsX =>
             {
-                let operand = self.read_next_byte() as i8;
-                if !self.is_flag_set(FLAG_N)
-                {
-                    (self.pc, _) = self.pc.overflowing_add(operand as u16);
-                    self.cycles += 1;
-                }
+                let base = self.read_next_word(bus);
+                self.indexed(bus, base, self.x, access)
+            }
 
+            AbsY =>
+            {
+                let base = self.read_next_word(bus);
+                self.indexed(bus, base, self.y, access)
             }
 
-            0x78 =>
-                self.set_flags(FLAG_I),
+            // the 65C02 fixed the page wrap, at the cost of a cycle
+            Ind if self.is_cmos() =>
+            {
+                let ptr = self.read_next_word(bus);
+                self.read(bus, self.pc.wrapping_sub(1));
+                self.read_word(bus, ptr)
+            }
 
-            0x8e =>
+            Ind =>
             {
-                let operand = self.read_next_word();
-                self.write(operand, self.x);
+                let ptr = self.read_next_word(bus);
+                self.read_word_wrapped(bus, ptr)
             }
 
-            0xa2 =>
+            IndAbsX =>
             {
-                let operand = self.read_next_byte();    
-                self.x = operand;
+                let ptr = self.read_next_word(bus);
+                self.read(bus, self.pc.wrapping_sub(1));
+                self.read_word(bus, ptr.wrapping_add(self.x as u16))
             }
 
-            0xad =>
+            IndZpg =>
             {
-                let operand = self.read_next_word();
-                let operand = self.read(operand);
-                self.a = operand;
+                let ptr = self.read_next_byte(bus);
+                self.read_word_wrapped(bus, ptr as u16)
             }
 
-            0xd8 =>
-                self.clear_flags(FLAG_D), 
+            IndX =>
+            {
+                let ptr = self.zero_page_indexed(bus, self.x);
+                self.read_word_wrapped(bus, ptr)
+            }
 
+            IndY =>
+            {
+                let ptr = self.read_next_byte(bus);
+                let base = self.read_word_wrapped(bus, ptr as u16);
+                self.indexed(bus, base, self.y, access)
+            }
 
-            other => panic!("opcode {:02x} not implemented", other),
+            other => panic!("addressing mode {:?} has no address", other),
         }
     }
 
-}
+    // the base address is read while the index is being added
+    fn zero_page_indexed(&mut self, bus: &mut dyn Bus, index: u8) -> u16
+    {
+        let base = self.read_next_byte(bus);
+        self.read(bus, base as u16);
+        base.wrapping_add(index) as u16
+    }
 
-impl<'a> Display for CPU<'a>
-{
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    // the index is added to the low byte first, and the bus is read
+    // with whatever that gives us while the high byte is fixed up.
+    // reads only need the extra cycle when a page is crossed. the
+    // 65C02 reads the last operand byte again instead of a bad address
+    fn indexed(&mut self, bus: &mut dyn Bus, base: u16, index: u8,
+               access: Access) -> u16
     {
-        write!(f, "A: 0x{:02X} X: 0x{:02X} Y: 0x{:02X}  NV-BDIZC\n\
-                  PC: 0x{:04X} S: 0x{:02X}       {:08b}",
-                  self.a, self.x, self.y, self.pc, self.s, self.p)
-                  
+        let addr = base.wrapping_add(index as u16);
+        let crossed = (addr ^ base) & 0xff00 != 0;
+        if access == Access::Write || crossed
+        {
+            let dummy = if self.is_cmos() && crossed
+                { self.pc.wrapping_sub(1) }
+            else
+                { (base & 0xff00) | (addr & 0x00ff) };
+            self.read(bus, dummy);
+        }
+        addr
+    }
+
+    fn load(&mut self, bus: &mut dyn Bus, mode: AddrMode) -> u8
+    {
+        match mode
+        {
+            Acc => self.a,
+            Imm => self.read_next_byte(bus),
+            _ =>
+            {
+                let addr = self.operand_addr(bus, mode, Access::Read);
+                self.read(bus, addr)
+            }
+        }
+    }
+
+    fn store(&mut self, bus: &mut dyn Bus, mode: AddrMode, data: u8)
+    {
+        let addr = self.operand_addr(bus, mode, Access::Write);
+        self.write(bus, addr, data);
+    }
+
+    // read-modify-write, either on the accumulator or in memory.
+    // returns the result for the unofficial opcodes to use
+    fn modify(&mut self, bus: &mut dyn Bus, mode: AddrMode,
+              f: fn(&mut Self, u8) -> u8) -> u8
+    {
+        self.modify_as(bus, mode, Access::Write, f)
+    }
+
+    // the 65C02 shifts only take the indexing cycle on a page cross
+    fn shift(&mut self, bus: &mut dyn Bus, mode: AddrMode,
+             f: fn(&mut Self, u8) -> u8)
+    {
+        let access = if self.is_cmos() { Access::Read } else { Access::Write };
+        self.modify_as(bus, mode, access, f);
+    }
+
+    fn modify_as(&mut self, bus: &mut dyn Bus, mode: AddrMode, access: Access,
+                 f: fn(&mut Self, u8) -> u8) -> u8
+    {
+        if mode == Acc
+        {
+            let data = self.a;
+            self.a = f(self, data);
+            self.a
+        }
+        else
+        {
+            let addr = self.operand_addr(bus, mode, access);
+            let data = self.read(bus, addr);
+            // written back while it's being modified. the 65C02
+            // reads it again instead
+            if self.is_cmos()
+                { self.read(bus, addr); }
+            else
+                { self.write(bus, addr, data); }
+            let result = f(self, data);
+            self.write(bus, addr, result);
+            result
+        }
+    }
+
+    // SHA, SHX, SHY and TAS store the register anded with the high
+    // byte of the base address plus one. when the index crosses a
+    // page that same value ends up as the high byte of the address
+    fn store_and_high(&mut self, bus: &mut dyn Bus, mode: AddrMode, data: u8)
+    {
+        let (base, index) = match mode
+        {
+            AbsX => (self.read_next_word(bus), self.x),
+            AbsY => (self.read_next_word(bus), self.y),
+            IndY =>
+            {
+                let ptr = self.read_next_byte(bus);
+                (self.read_word_wrapped(bus, ptr as u16), self.y)
+            }
+            other => panic!("addressing mode {:?} can't be used here", other),
+        };
+
+        let addr = self.indexed(bus, base, index, Access::Write);
+        let data = data & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if (addr ^ base) & 0xff00 != 0
+            { (data as u16) << 8 | (addr & 0x00ff) }
+        else
+            { addr };
+        self.write(bus, addr, data);
+    }
+
+
+    // alu helpers
+
+    fn add_with_carry(&mut self, data: u8)
+    {
+        let sum = self.a as u16 + data as u16 + (self.p & FLAG_C) as u16;
+        let result = sum as u8;
+        self.set_flags_if(FLAG_C, sum > 0xff);
+        self.set_flags_if(FLAG_V, (self.a ^ result) & (data ^ result) & 0x80 != 0);
+        self.a = result;
+        self.set_zn(result);
+    }
+
+    fn decimal_enabled(&self) -> bool
+    {
+        self.variant != Variant::Ricoh2A03 && self.is_flag_set(FLAG_D)
+    }
+
+    fn add(&mut self, data: u8)
+    {
+        if self.decimal_enabled()
+            { self.add_decimal(data) }
+        else
+            { self.add_with_carry(data) }
+    }
+
+    fn subtract(&mut self, data: u8)
+    {
+        if self.decimal_enabled()
+            { self.subtract_decimal(data) }
+        else
+            { self.add_with_carry(!data) }
+    }
+
+    // these follow the sequences in Bruce Clark's decimal mode
+    // tutorial on 6502.org, including what happens with digits that
+    // aren't valid BCD. on NMOS chips N, V and Z come out of the
+    // intermediate results, the 65C02 sets N and Z from the answer
+    fn add_decimal(&mut self, data: u8)
+    {
+        let carry = (self.p & FLAG_C) as u16;
+        let binary = (self.a as u16 + data as u16 + carry) as u8;
+
+        let mut lo = (self.a & 0x0f) as u16 + (data & 0x0f) as u16 + carry;
+        if lo >= 0x0a
+        {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let sum = (self.a & 0xf0) as u16 + (data & 0xf0) as u16 + lo;
+        let signed = (self.a & 0xf0) as i8 as i16 + (data & 0xf0) as i8 as i16 + lo as i16;
+        let result = if sum >= 0xa0 { sum + 0x60 } else { sum };
+
+        self.set_flags_if(FLAG_C, result >= 0x100);
+        self.set_flags_if(FLAG_V, !(-128..=127).contains(&signed));
+        self.a = result as u8;
+        if self.is_cmos()
+        {
+            self.set_zn(self.a);
+        }
+        else
+        {
+            self.set_flags_if(FLAG_Z, binary == 0);
+            self.set_flags_if(FLAG_N, sum & 0x80 != 0);
+        }
+    }
+
+    // C and V are the same as in binary mode on every chip
+    fn subtract_decimal(&mut self, data: u8)
+    {
+        let borrow = 1 - (self.p & FLAG_C) as i16;
+        let a = self.a;
+        self.add_with_carry(!data);
+
+        let mut lo = (a & 0x0f) as i16 - (data & 0x0f) as i16 - borrow;
+        let result = if self.is_cmos()
+        {
+            let mut diff = a as i16 - data as i16 - borrow;
+            if diff < 0
+            {
+                diff -= 0x60;
+            }
+            if lo < 0
+            {
+                diff -= 0x06;
+            }
+            diff
+        }
+        else
+        {
+            if lo < 0
+            {
+                lo = ((lo - 0x06) & 0x0f) - 0x10;
+            }
+            let mut diff = (a & 0xf0) as i16 - (data & 0xf0) as i16 + lo;
+            if diff < 0
+            {
+                diff -= 0x60;
+            }
+            diff
+        };
+
+        self.a = result as u8;
+        if self.is_cmos()
+        {
+            self.set_zn(self.a);
+        }
+    }
+
+    fn compare(&mut self, reg: u8, data: u8)
+    {
+        self.set_flags_if(FLAG_C, reg >= data);
+        self.set_zn(reg.wrapping_sub(data));
+    }
+
+    fn shift_left(&mut self, data: u8) -> u8
+    {
+        let result = data << 1;
+        self.set_flags_if(FLAG_C, data & 0x80 != 0);
+        self.set_zn(result);
+        result
+    }
+
+    fn shift_right(&mut self, data: u8) -> u8
+    {
+        let result = data >> 1;
+        self.set_flags_if(FLAG_C, data & 0x01 != 0);
+        self.set_zn(result);
+        result
+    }
+
+    fn rotate_left(&mut self, data: u8) -> u8
+    {
+        let result = data << 1 | (self.p & FLAG_C);
+        self.set_flags_if(FLAG_C, data & 0x80 != 0);
+        self.set_zn(result);
+        result
+    }
+
+    fn rotate_right(&mut self, data: u8) -> u8
+    {
+        let result = data >> 1 | (self.p & FLAG_C) << 7;
+        self.set_flags_if(FLAG_C, data & 0x01 != 0);
+        self.set_zn(result);
+        result
+    }
+
+    fn increment(&mut self, data: u8) -> u8
+    {
+        let result = data.wrapping_add(1);
+        self.set_zn(result);
+        result
+    }
+
+    fn decrement(&mut self, data: u8) -> u8
+    {
+        let result = data.wrapping_sub(1);
+        self.set_zn(result);
+        result
+    }
+
+    // a taken branch reads the next opcode and throws it away,
+    // and one more time if the high byte of pc has to be fixed
+    fn branch(&mut self, bus: &mut dyn Bus, cond: bool)
+    {
+        let offset = self.read_next_byte(bus) as i8;
+        if cond
+        {
+            // taken branches don't poll for interrupts on their last
+            // cycle unless they cross a page, so one that only just
+            // came in has to wait for the next instruction. IRQ gets
+            // seen again since it's a level, NMI stays pending
+            if self.irq_pending && !self.take_irq
+            {
+                self.irq_pending = false;
+            }
+            let late_nmi = self.nmi_pending && !self.take_nmi;
+
+            let target = self.pc.wrapping_add(offset as u16);
+            self.read(bus, self.pc);
+            if (target ^ self.pc) & 0xff00 != 0
+            {
+                self.read(bus, (self.pc & 0xff00) | (target & 0x00ff));
+            }
+            else if late_nmi
+            {
+                self.take_nmi = false;
+            }
+            self.pc = target;
+        }
+    }
+
+
+    // instructions. all of them take the addressing mode even if
+    // they only have one, so they can be dispatched the same way
+
+    // load/store
+    fn lda(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a = self.load(bus, mode);
+        self.set_zn(self.a);
+    }
+
+    fn ldx(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.x = self.load(bus, mode);
+        self.set_zn(self.x);
+    }
+
+    fn ldy(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.y = self.load(bus, mode);
+        self.set_zn(self.y);
+    }
+
+    fn sta(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store(bus, mode, self.a);
+    }
+
+    fn stx(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store(bus, mode, self.x);
+    }
+
+    fn sty(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store(bus, mode, self.y);
+    }
+
+    // transfers
+    fn tax(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.x = self.a;
+        self.set_zn(self.x);
+    }
+
+    fn tay(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.y = self.a;
+        self.set_zn(self.y);
+    }
+
+    fn txa(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.a = self.x;
+        self.set_zn(self.a);
+    }
+
+    fn tya(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.a = self.y;
+        self.set_zn(self.a);
+    }
+
+    fn tsx(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.x = self.s;
+        self.set_zn(self.x);
+    }
+
+    fn txs(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.s = self.x;
+    }
+
+    // stack
+    fn pha(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.push(bus, self.a);
+    }
+
+    fn php(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.push(bus, self.p | FLAG_B | FLAG_U);
+    }
+
+    fn pla(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.peek_stack(bus);
+        self.a = self.pull(bus);
+        self.set_zn(self.a);
+    }
+
+    fn plp(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.peek_stack(bus);
+        self.p = self.pull(bus) & !FLAG_B | FLAG_U;
+    }
+
+    // logic and arithmetic
+    fn and(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a &= self.load(bus, mode);
+        self.set_zn(self.a);
+    }
+
+    fn ora(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a |= self.load(bus, mode);
+        self.set_zn(self.a);
+    }
+
+    fn eor(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a ^= self.load(bus, mode);
+        self.set_zn(self.a);
+    }
+
+    // the 65C02's immediate BIT only has Z to go on
+    fn bit(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        self.set_flags_if(FLAG_Z, self.a & data == 0);
+        if mode != Imm
+        {
+            self.set_flags_if(FLAG_N, data & FLAG_N != 0);
+            self.set_flags_if(FLAG_V, data & FLAG_V != 0);
+        }
+    }
+
+    // the 2A03 has no decimal mode, so D is ignored there. the
+    // 65C02 takes an extra cycle to fix up its decimal flags
+    fn adc(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        self.add(data);
+        if self.is_cmos() && self.decimal_enabled()
+        {
+            self.read(bus, self.pc);
+        }
+    }
+
+    fn sbc(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        self.subtract(data);
+        if self.is_cmos() && self.decimal_enabled()
+        {
+            self.read(bus, self.pc);
+        }
+    }
+
+    fn cmp(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        self.compare(self.a, data);
+    }
+
+    fn cpx(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        self.compare(self.x, data);
+    }
+
+    fn cpy(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        self.compare(self.y, data);
+    }
+
+    // increments and decrements
+    fn inc(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.modify(bus, mode, Self::increment);
+    }
+
+    fn dec(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.modify(bus, mode, Self::decrement);
+    }
+
+    fn inx(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.x = self.increment(self.x);
+    }
+
+    fn iny(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.y = self.increment(self.y);
+    }
+
+    fn dex(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.x = self.decrement(self.x);
+    }
+
+    fn dey(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.y = self.decrement(self.y);
+    }
+
+    // shifts
+    fn asl(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.shift(bus, mode, Self::shift_left);
+    }
+
+    fn lsr(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.shift(bus, mode, Self::shift_right);
+    }
+
+    fn rol(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.shift(bus, mode, Self::rotate_left);
+    }
+
+    fn ror(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.shift(bus, mode, Self::rotate_right);
+    }
+
+    // jumps and calls
+    fn jmp(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.pc = self.operand_addr(bus, mode, Access::Read);
+    }
+
+    // the high byte of the target is only fetched after pc is
+    // pushed, so what ends up on the stack points to it
+    fn jsr(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        let lo = self.read_next_byte(bus);
+        self.peek_stack(bus);
+        let (ret, s) = (self.pc.wrapping_add(1), self.s);
+        self.push_word(bus, self.pc);
+        let hi = self.read(bus, self.pc);
+        self.pc = (hi as u16) << 8 | lo as u16;
+        self.calls.push(FrameKind::Call, self.pc, ret, s);
+    }
+
+    fn rts(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        let s = self.s;
+        self.peek_stack(bus);
+        self.pc = self.pull_word(bus);
+        self.read_next_byte(bus);
+        self.calls.ret(false, s, self.pc);
+    }
+
+    fn brk(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.pc = self.pc.wrapping_add(1); // skip the padding byte
+        self.interrupt(bus, FLAG_B);
+
+        // I is set now, an NMI that came in while we were fetching
+        // the vector waits until after the first handler instruction
+        self.take_nmi = false;
+    }
+
+    fn rti(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        let s = self.s;
+        self.peek_stack(bus);
+        self.p = self.pull(bus) & !FLAG_B | FLAG_U;
+        self.pc = self.pull_word(bus);
+        self.calls.ret(true, s, self.pc);
+    }
+
+    // branches
+    fn bpl(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, !self.is_flag_set(FLAG_N));
+    }
+
+    fn bmi(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, self.is_flag_set(FLAG_N));
+    }
+
+    fn bvc(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, !self.is_flag_set(FLAG_V));
+    }
+
+    fn bvs(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, self.is_flag_set(FLAG_V));
+    }
+
+    fn bcc(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, !self.is_flag_set(FLAG_C));
+    }
+
+    fn bcs(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, self.is_flag_set(FLAG_C));
+    }
+
+    fn bne(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, !self.is_flag_set(FLAG_Z));
+    }
+
+    fn beq(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, self.is_flag_set(FLAG_Z));
+    }
+
+    // flags
+    fn clc(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.clear_flags(FLAG_C);
+    }
+
+    fn sec(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.set_flags(FLAG_C);
+    }
+
+    fn cli(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.clear_flags(FLAG_I);
+    }
+
+    fn sei(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.set_flags(FLAG_I);
+    }
+
+    fn cld(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.clear_flags(FLAG_D);
+    }
+
+    fn sed(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.set_flags(FLAG_D);
+    }
+
+    fn clv(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.clear_flags(FLAG_V);
+    }
+
+    // the unofficial ones still fetch their operand
+    fn nop(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        if mode != Imp
+        {
+            self.load(bus, mode);
+        }
+    }
+
+
+    // unofficial opcodes
+    fn slo(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a |= self.modify(bus, mode, Self::shift_left);
+        self.set_zn(self.a);
+    }
+
+    fn rla(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a &= self.modify(bus, mode, Self::rotate_left);
+        self.set_zn(self.a);
+    }
+
+    fn sre(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a ^= self.modify(bus, mode, Self::shift_right);
+        self.set_zn(self.a);
+    }
+
+    fn rra(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.modify(bus, mode, Self::rotate_right);
+        self.add(data);
+    }
+
+    fn dcp(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.modify(bus, mode, Self::decrement);
+        self.compare(self.a, data);
+    }
+
+    fn isb(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.modify(bus, mode, Self::increment);
+        self.subtract(data);
+    }
+
+    fn sax(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store(bus, mode, self.a & self.x);
+    }
+
+    fn lax(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a = self.load(bus, mode);
+        self.x = self.a;
+        self.set_zn(self.a);
+    }
+
+    fn anc(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a &= self.load(bus, mode);
+        self.set_zn(self.a);
+        self.set_flags_if(FLAG_C, self.a & 0x80 != 0);
+    }
+
+    fn alr(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a &= self.load(bus, mode);
+        self.a = self.shift_right(self.a);
+    }
+
+    // the carry and overflow come out of the adder, not the shifter
+    fn arr(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.a & self.load(bus, mode);
+        self.a = data >> 1 | (self.p & FLAG_C) << 7;
+        self.set_zn(self.a);
+        if self.decimal_enabled()
+        {
+            // each digit gets the adder's decimal fixup on its own
+            self.set_flags_if(FLAG_V, (data ^ self.a) & 0x40 != 0);
+            if (data & 0x0f) + (data & 0x01) > 0x05
+            {
+                self.a = (self.a & 0xf0) | (self.a.wrapping_add(0x06) & 0x0f);
+            }
+            let carry = (data >> 4) + ((data >> 4) & 0x01) > 0x05;
+            self.set_flags_if(FLAG_C, carry);
+            if carry
+            {
+                self.a = self.a.wrapping_add(0x60);
+            }
+            return;
+        }
+        self.set_flags_if(FLAG_C, self.a & 0x40 != 0);
+        self.set_flags_if(FLAG_V, (self.a ^ self.a << 1) & 0x40 != 0);
+    }
+
+    fn sbx(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode);
+        let ax = self.a & self.x;
+        self.compare(ax, data);
+        self.x = ax.wrapping_sub(data);
+    }
+
+    fn ane(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a = (self.a | MAGIC) & self.x & self.load(bus, mode);
+        self.set_zn(self.a);
+    }
+
+    fn lxa(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.a = (self.a | MAGIC) & self.load(bus, mode);
+        self.x = self.a;
+        self.set_zn(self.a);
+    }
+
+    fn sha(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store_and_high(bus, mode, self.a & self.x);
+    }
+
+    fn shx(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store_and_high(bus, mode, self.x);
+    }
+
+    fn shy(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store_and_high(bus, mode, self.y);
+    }
+
+    fn tas(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.s = self.a & self.x;
+        self.store_and_high(bus, mode, self.s);
+    }
+
+    fn las(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        let data = self.load(bus, mode) & self.s;
+        self.a = data;
+        self.x = data;
+        self.s = data;
+        self.set_zn(data);
+    }
+
+    fn jam(&mut self, _bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.halted = true;
+    }
+
+
+    // 65C02 additions
+    fn bra(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.branch(bus, true);
+    }
+
+    fn phx(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.push(bus, self.x);
+    }
+
+    fn phy(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.push(bus, self.y);
+    }
+
+    fn plx(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.peek_stack(bus);
+        self.x = self.pull(bus);
+        self.set_zn(self.x);
+    }
+
+    fn ply(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.peek_stack(bus);
+        self.y = self.pull(bus);
+        self.set_zn(self.y);
+    }
+
+    fn stz(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.store(bus, mode, 0);
+    }
+
+    fn test_and_set(&mut self, data: u8) -> u8
+    {
+        self.set_flags_if(FLAG_Z, self.a & data == 0);
+        data | self.a
+    }
+
+    fn test_and_reset(&mut self, data: u8) -> u8
+    {
+        self.set_flags_if(FLAG_Z, self.a & data == 0);
+        data & !self.a
+    }
+
+    fn tsb(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.modify(bus, mode, Self::test_and_set);
+    }
+
+    fn trb(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.modify(bus, mode, Self::test_and_reset);
+    }
+
+    fn set_bit<const BIT: u8>(&mut self, data: u8) -> u8
+    {
+        data | 1 << BIT
+    }
+
+    fn reset_bit<const BIT: u8>(&mut self, data: u8) -> u8
+    {
+        data & !(1 << BIT)
+    }
+
+    fn smb<const BIT: u8>(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.modify(bus, mode, Self::set_bit::<BIT>);
+    }
+
+    fn rmb<const BIT: u8>(&mut self, bus: &mut dyn Bus, mode: AddrMode)
+    {
+        self.modify(bus, mode, Self::reset_bit::<BIT>);
+    }
+
+    // the zero page byte is read twice before the offset is fetched
+    fn test_bit(&mut self, bus: &mut dyn Bus, bit: u8) -> bool
+    {
+        let addr = self.read_next_byte(bus) as u16;
+        let data = self.read(bus, addr);
+        self.read(bus, addr);
+        data & 1 << bit != 0
+    }
+
+    fn bbs<const BIT: u8>(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        let set = self.test_bit(bus, BIT);
+        self.branch(bus, set);
+    }
+
+    fn bbr<const BIT: u8>(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        let set = self.test_bit(bus, BIT);
+        self.branch(bus, !set);
+    }
+
+    fn wai(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.read(bus, self.pc);
+        self.waiting = true;
+    }
+
+    fn stp(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        self.read(bus, self.pc);
+        self.halted = true;
+    }
+
+    // 0x5c, an eight cycle NOP that reads from the top page
+    fn nop_wide(&mut self, bus: &mut dyn Bus, _mode: AddrMode)
+    {
+        let addr = self.read_next_word(bus);
+        for _ in 0..5
+        {
+            self.read(bus, 0xff00 | (addr & 0x00ff));
+        }
+    }
+
+
+    // BRK, IRQ and NMI share this sequence. the vector is picked after
+    // pc is pushed, so an NMI that arrives before that hijacks the
+    // other two and they never run
+    fn interrupt(&mut self, bus: &mut dyn Bus, flags: u8)
+    {
+        let (ret, s) = (self.pc, self.s);
+        self.push_word(bus, self.pc);
+
+        let (vector, kind) = if self.nmi_pending
+        {
+            self.nmi_pending = false;
+            (NMI_VECTOR, FrameKind::Nmi)
+        }
+        else if flags & FLAG_B != 0
+            { (IRQ_VECTOR, FrameKind::Brk) }
+        else
+            { (IRQ_VECTOR, FrameKind::Irq) };
+
+        self.push(bus, self.p | flags | FLAG_U);
+        self.set_flags(FLAG_I);
+        if self.is_cmos()
+        {
+            self.clear_flags(FLAG_D);
+        }
+        self.pc = self.read_word(bus, vector);
+        self.calls.push(kind, self.pc, ret, s);
+    }
+
+    pub fn is_halted(&self) -> bool
+    {
+        self.halted
+    }
+
+    pub fn is_waiting(&self) -> bool
+    {
+        self.waiting
+    }
+
+    // the subroutines and handlers we're in, and what went wrong with them
+    pub fn calls(&self) -> &CallStack
+    {
+        &self.calls
+    }
+
+    // everything but the call stack, which is only there for debugging
+    pub fn save_state(&self, w: &mut Writer)
+    {
+        for reg in [self.a, self.x, self.y, self.s, self.p]
+        {
+            w.u8(reg);
+        }
+        w.u16(self.pc);
+        w.u64(self.cycles);
+        w.u8(self.variant as u8);
+        for flag in [self.halted, self.waiting, self.nmi_line, self.irq_line,
+                     self.nmi_last, self.nmi_pending, self.irq_pending,
+                     self.take_nmi, self.take_irq]
+        {
+            w.bool(flag);
+        }
+    }
+
+    pub fn load_state(r: &mut Reader) -> Result<CPU, state::Error>
+    {
+        let [a, x, y, s, p] = r.array()?;
+        let pc = r.u16()?;
+        let cycles = r.u64()?;
+        let variant = Variant::from_u8(r.u8()?)
+            .ok_or(state::Error::BadValue("cpu variant"))?;
+
+        let mut cpu = CPU::with_variant(variant);
+        (cpu.a, cpu.x, cpu.y, cpu.s, cpu.p, cpu.pc, cpu.cycles) = (a, x, y, s, p, pc, cycles);
+        for flag in [&mut cpu.halted, &mut cpu.waiting, &mut cpu.nmi_line, &mut cpu.irq_line,
+                     &mut cpu.nmi_last, &mut cpu.nmi_pending, &mut cpu.irq_pending,
+                     &mut cpu.take_nmi, &mut cpu.take_irq]
+        {
+            *flag = r.bool()?;
+        }
+        Ok(cpu)
+    }
+
+    pub fn step(&mut self, bus: &mut dyn Bus)
+    {
+        if self.halted
+        {
+            // the bus is left reading $ffff forever
+            self.read(bus, 0xffff);
+            return;
+        }
+
+        self.calls.start(self.pc);
+        if self.waiting
+        {
+            // any interrupt wakes it up, even an IRQ while I is set.
+            // then it either gets serviced below or execution goes on
+            self.read(bus, self.pc);
+            if self.nmi_pending || self.irq_line
+            {
+                self.waiting = false;
+            }
+        }
+        else
+        {
+            let opcode = self.read_next_byte(bus);
+            self.execute(bus, self.variant.opcodes()[opcode as usize]);
+        }
+        self.poll(bus);
+    }
+
+    // same as step, but instructions in rom come out of cache instead
+    // of being fetched and decoded again. anything else is left to step
+    pub fn step_cached(&mut self, bus: &mut dyn Bus, cache: &mut DecodeCache)
+    {
+        if self.halted || self.waiting
+        {
+            return self.step(bus);
+        }
+        let Some(decoded) = cache.get(bus, self.variant, self.pc)
+        else { return self.step(bus) };
+
+        self.calls.start(self.pc);
+        self.tick(bus); // the opcode fetch
+        self.pc = self.pc.wrapping_add(1);
+        let len = decoded.opcode.mode.operand_len() as usize;
+        let [lo, hi] = decoded.operand.map(|b| 0x100 | b as u32);
+        self.prefetched = match len
+        {
+            0 => 0,
+            1 => lo,
+            _ => lo | hi << 16,
+        };
+        self.execute(bus, *decoded.opcode);
+        self.prefetched = 0;
+        self.poll(bus);
+    }
+
+    // runs an instruction whose opcode was just fetched
+    fn execute(&mut self, bus: &mut dyn Bus, opcode: Opcode)
+    {
+        if let (Imp | Acc, 2..) = (opcode.mode, opcode.cycles)
+        {
+            // single byte instructions still read the next one,
+            // except for the 65C02's one cycle NOPs
+            self.read(bus, self.pc);
+        }
+        (opcode.op)(self, bus, opcode.mode);
+        self.calls.prune(self.s);
+    }
+
+    // takes an interrupt if one was seen in time
+    fn poll(&mut self, bus: &mut dyn Bus)
+    {
+        if self.take_nmi || self.take_irq
+        {
+            // the opcode fetch is thrown away and a BRK is forced in
+            self.read(bus, self.pc);
+            self.read(bus, self.pc);
+            self.interrupt(bus, 0);
+        }
+    }
+
+}
+
+impl Display for CPU
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        write!(f, "A: 0x{:02X} X: 0x{:02X} Y: 0x{:02X}  NV-BDIZC\n\
+                  PC: 0x{:04X} S: 0x{:02X}       {:08b}",
+                  self.a, self.x, self.y, self.pc, self.s, self.p)
+
     }
 }
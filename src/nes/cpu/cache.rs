@@ -0,0 +1,148 @@
+// Decoded instructions kept around so the hot loop doesn't have to go
+// through the bus and the decode table for every opcode and operand.
+// Only rom is cached, in pages of 8K like the banks mappers switch,
+// keyed by where they start in the rom file, so a bank that's switched
+// out and back in again is still decoded. Which page each 8K of the
+// cpu's address space shows is looked up once and then kept until the
+// bus says its banks have been switched. Code running from ram is
+// always fetched the slow way, so writing to it can't leave anything
+// stale behind.
+//
+// The cycles are the same either way: a cached fetch still takes its
+// cycle, it just doesn't ask the bus for a byte that can't have changed.
+
+use super::{ Opcode, Variant };
+use crate::nes::bus::Bus;
+
+use std::fmt::{ Debug, Formatter };
+
+const PAGE_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy)]
+pub struct Decoded
+{
+    pub opcode: &'static Opcode,
+    pub operand: [u8; 2],
+}
+
+type Page = Box<[Option<Decoded>; PAGE_SIZE]>;
+
+// what one 8K window of the address space has in it
+#[derive(Clone, Copy, PartialEq)]
+enum Window
+{
+    Unknown,
+    Page(usize), // index into pages
+    Uncached,    // ram, registers or rom mapped finer than 8K
+}
+
+pub struct DecodeCache
+{
+    variant: Variant,
+    pages: Vec<(usize, Page)>, // file offset it starts at, what's in it
+    windows: [Window; 8],
+    switches: u64, // the bus's count of bank switches the windows are from
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeCache
+{
+    pub fn new(variant: Variant) -> DecodeCache
+    {
+        DecodeCache {
+            variant,
+            pages: Vec::new(),
+            windows: [Window::Unknown; 8],
+            switches: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // for when the rom itself changes, like another cart going in
+    pub fn clear(&mut self)
+    {
+        self.pages.clear();
+        self.windows = [Window::Unknown; 8];
+    }
+
+    // the instruction at addr, if it's all in one page of rom
+    pub fn get(&mut self, bus: &dyn Bus, variant: Variant, addr: u16) -> Option<Decoded>
+    {
+        if variant != self.variant
+        {
+            self.clear();
+            self.variant = variant;
+        }
+        let switches = bus.bank_switches();
+        if switches != self.switches
+        {
+            self.windows = [Window::Unknown; 8];
+            self.switches = switches;
+        }
+
+        let window = (addr >> 13) as usize;
+        if self.windows[window] == Window::Unknown
+        {
+            self.windows[window] = self.lookup(bus, addr & 0xe000);
+        }
+        let Window::Page(page) = self.windows[window]
+        else { return None };
+
+        let index = addr as usize % PAGE_SIZE;
+        let entry = &mut self.pages[page].1[index];
+        if let Some(decoded) = *entry
+        {
+            self.hits += 1;
+            return Some(decoded);
+        }
+
+        let opcode = &self.variant.opcodes()[bus.peek(addr) as usize];
+        let len = opcode.mode.operand_len() as usize;
+        if index + len >= PAGE_SIZE
+        {
+            // runs into the next window, which might get switched
+            return None;
+        }
+        self.misses += 1;
+        let mut operand = [0; 2];
+        for (i, byte) in operand.iter_mut().enumerate().take(len)
+        {
+            *byte = bus.peek(addr + i as u16 + 1);
+        }
+        *entry = Some(Decoded { opcode, operand });
+        *entry
+    }
+
+    // which page the window at base shows, if all of it is rom
+    fn lookup(&mut self, bus: &dyn Bus, base: u16) -> Window
+    {
+        let (Some(start), Some(end)) = (bus.rom_offset(base), bus.rom_offset(base + 0x1fff))
+        else { return Window::Uncached };
+        if end != start + PAGE_SIZE - 1
+        {
+            return Window::Uncached;
+        }
+
+        match self.pages.iter().position(|(offset, _)| *offset == start)
+        {
+            Some(page) => Window::Page(page),
+            None =>
+            {
+                let page = vec![None; PAGE_SIZE].into_boxed_slice().try_into();
+                self.pages.push((start, page.unwrap_or_else(|_| unreachable!())));
+                Window::Page(self.pages.len() - 1)
+            }
+        }
+    }
+}
+
+impl Debug for DecodeCache
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        write!(f, "DecodeCache {{ pages: {}, hits: {}, misses: {} }}",
+               self.pages.len(), self.hits, self.misses)
+    }
+}
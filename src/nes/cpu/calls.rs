@@ -0,0 +1,323 @@
+// Keeps track of the subroutines and interrupt handlers the cpu is in,
+// so a debugger can show how it got where it is. Frames are matched to
+// returns by the stack pointer and not by counting, since games pull
+// return addresses off by hand, reset s with TXS and push an address
+// to RTS to it as a jump. Only what can't be explained by any of that
+// ends up as a diagnostic.
+
+use std::collections::VecDeque;
+use std::fmt::{ Display, Formatter };
+
+// how many diagnostics are kept, a cpu running data makes plenty
+const MAX_DIAGNOSTICS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind
+{
+    Call, // JSR
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame
+{
+    pub kind: FrameKind,
+    pub from: u16,   // the JSR, or the instruction the interrupt came after
+    pub target: u16, // where it went
+    pub ret: u16,    // where it should come back to
+    pub s: u8,       // the stack pointer before anything was pushed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Diagnostic
+{
+    // a push wrapped s from $00 to $FF, pc is the instruction that did it
+    Overflow { pc: u16 },
+    // a pull wrapped s from $FF to $00
+    Underflow { pc: u16 },
+    // an RTS out of an interrupt handler, or an RTI out of a subroutine
+    Mismatch { pc: u16, frame: Frame },
+    // the return went somewhere else than where the frame came from
+    WrongReturn { pc: u16, frame: Frame, to: u16 },
+}
+
+impl Display for Diagnostic
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        match self
+        {
+            Diagnostic::Overflow { pc } =>
+                write!(f, "${:04X}: stack overflow", pc),
+            Diagnostic::Underflow { pc } =>
+                write!(f, "${:04X}: stack underflow", pc),
+            Diagnostic::Mismatch { pc, frame } =>
+            {
+                let op = if frame.kind == FrameKind::Call { "RTI" } else { "RTS" };
+                write!(f, "${:04X}: {} out of {}", pc, op, frame)
+            }
+            Diagnostic::WrongReturn { pc, frame, to } =>
+                write!(f, "${:04X}: returned to ${:04X} out of {}", pc, to, frame),
+        }
+    }
+}
+
+impl Frame
+{
+    // s right after the frame was pushed
+    fn entry(&self) -> u8
+    {
+        let pushed = if self.kind == FrameKind::Call { 2 } else { 3 };
+        self.s.wrapping_sub(pushed)
+    }
+}
+
+impl Display for Frame
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        let kind = match self.kind
+        {
+            FrameKind::Call => "JSR",
+            FrameKind::Nmi => "NMI",
+            FrameKind::Irq => "IRQ",
+            FrameKind::Brk => "BRK",
+        };
+        write!(f, "{} ${:04X} from ${:04X}, returns to ${:04X}",
+               kind, self.target, self.from, self.ret)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CallStack
+{
+    frames: Vec<Frame>,
+    diagnostics: VecDeque<Diagnostic>,
+    count: u64, // diagnostics ever seen, including the dropped ones
+
+    pc: u16, // the instruction being run, for the diagnostics
+}
+
+impl CallStack
+{
+    pub fn new() -> CallStack
+    {
+        CallStack::default()
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.frames.clear();
+        self.diagnostics.clear();
+    }
+
+    // innermost last
+    pub fn frames(&self) -> &[Frame]
+    {
+        &self.frames
+    }
+
+    // oldest first
+    pub fn diagnostics(&self) -> impl Iterator<Item = &Diagnostic>
+    {
+        self.diagnostics.iter()
+    }
+
+    // goes up with every diagnostic, so a change means something new happened
+    pub fn count(&self) -> u64
+    {
+        self.count
+    }
+
+    pub(super) fn start(&mut self, pc: u16)
+    {
+        self.pc = pc;
+    }
+
+    // a JSR or an interrupt, coming from the instruction being run
+    pub(super) fn push(&mut self, kind: FrameKind, target: u16, ret: u16, s: u8)
+    {
+        self.frames.push(Frame { kind, from: self.pc, target, ret, s });
+    }
+
+    pub(super) fn overflow(&mut self)
+    {
+        self.flag(Diagnostic::Overflow { pc: self.pc });
+    }
+
+    pub(super) fn underflow(&mut self)
+    {
+        self.flag(Diagnostic::Underflow { pc: self.pc });
+    }
+
+    // an RTS or RTI (rti true) started pulling with s at s and went to
+    // to. the frame it's returning from is the one whose pushes left s
+    // there, whether it pulls as much as that frame pushed is another
+    // matter
+    pub(super) fn ret(&mut self, rti: bool, s: u8, to: u16)
+    {
+        // frames left behind by pulling their address off by hand
+        while self.frames.last().is_some_and(|f| f.entry() < s)
+        {
+            self.frames.pop();
+        }
+
+        let Some(&frame) = self.frames.last()
+        else { return };
+        if frame.entry() != s
+        {
+            // still inside the frame, it's an RTS used as a jump
+            return;
+        }
+
+        self.frames.pop();
+        if rti != (frame.kind != FrameKind::Call)
+        {
+            self.flag(Diagnostic::Mismatch { pc: self.pc, frame });
+        }
+        else if to != frame.ret
+        {
+            self.flag(Diagnostic::WrongReturn { pc: self.pc, frame, to });
+        }
+    }
+
+    // drops the frames whose return address is no longer on the stack
+    // now that s is where it is, after a PLA or a TXS say
+    pub(super) fn prune(&mut self, s: u8)
+    {
+        while self.frames.last().is_some_and(|f| f.s <= s)
+        {
+            self.frames.pop();
+        }
+    }
+
+    fn flag(&mut self, diagnostic: Diagnostic)
+    {
+        if self.diagnostics.len() == MAX_DIAGNOSTICS
+        {
+            self.diagnostics.pop_front();
+        }
+        self.diagnostics.push_back(diagnostic);
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::asm;
+    use crate::nes::cpu::CPU;
+    use crate::nes::harness::{ FlatRam, Stop, run_until_trap };
+
+    // runs source from $0200 until it traps, and what it got flagged for
+    fn diagnostics(source: &str) -> Vec<Diagnostic>
+    {
+        let mut ram = FlatRam::new();
+        asm::assemble(source).unwrap().load(&mut ram);
+        let mut cpu = CPU::new();
+        cpu.pc = 0x0200;
+        assert!(matches!(run_until_trap(&mut cpu, &mut ram, 10_000), Stop::Trap(_)));
+        cpu.calls().diagnostics().copied().collect()
+    }
+
+    #[test]
+    fn overflow()
+    {
+        let found = diagnostics("
+                .org $0200
+                ldx #$01
+                txs
+                pha
+                pha             ; $0204, s goes from $00 to $FF
+        done:   jmp done
+        ");
+        assert_eq!(found, [Diagnostic::Overflow { pc: 0x0204 }]);
+        assert_eq!(found[0].to_string(), "$0204: stack overflow");
+    }
+
+    #[test]
+    fn underflow()
+    {
+        let found = diagnostics("
+                .org $0200
+                ldx #$fe
+                txs
+                pla
+                pla             ; $0204, s goes from $FF to $00
+        done:   jmp done
+        ");
+        assert_eq!(found, [Diagnostic::Underflow { pc: 0x0204 }]);
+        assert_eq!(found[0].to_string(), "$0204: stack underflow");
+    }
+
+    #[test]
+    fn mismatch()
+    {
+        // the rti takes the jsr's address as p and the byte above it
+        // as the high byte of where it goes
+        let found = diagnostics("
+                .org $0200
+                ldx #$f0
+                txs
+                jsr sub         ; $0203
+        sub:    rti             ; $0206
+                .org $01f1
+                .byte $03
+                .org $0302
+        done:   jmp done
+        ");
+        let frame = Frame { kind: FrameKind::Call, from: 0x0203, target: 0x0206, ret: 0x0206, s: 0xf0 };
+        assert_eq!(found, [Diagnostic::Mismatch { pc: 0x0206, frame }]);
+        assert_eq!(found[0].to_string(),
+                   "$0206: RTI out of JSR $0206 from $0203, returns to $0206");
+    }
+
+    #[test]
+    fn wrong_return()
+    {
+        // the return address is changed where it is on the stack
+        let found = diagnostics("
+                .org $0200
+                ldx #$ff
+                txs
+                jsr sub         ; $0203
+                nop
+        done:   jmp done        ; $0207
+        sub:    tsx             ; $020A
+                inc $0101,x
+                rts             ; $020E
+        ");
+        let frame = Frame { kind: FrameKind::Call, from: 0x0203, target: 0x020a, ret: 0x0206, s: 0xff };
+        assert_eq!(found, [Diagnostic::WrongReturn { pc: 0x020e, frame, to: 0x0207 }]);
+        assert_eq!(found[0].to_string(),
+                   "$020E: returned to $0207 out of JSR $020A from $0203, returns to $0206");
+    }
+
+    #[test]
+    fn returns_that_are_fine()
+    {
+        // a plain call, an address pulled off by hand, and an rts used
+        // as a jump
+        let found = diagnostics("
+                .org $0200
+                ldx #$ff
+                txs
+                jsr sub
+                jsr drop
+        back:   lda #>done
+                pha
+                lda #<done - 1
+                pha
+                rts
+        sub:    rts
+        drop:   pla
+                pla
+                jmp back
+        done:   jmp done
+        ");
+        assert_eq!(found, []);
+    }
+}
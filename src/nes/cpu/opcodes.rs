@@ -0,0 +1,583 @@
+// The decode table. Every opcode maps to the operation that executes
+// it and the addressing mode its operand is fetched with, along with
+// what the disassembler and the cycle counter need to know about it.
+
+use super::{ CPU, AddrMode, AddrMode::* };
+use crate::nes::bus::Bus;
+
+#[derive(Clone, Copy)]
+pub struct Opcode
+{
+    pub mnemonic: &'static str,
+    pub op: fn(&mut CPU, &mut dyn Bus, AddrMode),
+    pub mode: AddrMode,
+    pub cycles: u8, // base count, without page crossing and branch penalties
+    pub official: bool,
+}
+
+const fn entry(mnemonic: &'static str, op: fn(&mut CPU, &mut dyn Bus, AddrMode),
+               mode: AddrMode, cycles: u8) -> Opcode
+{
+    Opcode { mnemonic, op, mode, cycles, official: true }
+}
+
+// the undocumented ones. most are two official instructions glued
+// together since the decoder enables both of them at once
+const fn unofficial(mnemonic: &'static str, op: fn(&mut CPU, &mut dyn Bus, AddrMode),
+                    mode: AddrMode, cycles: u8) -> Opcode
+{
+    Opcode { mnemonic, op, mode, cycles, official: false }
+}
+
+pub static OPCODES: [Opcode; 256] = [
+    // 0x00
+    entry("BRK", CPU::brk, Imp, 7),
+    entry("ORA", CPU::ora, IndX, 6),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("SLO", CPU::slo, IndX, 8),
+    unofficial("NOP", CPU::nop, Zpg, 3),
+    entry("ORA", CPU::ora, Zpg, 3),
+    entry("ASL", CPU::asl, Zpg, 5),
+    unofficial("SLO", CPU::slo, Zpg, 5),
+    entry("PHP", CPU::php, Imp, 3),
+    entry("ORA", CPU::ora, Imm, 2),
+    entry("ASL", CPU::asl, Acc, 2),
+    unofficial("ANC", CPU::anc, Imm, 2),
+    unofficial("NOP", CPU::nop, Abs, 4),
+    entry("ORA", CPU::ora, Abs, 4),
+    entry("ASL", CPU::asl, Abs, 6),
+    unofficial("SLO", CPU::slo, Abs, 6),
+    // 0x10
+    entry("BPL", CPU::bpl, Rel, 2),
+    entry("ORA", CPU::ora, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("SLO", CPU::slo, IndY, 8),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("ORA", CPU::ora, ZpgX, 4),
+    entry("ASL", CPU::asl, ZpgX, 6),
+    unofficial("SLO", CPU::slo, ZpgX, 6),
+    entry("CLC", CPU::clc, Imp, 2),
+    entry("ORA", CPU::ora, AbsY, 4),
+    unofficial("NOP", CPU::nop, Imp, 2),
+    unofficial("SLO", CPU::slo, AbsY, 7),
+    unofficial("NOP", CPU::nop, AbsX, 4),
+    entry("ORA", CPU::ora, AbsX, 4),
+    entry("ASL", CPU::asl, AbsX, 7),
+    unofficial("SLO", CPU::slo, AbsX, 7),
+    // 0x20
+    entry("JSR", CPU::jsr, Abs, 6),
+    entry("AND", CPU::and, IndX, 6),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("RLA", CPU::rla, IndX, 8),
+    entry("BIT", CPU::bit, Zpg, 3),
+    entry("AND", CPU::and, Zpg, 3),
+    entry("ROL", CPU::rol, Zpg, 5),
+    unofficial("RLA", CPU::rla, Zpg, 5),
+    entry("PLP", CPU::plp, Imp, 4),
+    entry("AND", CPU::and, Imm, 2),
+    entry("ROL", CPU::rol, Acc, 2),
+    unofficial("ANC", CPU::anc, Imm, 2),
+    entry("BIT", CPU::bit, Abs, 4),
+    entry("AND", CPU::and, Abs, 4),
+    entry("ROL", CPU::rol, Abs, 6),
+    unofficial("RLA", CPU::rla, Abs, 6),
+    // 0x30
+    entry("BMI", CPU::bmi, Rel, 2),
+    entry("AND", CPU::and, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("RLA", CPU::rla, IndY, 8),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("AND", CPU::and, ZpgX, 4),
+    entry("ROL", CPU::rol, ZpgX, 6),
+    unofficial("RLA", CPU::rla, ZpgX, 6),
+    entry("SEC", CPU::sec, Imp, 2),
+    entry("AND", CPU::and, AbsY, 4),
+    unofficial("NOP", CPU::nop, Imp, 2),
+    unofficial("RLA", CPU::rla, AbsY, 7),
+    unofficial("NOP", CPU::nop, AbsX, 4),
+    entry("AND", CPU::and, AbsX, 4),
+    entry("ROL", CPU::rol, AbsX, 7),
+    unofficial("RLA", CPU::rla, AbsX, 7),
+    // 0x40
+    entry("RTI", CPU::rti, Imp, 6),
+    entry("EOR", CPU::eor, IndX, 6),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("SRE", CPU::sre, IndX, 8),
+    unofficial("NOP", CPU::nop, Zpg, 3),
+    entry("EOR", CPU::eor, Zpg, 3),
+    entry("LSR", CPU::lsr, Zpg, 5),
+    unofficial("SRE", CPU::sre, Zpg, 5),
+    entry("PHA", CPU::pha, Imp, 3),
+    entry("EOR", CPU::eor, Imm, 2),
+    entry("LSR", CPU::lsr, Acc, 2),
+    unofficial("ALR", CPU::alr, Imm, 2),
+    entry("JMP", CPU::jmp, Abs, 3),
+    entry("EOR", CPU::eor, Abs, 4),
+    entry("LSR", CPU::lsr, Abs, 6),
+    unofficial("SRE", CPU::sre, Abs, 6),
+    // 0x50
+    entry("BVC", CPU::bvc, Rel, 2),
+    entry("EOR", CPU::eor, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("SRE", CPU::sre, IndY, 8),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("EOR", CPU::eor, ZpgX, 4),
+    entry("LSR", CPU::lsr, ZpgX, 6),
+    unofficial("SRE", CPU::sre, ZpgX, 6),
+    entry("CLI", CPU::cli, Imp, 2),
+    entry("EOR", CPU::eor, AbsY, 4),
+    unofficial("NOP", CPU::nop, Imp, 2),
+    unofficial("SRE", CPU::sre, AbsY, 7),
+    unofficial("NOP", CPU::nop, AbsX, 4),
+    entry("EOR", CPU::eor, AbsX, 4),
+    entry("LSR", CPU::lsr, AbsX, 7),
+    unofficial("SRE", CPU::sre, AbsX, 7),
+    // 0x60
+    entry("RTS", CPU::rts, Imp, 6),
+    entry("ADC", CPU::adc, IndX, 6),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("RRA", CPU::rra, IndX, 8),
+    unofficial("NOP", CPU::nop, Zpg, 3),
+    entry("ADC", CPU::adc, Zpg, 3),
+    entry("ROR", CPU::ror, Zpg, 5),
+    unofficial("RRA", CPU::rra, Zpg, 5),
+    entry("PLA", CPU::pla, Imp, 4),
+    entry("ADC", CPU::adc, Imm, 2),
+    entry("ROR", CPU::ror, Acc, 2),
+    unofficial("ARR", CPU::arr, Imm, 2),
+    entry("JMP", CPU::jmp, Ind, 5),
+    entry("ADC", CPU::adc, Abs, 4),
+    entry("ROR", CPU::ror, Abs, 6),
+    unofficial("RRA", CPU::rra, Abs, 6),
+    // 0x70
+    entry("BVS", CPU::bvs, Rel, 2),
+    entry("ADC", CPU::adc, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("RRA", CPU::rra, IndY, 8),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("ADC", CPU::adc, ZpgX, 4),
+    entry("ROR", CPU::ror, ZpgX, 6),
+    unofficial("RRA", CPU::rra, ZpgX, 6),
+    entry("SEI", CPU::sei, Imp, 2),
+    entry("ADC", CPU::adc, AbsY, 4),
+    unofficial("NOP", CPU::nop, Imp, 2),
+    unofficial("RRA", CPU::rra, AbsY, 7),
+    unofficial("NOP", CPU::nop, AbsX, 4),
+    entry("ADC", CPU::adc, AbsX, 4),
+    entry("ROR", CPU::ror, AbsX, 7),
+    unofficial("RRA", CPU::rra, AbsX, 7),
+    // 0x80
+    unofficial("NOP", CPU::nop, Imm, 2),
+    entry("STA", CPU::sta, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("SAX", CPU::sax, IndX, 6),
+    entry("STY", CPU::sty, Zpg, 3),
+    entry("STA", CPU::sta, Zpg, 3),
+    entry("STX", CPU::stx, Zpg, 3),
+    unofficial("SAX", CPU::sax, Zpg, 3),
+    entry("DEY", CPU::dey, Imp, 2),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    entry("TXA", CPU::txa, Imp, 2),
+    unofficial("ANE", CPU::ane, Imm, 2),
+    entry("STY", CPU::sty, Abs, 4),
+    entry("STA", CPU::sta, Abs, 4),
+    entry("STX", CPU::stx, Abs, 4),
+    unofficial("SAX", CPU::sax, Abs, 4),
+    // 0x90
+    entry("BCC", CPU::bcc, Rel, 2),
+    entry("STA", CPU::sta, IndY, 6),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("SHA", CPU::sha, IndY, 6),
+    entry("STY", CPU::sty, ZpgX, 4),
+    entry("STA", CPU::sta, ZpgX, 4),
+    entry("STX", CPU::stx, ZpgY, 4),
+    unofficial("SAX", CPU::sax, ZpgY, 4),
+    entry("TYA", CPU::tya, Imp, 2),
+    entry("STA", CPU::sta, AbsY, 5),
+    entry("TXS", CPU::txs, Imp, 2),
+    unofficial("TAS", CPU::tas, AbsY, 5),
+    unofficial("SHY", CPU::shy, AbsX, 5),
+    entry("STA", CPU::sta, AbsX, 5),
+    unofficial("SHX", CPU::shx, AbsY, 5),
+    unofficial("SHA", CPU::sha, AbsY, 5),
+    // 0xa0
+    entry("LDY", CPU::ldy, Imm, 2),
+    entry("LDA", CPU::lda, IndX, 6),
+    entry("LDX", CPU::ldx, Imm, 2),
+    unofficial("LAX", CPU::lax, IndX, 6),
+    entry("LDY", CPU::ldy, Zpg, 3),
+    entry("LDA", CPU::lda, Zpg, 3),
+    entry("LDX", CPU::ldx, Zpg, 3),
+    unofficial("LAX", CPU::lax, Zpg, 3),
+    entry("TAY", CPU::tay, Imp, 2),
+    entry("LDA", CPU::lda, Imm, 2),
+    entry("TAX", CPU::tax, Imp, 2),
+    unofficial("LXA", CPU::lxa, Imm, 2),
+    entry("LDY", CPU::ldy, Abs, 4),
+    entry("LDA", CPU::lda, Abs, 4),
+    entry("LDX", CPU::ldx, Abs, 4),
+    unofficial("LAX", CPU::lax, Abs, 4),
+    // 0xb0
+    entry("BCS", CPU::bcs, Rel, 2),
+    entry("LDA", CPU::lda, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("LAX", CPU::lax, IndY, 5),
+    entry("LDY", CPU::ldy, ZpgX, 4),
+    entry("LDA", CPU::lda, ZpgX, 4),
+    entry("LDX", CPU::ldx, ZpgY, 4),
+    unofficial("LAX", CPU::lax, ZpgY, 4),
+    entry("CLV", CPU::clv, Imp, 2),
+    entry("LDA", CPU::lda, AbsY, 4),
+    entry("TSX", CPU::tsx, Imp, 2),
+    unofficial("LAS", CPU::las, AbsY, 4),
+    entry("LDY", CPU::ldy, AbsX, 4),
+    entry("LDA", CPU::lda, AbsX, 4),
+    entry("LDX", CPU::ldx, AbsY, 4),
+    unofficial("LAX", CPU::lax, AbsY, 4),
+    // 0xc0
+    entry("CPY", CPU::cpy, Imm, 2),
+    entry("CMP", CPU::cmp, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("DCP", CPU::dcp, IndX, 8),
+    entry("CPY", CPU::cpy, Zpg, 3),
+    entry("CMP", CPU::cmp, Zpg, 3),
+    entry("DEC", CPU::dec, Zpg, 5),
+    unofficial("DCP", CPU::dcp, Zpg, 5),
+    entry("INY", CPU::iny, Imp, 2),
+    entry("CMP", CPU::cmp, Imm, 2),
+    entry("DEX", CPU::dex, Imp, 2),
+    unofficial("SBX", CPU::sbx, Imm, 2),
+    entry("CPY", CPU::cpy, Abs, 4),
+    entry("CMP", CPU::cmp, Abs, 4),
+    entry("DEC", CPU::dec, Abs, 6),
+    unofficial("DCP", CPU::dcp, Abs, 6),
+    // 0xd0
+    entry("BNE", CPU::bne, Rel, 2),
+    entry("CMP", CPU::cmp, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("DCP", CPU::dcp, IndY, 8),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("CMP", CPU::cmp, ZpgX, 4),
+    entry("DEC", CPU::dec, ZpgX, 6),
+    unofficial("DCP", CPU::dcp, ZpgX, 6),
+    entry("CLD", CPU::cld, Imp, 2),
+    entry("CMP", CPU::cmp, AbsY, 4),
+    unofficial("NOP", CPU::nop, Imp, 2),
+    unofficial("DCP", CPU::dcp, AbsY, 7),
+    unofficial("NOP", CPU::nop, AbsX, 4),
+    entry("CMP", CPU::cmp, AbsX, 4),
+    entry("DEC", CPU::dec, AbsX, 7),
+    unofficial("DCP", CPU::dcp, AbsX, 7),
+    // 0xe0
+    entry("CPX", CPU::cpx, Imm, 2),
+    entry("SBC", CPU::sbc, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("ISB", CPU::isb, IndX, 8),
+    entry("CPX", CPU::cpx, Zpg, 3),
+    entry("SBC", CPU::sbc, Zpg, 3),
+    entry("INC", CPU::inc, Zpg, 5),
+    unofficial("ISB", CPU::isb, Zpg, 5),
+    entry("INX", CPU::inx, Imp, 2),
+    entry("SBC", CPU::sbc, Imm, 2),
+    entry("NOP", CPU::nop, Imp, 2),
+    unofficial("SBC", CPU::sbc, Imm, 2),
+    entry("CPX", CPU::cpx, Abs, 4),
+    entry("SBC", CPU::sbc, Abs, 4),
+    entry("INC", CPU::inc, Abs, 6),
+    unofficial("ISB", CPU::isb, Abs, 6),
+    // 0xf0
+    entry("BEQ", CPU::beq, Rel, 2),
+    entry("SBC", CPU::sbc, IndY, 5),
+    unofficial("JAM", CPU::jam, Imp, 2),
+    unofficial("ISB", CPU::isb, IndY, 8),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("SBC", CPU::sbc, ZpgX, 4),
+    entry("INC", CPU::inc, ZpgX, 6),
+    unofficial("ISB", CPU::isb, ZpgX, 6),
+    entry("SED", CPU::sed, Imp, 2),
+    entry("SBC", CPU::sbc, AbsY, 4),
+    unofficial("NOP", CPU::nop, Imp, 2),
+    unofficial("ISB", CPU::isb, AbsY, 7),
+    unofficial("NOP", CPU::nop, AbsX, 4),
+    entry("SBC", CPU::sbc, AbsX, 4),
+    entry("INC", CPU::inc, AbsX, 7),
+    unofficial("ISB", CPU::isb, AbsX, 7),
+];
+
+// the 65C02 keeps the official opcodes (a few with new timings), puts
+// new instructions in most of the holes and turns the rest into NOPs
+// of different lengths. it never jams
+pub static CMOS_OPCODES: [Opcode; 256] = [
+    // 0x00
+    entry("BRK", CPU::brk, Imp, 7),
+    entry("ORA", CPU::ora, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("TSB", CPU::tsb, Zpg, 5),
+    entry("ORA", CPU::ora, Zpg, 3),
+    entry("ASL", CPU::asl, Zpg, 5),
+    entry("RMB0", CPU::rmb::<0>, Zpg, 5),
+    entry("PHP", CPU::php, Imp, 3),
+    entry("ORA", CPU::ora, Imm, 2),
+    entry("ASL", CPU::asl, Acc, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("TSB", CPU::tsb, Abs, 6),
+    entry("ORA", CPU::ora, Abs, 4),
+    entry("ASL", CPU::asl, Abs, 6),
+    entry("BBR0", CPU::bbr::<0>, ZpgRel, 5),
+    // 0x10
+    entry("BPL", CPU::bpl, Rel, 2),
+    entry("ORA", CPU::ora, IndY, 5),
+    entry("ORA", CPU::ora, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("TRB", CPU::trb, Zpg, 5),
+    entry("ORA", CPU::ora, ZpgX, 4),
+    entry("ASL", CPU::asl, ZpgX, 6),
+    entry("RMB1", CPU::rmb::<1>, Zpg, 5),
+    entry("CLC", CPU::clc, Imp, 2),
+    entry("ORA", CPU::ora, AbsY, 4),
+    entry("INC", CPU::inc, Acc, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("TRB", CPU::trb, Abs, 6),
+    entry("ORA", CPU::ora, AbsX, 4),
+    entry("ASL", CPU::asl, AbsX, 6),
+    entry("BBR1", CPU::bbr::<1>, ZpgRel, 5),
+    // 0x20
+    entry("JSR", CPU::jsr, Abs, 6),
+    entry("AND", CPU::and, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("BIT", CPU::bit, Zpg, 3),
+    entry("AND", CPU::and, Zpg, 3),
+    entry("ROL", CPU::rol, Zpg, 5),
+    entry("RMB2", CPU::rmb::<2>, Zpg, 5),
+    entry("PLP", CPU::plp, Imp, 4),
+    entry("AND", CPU::and, Imm, 2),
+    entry("ROL", CPU::rol, Acc, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("BIT", CPU::bit, Abs, 4),
+    entry("AND", CPU::and, Abs, 4),
+    entry("ROL", CPU::rol, Abs, 6),
+    entry("BBR2", CPU::bbr::<2>, ZpgRel, 5),
+    // 0x30
+    entry("BMI", CPU::bmi, Rel, 2),
+    entry("AND", CPU::and, IndY, 5),
+    entry("AND", CPU::and, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("BIT", CPU::bit, ZpgX, 4),
+    entry("AND", CPU::and, ZpgX, 4),
+    entry("ROL", CPU::rol, ZpgX, 6),
+    entry("RMB3", CPU::rmb::<3>, Zpg, 5),
+    entry("SEC", CPU::sec, Imp, 2),
+    entry("AND", CPU::and, AbsY, 4),
+    entry("DEC", CPU::dec, Acc, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("BIT", CPU::bit, AbsX, 4),
+    entry("AND", CPU::and, AbsX, 4),
+    entry("ROL", CPU::rol, AbsX, 6),
+    entry("BBR3", CPU::bbr::<3>, ZpgRel, 5),
+    // 0x40
+    entry("RTI", CPU::rti, Imp, 6),
+    entry("EOR", CPU::eor, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    unofficial("NOP", CPU::nop, Zpg, 3),
+    entry("EOR", CPU::eor, Zpg, 3),
+    entry("LSR", CPU::lsr, Zpg, 5),
+    entry("RMB4", CPU::rmb::<4>, Zpg, 5),
+    entry("PHA", CPU::pha, Imp, 3),
+    entry("EOR", CPU::eor, Imm, 2),
+    entry("LSR", CPU::lsr, Acc, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("JMP", CPU::jmp, Abs, 3),
+    entry("EOR", CPU::eor, Abs, 4),
+    entry("LSR", CPU::lsr, Abs, 6),
+    entry("BBR4", CPU::bbr::<4>, ZpgRel, 5),
+    // 0x50
+    entry("BVC", CPU::bvc, Rel, 2),
+    entry("EOR", CPU::eor, IndY, 5),
+    entry("EOR", CPU::eor, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("EOR", CPU::eor, ZpgX, 4),
+    entry("LSR", CPU::lsr, ZpgX, 6),
+    entry("RMB5", CPU::rmb::<5>, Zpg, 5),
+    entry("CLI", CPU::cli, Imp, 2),
+    entry("EOR", CPU::eor, AbsY, 4),
+    entry("PHY", CPU::phy, Imp, 3),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    unofficial("NOP", CPU::nop_wide, Abs, 8),
+    entry("EOR", CPU::eor, AbsX, 4),
+    entry("LSR", CPU::lsr, AbsX, 6),
+    entry("BBR5", CPU::bbr::<5>, ZpgRel, 5),
+    // 0x60
+    entry("RTS", CPU::rts, Imp, 6),
+    entry("ADC", CPU::adc, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("STZ", CPU::stz, Zpg, 3),
+    entry("ADC", CPU::adc, Zpg, 3),
+    entry("ROR", CPU::ror, Zpg, 5),
+    entry("RMB6", CPU::rmb::<6>, Zpg, 5),
+    entry("PLA", CPU::pla, Imp, 4),
+    entry("ADC", CPU::adc, Imm, 2),
+    entry("ROR", CPU::ror, Acc, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("JMP", CPU::jmp, Ind, 6),
+    entry("ADC", CPU::adc, Abs, 4),
+    entry("ROR", CPU::ror, Abs, 6),
+    entry("BBR6", CPU::bbr::<6>, ZpgRel, 5),
+    // 0x70
+    entry("BVS", CPU::bvs, Rel, 2),
+    entry("ADC", CPU::adc, IndY, 5),
+    entry("ADC", CPU::adc, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("STZ", CPU::stz, ZpgX, 4),
+    entry("ADC", CPU::adc, ZpgX, 4),
+    entry("ROR", CPU::ror, ZpgX, 6),
+    entry("RMB7", CPU::rmb::<7>, Zpg, 5),
+    entry("SEI", CPU::sei, Imp, 2),
+    entry("ADC", CPU::adc, AbsY, 4),
+    entry("PLY", CPU::ply, Imp, 4),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("JMP", CPU::jmp, IndAbsX, 6),
+    entry("ADC", CPU::adc, AbsX, 4),
+    entry("ROR", CPU::ror, AbsX, 6),
+    entry("BBR7", CPU::bbr::<7>, ZpgRel, 5),
+    // 0x80
+    entry("BRA", CPU::bra, Rel, 2),
+    entry("STA", CPU::sta, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("STY", CPU::sty, Zpg, 3),
+    entry("STA", CPU::sta, Zpg, 3),
+    entry("STX", CPU::stx, Zpg, 3),
+    entry("SMB0", CPU::smb::<0>, Zpg, 5),
+    entry("DEY", CPU::dey, Imp, 2),
+    entry("BIT", CPU::bit, Imm, 2),
+    entry("TXA", CPU::txa, Imp, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("STY", CPU::sty, Abs, 4),
+    entry("STA", CPU::sta, Abs, 4),
+    entry("STX", CPU::stx, Abs, 4),
+    entry("BBS0", CPU::bbs::<0>, ZpgRel, 5),
+    // 0x90
+    entry("BCC", CPU::bcc, Rel, 2),
+    entry("STA", CPU::sta, IndY, 6),
+    entry("STA", CPU::sta, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("STY", CPU::sty, ZpgX, 4),
+    entry("STA", CPU::sta, ZpgX, 4),
+    entry("STX", CPU::stx, ZpgY, 4),
+    entry("SMB1", CPU::smb::<1>, Zpg, 5),
+    entry("TYA", CPU::tya, Imp, 2),
+    entry("STA", CPU::sta, AbsY, 5),
+    entry("TXS", CPU::txs, Imp, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("STZ", CPU::stz, Abs, 4),
+    entry("STA", CPU::sta, AbsX, 5),
+    entry("STZ", CPU::stz, AbsX, 5),
+    entry("BBS1", CPU::bbs::<1>, ZpgRel, 5),
+    // 0xa0
+    entry("LDY", CPU::ldy, Imm, 2),
+    entry("LDA", CPU::lda, IndX, 6),
+    entry("LDX", CPU::ldx, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("LDY", CPU::ldy, Zpg, 3),
+    entry("LDA", CPU::lda, Zpg, 3),
+    entry("LDX", CPU::ldx, Zpg, 3),
+    entry("SMB2", CPU::smb::<2>, Zpg, 5),
+    entry("TAY", CPU::tay, Imp, 2),
+    entry("LDA", CPU::lda, Imm, 2),
+    entry("TAX", CPU::tax, Imp, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("LDY", CPU::ldy, Abs, 4),
+    entry("LDA", CPU::lda, Abs, 4),
+    entry("LDX", CPU::ldx, Abs, 4),
+    entry("BBS2", CPU::bbs::<2>, ZpgRel, 5),
+    // 0xb0
+    entry("BCS", CPU::bcs, Rel, 2),
+    entry("LDA", CPU::lda, IndY, 5),
+    entry("LDA", CPU::lda, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("LDY", CPU::ldy, ZpgX, 4),
+    entry("LDA", CPU::lda, ZpgX, 4),
+    entry("LDX", CPU::ldx, ZpgY, 4),
+    entry("SMB3", CPU::smb::<3>, Zpg, 5),
+    entry("CLV", CPU::clv, Imp, 2),
+    entry("LDA", CPU::lda, AbsY, 4),
+    entry("TSX", CPU::tsx, Imp, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("LDY", CPU::ldy, AbsX, 4),
+    entry("LDA", CPU::lda, AbsX, 4),
+    entry("LDX", CPU::ldx, AbsY, 4),
+    entry("BBS3", CPU::bbs::<3>, ZpgRel, 5),
+    // 0xc0
+    entry("CPY", CPU::cpy, Imm, 2),
+    entry("CMP", CPU::cmp, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("CPY", CPU::cpy, Zpg, 3),
+    entry("CMP", CPU::cmp, Zpg, 3),
+    entry("DEC", CPU::dec, Zpg, 5),
+    entry("SMB4", CPU::smb::<4>, Zpg, 5),
+    entry("INY", CPU::iny, Imp, 2),
+    entry("CMP", CPU::cmp, Imm, 2),
+    entry("DEX", CPU::dex, Imp, 2),
+    entry("WAI", CPU::wai, Imp, 3),
+    entry("CPY", CPU::cpy, Abs, 4),
+    entry("CMP", CPU::cmp, Abs, 4),
+    entry("DEC", CPU::dec, Abs, 6),
+    entry("BBS4", CPU::bbs::<4>, ZpgRel, 5),
+    // 0xd0
+    entry("BNE", CPU::bne, Rel, 2),
+    entry("CMP", CPU::cmp, IndY, 5),
+    entry("CMP", CPU::cmp, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("CMP", CPU::cmp, ZpgX, 4),
+    entry("DEC", CPU::dec, ZpgX, 6),
+    entry("SMB5", CPU::smb::<5>, Zpg, 5),
+    entry("CLD", CPU::cld, Imp, 2),
+    entry("CMP", CPU::cmp, AbsY, 4),
+    entry("PHX", CPU::phx, Imp, 3),
+    entry("STP", CPU::stp, Imp, 3),
+    unofficial("NOP", CPU::nop, Abs, 4),
+    entry("CMP", CPU::cmp, AbsX, 4),
+    entry("DEC", CPU::dec, AbsX, 7),
+    entry("BBS5", CPU::bbs::<5>, ZpgRel, 5),
+    // 0xe0
+    entry("CPX", CPU::cpx, Imm, 2),
+    entry("SBC", CPU::sbc, IndX, 6),
+    unofficial("NOP", CPU::nop, Imm, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("CPX", CPU::cpx, Zpg, 3),
+    entry("SBC", CPU::sbc, Zpg, 3),
+    entry("INC", CPU::inc, Zpg, 5),
+    entry("SMB6", CPU::smb::<6>, Zpg, 5),
+    entry("INX", CPU::inx, Imp, 2),
+    entry("SBC", CPU::sbc, Imm, 2),
+    entry("NOP", CPU::nop, Imp, 2),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    entry("CPX", CPU::cpx, Abs, 4),
+    entry("SBC", CPU::sbc, Abs, 4),
+    entry("INC", CPU::inc, Abs, 6),
+    entry("BBS6", CPU::bbs::<6>, ZpgRel, 5),
+    // 0xf0
+    entry("BEQ", CPU::beq, Rel, 2),
+    entry("SBC", CPU::sbc, IndY, 5),
+    entry("SBC", CPU::sbc, IndZpg, 5),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    unofficial("NOP", CPU::nop, ZpgX, 4),
+    entry("SBC", CPU::sbc, ZpgX, 4),
+    entry("INC", CPU::inc, ZpgX, 6),
+    entry("SMB7", CPU::smb::<7>, Zpg, 5),
+    entry("SED", CPU::sed, Imp, 2),
+    entry("SBC", CPU::sbc, AbsY, 4),
+    entry("PLX", CPU::plx, Imp, 4),
+    unofficial("NOP", CPU::nop, Imp, 1),
+    unofficial("NOP", CPU::nop, Abs, 4),
+    entry("SBC", CPU::sbc, AbsX, 4),
+    entry("INC", CPU::inc, AbsX, 7),
+    entry("BBS7", CPU::bbs::<7>, ZpgRel, 5),
+];
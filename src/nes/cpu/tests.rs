@@ -0,0 +1,1002 @@
+// Small programs assembled with nes::asm and run on FlatRam. Most of
+// them end by jumping to themselves so run_until_trap knows when
+// they're done.
+
+use super::*;
+use crate::nes::asm;
+use crate::nes::harness::{ FlatRam, Stop, run_until_trap };
+use crate::nes::bus::BusAccess::{ self, Read, Write };
+
+// assembles source into 64K of ram, pc starts at start: if there is
+// one and at $0200 if not. no reset, the stack starts empty at $01FF
+// and every flag is clear
+fn load(variant: Variant, source: &str) -> (CPU, FlatRam)
+{
+    let asm = asm::assemble(source).unwrap();
+    let mut ram = FlatRam::new();
+    asm.load(&mut ram);
+    let mut cpu = CPU::with_variant(variant);
+    cpu.pc = asm.label("start").unwrap_or(0x0200);
+    cpu.s = 0xff;
+    cpu.p = 0;
+    (cpu, ram)
+}
+
+// runs source on a 2A03 until it traps
+fn run(source: &str) -> (CPU, FlatRam)
+{
+    run_on(Variant::Ricoh2A03, source)
+}
+
+fn run_on(variant: Variant, source: &str) -> (CPU, FlatRam)
+{
+    let (mut cpu, mut ram) = load(variant, source);
+    match run_until_trap(&mut cpu, &mut ram, 100_000)
+    {
+        Stop::Trap(_) => (cpu, ram),
+        stop => panic!("program didn't finish: {:?}", stop),
+    }
+}
+
+// how many cycles the first instruction of source takes
+fn cycles(source: &str) -> u64
+{
+    let (mut cpu, mut ram) = load(Variant::Ricoh2A03, source);
+    cpu.step(&mut ram);
+    cpu.cycles
+}
+
+fn flags(cpu: &CPU) -> String
+{
+    "NV-BDIZC".chars().enumerate()
+        .map(|(i, c)| if cpu.p & 0x80 >> i != 0 { c } else { '.' })
+        .collect()
+}
+
+#[test]
+fn sums_a_table()
+{
+    let (cpu, ram) = run("
+            .org $0200
+            ldx #0
+            lda #0
+            clc
+    loop:   adc table,x
+            inx
+            cpx #5
+            bne loop
+            sta $10
+    done:   jmp done
+    table:  .byte 1, 2, 3, 4, 5
+    ");
+    assert_eq!(ram.mem[0x10], 15);
+    assert_eq!((cpu.a, cpu.x), (15, 5));
+    assert_eq!(flags(&cpu), "......ZC");
+}
+
+#[test]
+fn subroutines_and_the_stack()
+{
+    let (cpu, ram) = run("
+            .org $0200
+    start:  ldx #$ff
+            txs
+            lda #$12
+            pha
+            jsr double
+            pla
+            sta $00
+    done:   jmp done
+    double: asl
+            rts
+    ");
+    assert_eq!(cpu.a, 0x12);
+    assert_eq!(cpu.s, 0xff);
+    assert_eq!(ram.mem[0x00], 0x12);
+    assert_eq!(ram.mem[0x01fe], 0x02, "jsr pushes the address of its last byte");
+}
+
+#[test]
+fn loop_cycle_count()
+{
+    // ldx 2, then 10 times dex 2 + bne 3, except the last bne is 2,
+    // then the jmp that traps 3
+    let (cpu, _) = run("
+            .org $0200
+            ldx #10
+    loop:   dex
+            bne loop
+    done:   jmp done
+    ");
+    assert_eq!(cpu.x, 0);
+    assert_eq!(cpu.cycles, 2 + 10 * 5 - 1 + 3);
+}
+
+#[test]
+fn instruction_cycles()
+{
+    assert_eq!(cycles(".org $0200\nnop"), 2);
+    assert_eq!(cycles(".org $0200\nlda #1"), 2);
+    assert_eq!(cycles(".org $0200\nlda $10"), 3);
+    assert_eq!(cycles(".org $0200\nlda $10,x"), 4);
+    assert_eq!(cycles(".org $0200\nlda $1234"), 4);
+    assert_eq!(cycles(".org $0200\nsta $1234,x"), 5);
+    assert_eq!(cycles(".org $0200\nlda ($10,x)"), 6);
+    assert_eq!(cycles(".org $0200\nlda ($10),y"), 5);
+    assert_eq!(cycles(".org $0200\nsta ($10),y"), 6);
+    assert_eq!(cycles(".org $0200\ninc $10"), 5);
+    assert_eq!(cycles(".org $0200\ninc $1234,x"), 7);
+    assert_eq!(cycles(".org $0200\njmp $1234"), 3);
+    assert_eq!(cycles(".org $0200\njmp ($1234)"), 5);
+    assert_eq!(cycles(".org $0200\njsr $1234"), 6);
+    assert_eq!(cycles(".org $0200\npha"), 3);
+    assert_eq!(cycles(".org $0200\npla"), 4);
+    assert_eq!(cycles(".org $0200\nbrk"), 7);
+}
+
+// a with carry in, then op #operand. what's in a and the flags after
+fn arith(variant: Variant, decimal: bool, a: u8, carry: bool, op: &str, operand: u8)
+    -> (u8, String)
+{
+    let (cpu, _) = run_on(variant, &format!("
+            .org $0200
+            {}
+            {}
+            lda #{}
+            {} #{}
+    done:   jmp done
+    ", if decimal { "sed" } else { "cld" }, if carry { "sec" } else { "clc" }, a, op, operand));
+    (cpu.a, flags(&cpu))
+}
+
+#[test]
+fn adc_carry_and_overflow()
+{
+    let adc = |a, carry, operand| arith(Variant::Ricoh2A03, false, a, carry, "adc", operand);
+    assert_eq!(adc(0x01, false, 0x01), (0x02, "........".into()));
+    assert_eq!(adc(0x01, true, 0x01), (0x03, "........".into()));
+    assert_eq!(adc(0x7f, false, 0x01), (0x80, "NV......".into()));
+    assert_eq!(adc(0xff, false, 0x01), (0x00, "......ZC".into()));
+    assert_eq!(adc(0x80, false, 0x80), (0x00, ".V....ZC".into()));
+    assert_eq!(adc(0x80, true, 0xff), (0x80, "N......C".into()));
+    assert_eq!(adc(0x3f, true, 0x40), (0x80, "NV......".into()));
+}
+
+#[test]
+fn sbc_borrow_and_overflow()
+{
+    // carry in is the inverse of a borrow
+    let sbc = |a, carry, operand| arith(Variant::Ricoh2A03, false, a, carry, "sbc", operand);
+    assert_eq!(sbc(0x05, true, 0x03), (0x02, ".......C".into()));
+    assert_eq!(sbc(0x05, false, 0x03), (0x01, ".......C".into()));
+    assert_eq!(sbc(0x03, true, 0x05), (0xfe, "N.......".into()));
+    assert_eq!(sbc(0x03, true, 0x03), (0x00, "......ZC".into()));
+    assert_eq!(sbc(0x80, true, 0x01), (0x7f, ".V.....C".into()));
+    assert_eq!(sbc(0x7f, true, 0xff), (0x80, "NV......".into()));
+}
+
+#[test]
+fn decimal_mode()
+{
+    let nmos = |a, carry, op, operand| arith(Variant::Nmos6502, true, a, carry, op, operand);
+    assert_eq!(nmos(0x09, false, "adc", 0x01).0, 0x10);
+    assert_eq!(nmos(0x58, true, "adc", 0x46).0, 0x05);
+    assert!(nmos(0x58, true, "adc", 0x46).1.ends_with('C'));
+    assert_eq!(nmos(0x99, false, "adc", 0x01), (0x00, "N...D..C".into()));
+    assert_eq!(nmos(0x10, true, "sbc", 0x01).0, 0x09);
+    assert_eq!(nmos(0x00, true, "sbc", 0x01), (0x99, "N...D...".into()));
+
+    // the 65C02 gets N and Z right for the decimal result
+    let cmos = |a, carry, op, operand| arith(Variant::Cmos65C02, true, a, carry, op, operand);
+    assert_eq!(cmos(0x99, false, "adc", 0x01), (0x00, "....D.ZC".into()));
+
+    // and the 2A03 doesn't have it at all
+    let ricoh = |a, carry, op, operand| arith(Variant::Ricoh2A03, true, a, carry, op, operand);
+    assert_eq!(ricoh(0x09, false, "adc", 0x01).0, 0x0a);
+    assert_eq!(ricoh(0x10, true, "sbc", 0x01).0, 0x0f);
+}
+
+#[test]
+fn bit()
+{
+    let bit = |a: u8, data: u8|
+    {
+        let (cpu, _) = run(&format!("
+                .org $0200
+                lda #{}
+                bit $10
+        done:   jmp done
+                .org $10
+                .byte {}
+        ", a, data));
+        assert_eq!(cpu.a, a, "bit leaves a alone");
+        flags(&cpu)
+    };
+    assert_eq!(bit(0xff, 0xc0), "NV......");
+    assert_eq!(bit(0x01, 0x40), ".V....Z.");
+    assert_eq!(bit(0x01, 0x81), "N.......");
+    assert_eq!(bit(0x00, 0x00), "......Z.");
+}
+
+#[test]
+fn compares()
+{
+    let compare = |reg: &str, value: u8, operand: u8|
+    {
+        let (cpu, _) = run(&format!("
+                .org $0200
+                ld{} #{}
+                {} #{}
+        done:   jmp done
+        ", reg, value, if reg == "a" { "cmp".into() } else { format!("cp{}", reg) }, operand));
+        flags(&cpu)
+    };
+    for reg in ["a", "x", "y"]
+    {
+        assert_eq!(compare(reg, 5, 3), ".......C");
+        assert_eq!(compare(reg, 5, 5), "......ZC");
+        assert_eq!(compare(reg, 3, 5), "N.......");
+        assert_eq!(compare(reg, 0x80, 0x00), "N......C");
+        assert_eq!(compare(reg, 0x00, 0x80), "N.......");
+    }
+}
+
+#[test]
+fn shifts_on_a()
+{
+    let shift = |op: &str, a: u8, carry: bool|
+    {
+        let (cpu, _) = run(&format!("
+                .org $0200
+                lda #{}
+                {}
+                {} a
+        done:   jmp done
+        ", a, if carry { "sec" } else { "clc" }, op));
+        (cpu.a, flags(&cpu))
+    };
+    assert_eq!(shift("asl", 0x81, false), (0x02, ".......C".into()));
+    assert_eq!(shift("asl", 0x40, true), (0x80, "N.......".into()));
+    assert_eq!(shift("lsr", 0x01, true), (0x00, "......ZC".into()));
+    assert_eq!(shift("lsr", 0x82, false), (0x41, "........".into()));
+    assert_eq!(shift("rol", 0x80, true), (0x01, ".......C".into()));
+    assert_eq!(shift("rol", 0x40, false), (0x80, "N.......".into()));
+    assert_eq!(shift("ror", 0x01, true), (0x80, "N......C".into()));
+    assert_eq!(shift("ror", 0x01, false), (0x00, "......ZC".into()));
+}
+
+#[test]
+fn shifts_on_memory()
+{
+    let (cpu, ram) = run("
+            .org $0200
+            ldx #1
+            sec
+            asl $10
+            lsr $11,x
+            rol $0300
+            ror $0300,x
+    done:   jmp done
+            .org $10
+            .byte $c0, $00, $03
+            .org $0300
+            .byte $80, $02
+    ");
+    assert_eq!(ram.mem[0x10], 0x80);
+    assert_eq!(ram.mem[0x12], 0x01);
+    assert_eq!(ram.mem[0x0300], 0x01);
+    assert_eq!(ram.mem[0x0301], 0x81);
+    assert_eq!(flags(&cpu), "N.......", "last one was ror of $02 with carry in");
+}
+
+#[test]
+fn arr()
+{
+    let arr = |a, carry, operand| arith(Variant::Ricoh2A03, false, a, carry, "arr", operand);
+    // c is bit 6 of the result, v is bit 6 xor bit 5
+    assert_eq!(arr(0xff, true, 0xff), (0xff, "N......C".into()));
+    assert_eq!(arr(0x40, false, 0xff), (0x20, ".V......".into()));
+    assert_eq!(arr(0x80, false, 0xff), (0x40, ".V.....C".into()));
+    assert_eq!(arr(0xc0, false, 0xff), (0x60, ".......C".into()));
+    assert_eq!(arr(0x01, false, 0xff), (0x00, "......Z.".into()));
+
+    // in decimal each digit gets fixed up after the shift, n and z
+    // are from before
+    let nmos = |a, carry, operand| arith(Variant::Nmos6502, true, a, carry, "arr", operand);
+    assert_eq!(nmos(0xff, false, 0xff), (0xd5, "....D..C".into()));
+    assert_eq!(nmos(0x11, true, 0xff), (0x88, "N...D...".into()));
+    assert_eq!(nmos(0x40, false, 0xff), (0x20, ".V..D...".into()));
+    assert_eq!(nmos(0x0c, false, 0xff), (0x0c, "....D...".into()));
+}
+
+#[test]
+fn sbx()
+{
+    // x = (a & x) - operand without borrow in, flags like cmp
+    let sbx = |a: u8, x: u8, operand: u8|
+    {
+        let (cpu, _) = run(&format!("
+                .org $0200
+                lda #{}
+                ldx #{}
+                clc
+                sbx #{}
+        done:   jmp done
+        ", a, x, operand));
+        assert_eq!(cpu.a, a);
+        (cpu.x, flags(&cpu))
+    };
+    assert_eq!(sbx(0xff, 0x0f, 0x05), (0x0a, ".......C".into()));
+    assert_eq!(sbx(0xf0, 0x3f, 0x30), (0x00, "......ZC".into()));
+    assert_eq!(sbx(0xff, 0x0f, 0x10), (0xff, "N.......".into()));
+}
+
+#[test]
+fn loads_and_stores()
+{
+    let (cpu, ram) = run("
+            .org $0200
+            ldx #$f0
+            txs
+            ldy #1
+            las $0300,y
+            sta $20
+            lax $10
+            stx $21
+            ldx #$3c
+            lda #$f0
+            sax $22
+    done:   jmp done
+            .org $10
+            .byte $80
+            .org $0300
+            .byte $00, $3c
+    ");
+    assert_eq!(ram.mem[0x20], 0x30, "las is memory and s");
+    assert_eq!(cpu.s, 0x30);
+    assert_eq!(ram.mem[0x21], 0x80, "lax loads both");
+    assert_eq!(ram.mem[0x22], 0x30, "sax stores a and x");
+    assert_eq!(flags(&cpu), "N.......", "sax leaves the flags from the lda");
+}
+
+#[test]
+fn read_modify_writes()
+{
+    // each is the memory op and then the a op with what it wrote
+    let rmw = |op: &str, a: u8, carry: bool, data: u8|
+    {
+        let (cpu, ram) = run(&format!("
+                .org $0200
+                lda #{}
+                {}
+                {} $10
+        done:   jmp done
+                .org $10
+                .byte {}
+        ", a, if carry { "sec" } else { "clc" }, op, data));
+        (ram.mem[0x10], cpu.a, flags(&cpu))
+    };
+    assert_eq!(rmw("slo", 0x01, false, 0x81), (0x02, 0x03, ".......C".into()));
+    assert_eq!(rmw("rla", 0xff, true, 0x80), (0x01, 0x01, ".......C".into()));
+    assert_eq!(rmw("sre", 0xff, false, 0x03), (0x01, 0xfe, "N......C".into()));
+    // the bit ror shifts out is the carry into the add
+    assert_eq!(rmw("rra", 0x10, false, 0x03), (0x01, 0x12, "........".into()));
+    assert_eq!(rmw("rra", 0x10, true, 0x02), (0x81, 0x91, "N.......".into()));
+    assert_eq!(rmw("dcp", 0x05, false, 0x06), (0x05, 0x05, "......ZC".into()));
+    assert_eq!(rmw("dcp", 0x05, false, 0x00), (0xff, 0x05, "........".into()));
+    assert_eq!(rmw("isb", 0x10, true, 0x04), (0x05, 0x0b, ".......C".into()));
+    assert_eq!(rmw("isb", 0x00, true, 0xff), (0x00, 0x00, "......ZC".into()));
+}
+
+#[test]
+fn stores_and_high_byte()
+{
+    // what's stored is anded with the base's high byte plus one, and
+    // on a page cross that's the high byte of the address too
+    let (cpu, ram) = run("
+            .org $0200
+            ldx #$ff
+            ldy #$10
+            shx $1200,y
+            ldx #$05
+            ldy #$20
+            shx $12f0,y
+            ldy #$07
+            ldx #$20
+            shy $12f0,x
+            lda #$ff
+            ldx #$0f
+            ldy #$20
+            sha $16f0,y
+            sha ($10),y
+            tas $18f0,y
+    done:   jmp done
+            .org $10
+            .word $17f0
+    ");
+    assert_eq!(ram.mem[0x1210], 0x13, "no page cross");
+    assert_eq!(ram.mem[0x0110], 0x01, "shx: $05 & $13");
+    assert_eq!(ram.mem[0x0310], 0x03, "shy: $07 & $13");
+    assert_eq!(ram.mem[0x0710], 0x07, "sha abs,y: $0f & $17");
+    assert_eq!(ram.mem[0x0810], 0x08, "sha (zp),y: $0f & $18");
+    assert_eq!(ram.mem[0x0910], 0x09, "tas: $0f & $19");
+    assert_eq!(ram.mem[0x1310], 0x00, "not where the index took it");
+    assert_eq!(cpu.s, 0x0f, "tas puts a and x in s");
+}
+
+#[test]
+fn jmp_indirect_wraps_in_its_page()
+{
+    // the high byte comes from $0200, not $0300
+    let source = "
+            .org $0400
+    start:  jmp ($02ff)
+            .org $02ff
+            .byte $00
+            .org $0300
+            .byte $06
+            .org $0200
+            .byte $05
+    ";
+    let (mut cpu, mut ram) = load(Variant::Nmos6502, source);
+    cpu.step(&mut ram);
+    assert_eq!(cpu.pc, 0x0500);
+
+    // fixed on the 65C02, which takes a cycle more for it
+    let (mut cpu, mut ram) = load(Variant::Cmos65C02, source);
+    cpu.step(&mut ram);
+    assert_eq!(cpu.pc, 0x0600);
+    assert_eq!(cpu.cycles, 6);
+}
+
+// the assembler only knows the nmos opcodes, so the 65C02 ones are
+// spelled out as bytes
+
+#[test]
+fn cmos_stack_and_stz()
+{
+    let (cpu, ram) = run_on(Variant::Cmos65C02, "
+            .org $0200
+            ldx #$ff
+            txs
+            ldx #$12
+            ldy #$34
+            .byte $da           ; phx
+            .byte $5a           ; phy
+            ldx #0
+            ldy #0
+            .byte $fa           ; plx
+            .byte $7a           ; ply
+            lda #$ff
+            .byte $64, $10      ; stz $10
+            .byte $74, $10      ; stz $10,x, $44
+            .byte $9c, $00, $03 ; stz $0300
+            .byte $9e, $00, $03 ; stz $0300,x, $0334
+    done:   jmp done
+            .org $10
+            .byte $ff
+            .org $44
+            .byte $ff
+            .org $0300
+            .byte $ff
+            .org $0334
+            .byte $ff
+    ");
+
+    // pulled back the other way round, with the flags of what ply got
+    assert_eq!((cpu.x, cpu.y, cpu.s), (0x34, 0x12, 0xff));
+    assert_eq!(cpu.a, 0xff, "stz leaves a alone");
+    assert_eq!((ram.mem[0x01ff], ram.mem[0x01fe]), (0x12, 0x34));
+    assert_eq!([ram.mem[0x10], ram.mem[0x44], ram.mem[0x0300], ram.mem[0x0334]], [0; 4]);
+}
+
+#[test]
+fn tsb_and_trb()
+{
+    // what's in memory after and the flags, Z from a & the old value
+    // and N as lda left it
+    let modify = |op: u8, a: u8, data: u8|
+    {
+        let (cpu, ram) = run_on(Variant::Cmos65C02, &format!("
+                .org $0200
+                lda #{}
+                .byte {}, $10
+        done:   jmp done
+                .org $10
+                .byte {}
+        ", a, op, data));
+        assert_eq!(cpu.a, a);
+        (ram.mem[0x10], flags(&cpu))
+    };
+    let (tsb, trb) = (0x04, 0x14);
+    assert_eq!(modify(tsb, 0x0f, 0x3c), (0x3f, "........".into()));
+    assert_eq!(modify(tsb, 0x0f, 0xf0), (0xff, "......Z.".into()));
+    assert_eq!(modify(trb, 0x30, 0x3f), (0x0f, "........".into()));
+    assert_eq!(modify(trb, 0xc0, 0x0f), (0x0f, "N.....Z.".into()));
+}
+
+#[test]
+fn zero_page_indirect()
+{
+    let (cpu, ram) = run_on(Variant::Cmos65C02, "
+            .org $0200
+            ldy #$05
+            .byte $b2, $20      ; lda ($20)
+            tax
+            .byte $92, $22      ; sta ($22)
+            .byte $b2, $ff      ; lda ($ff)
+    done:   jmp done
+            .org $00
+            .byte $03
+            .org $20
+            .word $0300, $0310
+            .org $ff
+            .byte $04
+            .org $0300
+            .byte $5a
+            .org $0304
+            .byte $a5
+    ");
+
+    // no y added, and the pointer at $ff wraps round to $00
+    assert_eq!(cpu.x, 0x5a);
+    assert_eq!(ram.mem[0x0310], 0x5a);
+    assert_eq!(cpu.a, 0xa5);
+}
+
+#[test]
+fn bit_immediate()
+{
+    // only Z changes, N and V stay as bit $10 left them
+    let (cpu, _) = run_on(Variant::Cmos65C02, "
+            .org $0200
+            lda #$0f
+            bit $10
+            .byte $89, $30      ; bit #$30
+    done:   jmp done
+            .org $10
+            .byte $c0
+    ");
+    assert_eq!(flags(&cpu), "NV....Z.");
+
+    let (cpu, _) = run_on(Variant::Cmos65C02, "
+            .org $0200
+            lda #$01
+            bit $10
+            .byte $89, $c1      ; bit #$c1
+    done:   jmp done
+            .org $10
+            .byte $00
+    ");
+    assert_eq!(flags(&cpu), "........");
+}
+
+#[test]
+fn bra()
+{
+    // always taken, whatever the flags
+    let (cpu, _) = run_on(Variant::Cmos65C02, "
+            .org $0200
+            lda #0
+            .byte $80, $02      ; bra over the ldx
+            ldx #$11
+            ldy #$22
+            .byte $80, $05      ; bra fwd
+    back:   lda #$33
+    done:   jmp done
+    fwd:    .byte $80, $f9      ; bra back
+    ");
+    assert_eq!((cpu.a, cpu.x, cpu.y), (0x33, 0x00, 0x22));
+
+    // timed like any other taken branch
+    let bra = |source: &str|
+    {
+        let (mut cpu, mut ram) = load(Variant::Cmos65C02, source);
+        cpu.step(&mut ram);
+        (cpu.pc, cpu.cycles)
+    };
+    assert_eq!(bra(".org $0200\n.byte $80, $10"), (0x0212, 3));
+    assert_eq!(bra(".org $02f0\nstart: .byte $80, $10"), (0x0302, 4));
+}
+
+// every bus access the first instruction of source does, with x set
+fn accesses(source: &str, x: u8) -> Vec<BusAccess>
+{
+    let (mut cpu, mut ram) = load(Variant::Ricoh2A03, source);
+    cpu.x = x;
+    ram.log = Some(Vec::new());
+    cpu.step(&mut ram);
+    ram.log.unwrap()
+}
+
+#[test]
+fn read_modify_write_abs_x()
+{
+    // a dummy read from the address before the carry is fixed, even
+    // when there isn't one, then the old value is written back before
+    // the new one
+    let source = "
+            .org $0200
+            inc $1234,x
+            .org $1235
+            .byte $41
+    ";
+    assert_eq!(accesses(source, 1), [
+        Read(0x0200, 0xfe), Read(0x0201, 0x34), Read(0x0202, 0x12),
+        Read(0x1235, 0x41),
+        Read(0x1235, 0x41),
+        Write(0x1235, 0x41),
+        Write(0x1235, 0x42),
+    ]);
+
+    let source = "
+            .org $0200
+            inc $12f8,x
+            .org $1208
+            .byte $aa
+            .org $1308
+            .byte $41
+    ";
+    assert_eq!(accesses(source, 0x10), [
+        Read(0x0200, 0xfe), Read(0x0201, 0xf8), Read(0x0202, 0x12),
+        Read(0x1208, 0xaa),
+        Read(0x1308, 0x41),
+        Write(0x1308, 0x41),
+        Write(0x1308, 0x42),
+    ]);
+}
+
+#[test]
+fn indexed_read_page_cross()
+{
+    // no penalty if the index doesn't carry into the high byte
+    let source = "
+            .org $0200
+            lda $1234,x
+            .org $1235
+            .byte $41
+    ";
+    assert_eq!(accesses(source, 1), [
+        Read(0x0200, 0xbd), Read(0x0201, 0x34), Read(0x0202, 0x12),
+        Read(0x1235, 0x41),
+    ]);
+
+    // a cycle more if it does, reading from the wrong page first
+    let source = "
+            .org $0200
+            lda $12f0,x
+            .org $1210
+            .byte $aa
+            .org $1310
+            .byte $41
+    ";
+    assert_eq!(accesses(source, 0x20), [
+        Read(0x0200, 0xbd), Read(0x0201, 0xf0), Read(0x0202, 0x12),
+        Read(0x1210, 0xaa),
+        Read(0x1310, 0x41),
+    ]);
+}
+
+#[test]
+fn branch_timing()
+{
+    // Z starts clear. not taken, just the opcode and the offset
+    let source = "
+            .org $0200
+            beq target
+            .byte $ea, $ea
+    target: nop
+    ";
+    assert_eq!(accesses(source, 0), [Read(0x0200, 0xf0), Read(0x0201, 0x02)]);
+
+    // taken in the same page, the next opcode is read for nothing
+    let source = source.replace("beq", "bne");
+    assert_eq!(accesses(&source, 0), [
+        Read(0x0200, 0xd0), Read(0x0201, 0x02),
+        Read(0x0202, 0xea),
+    ]);
+
+    // taken into the next page, and then from the wrong page
+    let source = "
+            .org $02f0
+    start:  bne target
+            .org $0312
+    target: nop
+    ";
+    assert_eq!(accesses(source, 0), [
+        Read(0x02f0, 0xd0), Read(0x02f1, 0x20),
+        Read(0x02f2, 0x00),
+        Read(0x0212, 0x00),
+    ]);
+}
+
+// FlatRam with the NMI line going up at the end of one cycle of the
+// step, counting from 1, and staying up
+struct NmiAt
+{
+    ram: FlatRam,
+    cycle: u64,
+    at: u64,
+}
+
+impl Bus for NmiAt
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        self.ram.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        self.ram.write(addr, data);
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        self.ram.peek(addr)
+    }
+
+    fn cycle(&mut self)
+    {
+        self.cycle += 1;
+    }
+
+    fn nmi(&self) -> Option<bool>
+    {
+        Some(self.cycle >= self.at)
+    }
+}
+
+const VECTORS: &str = "
+            .org $0300
+    nmi:    jmp nmi
+            .org $0400
+    irq:    jmp irq
+            .org $fffa
+            .word nmi, start, irq
+";
+
+// where the interrupt handler that source ends up in would return to,
+// and the flags it pushed
+fn interrupted(mut cpu: CPU, bus: &mut dyn Bus) -> (u16, u16, u8)
+{
+    let stop = run_until_trap(&mut cpu, bus, 1000);
+    let Stop::Trap(handler) = stop
+    else { panic!("no interrupt: {:?}", stop) };
+    let ret = bus.peek(0x01fe) as u16 | (bus.peek(0x01ff) as u16) << 8;
+    (handler, ret, bus.peek(0x01fd))
+}
+
+// runs source with the NMI line going up at the end of cycle at
+fn nmi_at(source: &str, at: u64) -> (u16, u16, u8)
+{
+    let (cpu, ram) = load(Variant::Ricoh2A03, &format!("{}{}", source, VECTORS));
+    let mut bus = NmiAt { ram, cycle: 0, at };
+    interrupted(cpu, &mut bus)
+}
+
+// runs source with IRQ held from the start and I as given
+fn irq_held(source: &str, p: u8) -> (u16, u16, u8)
+{
+    let (mut cpu, mut ram) = load(Variant::Ricoh2A03, &format!("{}{}", source, VECTORS));
+    cpu.p = p;
+    cpu.set_irq(true);
+    interrupted(cpu, &mut ram)
+}
+
+#[test]
+fn nmi_after_the_instruction_it_came_in()
+{
+    // seen by the second to last cycle, taken right after
+    let source = "
+            .org $0200
+    start:  lda $1234
+            nop
+    ";
+    assert_eq!(nmi_at(source, 3).1, 0x0203);
+    // on the last cycle is too late, it waits an instruction
+    assert_eq!(nmi_at(source, 4).1, 0x0204);
+}
+
+#[test]
+fn taken_branch_delays_nmi()
+{
+    let source = "
+            .org $0200
+    start:  bne target
+    target: nop
+            nop
+    ";
+    assert_eq!(nmi_at(source, 1).1, 0x0202, "in before the operand");
+    assert_eq!(nmi_at(source, 2).1, 0x0203, "in on the operand, the nop runs first");
+
+    // not when it crosses a page, that cycle polls as usual
+    let source = "
+            .org $02f0
+    start:  bne target
+            .org $0312
+    target: nop
+            nop
+    ";
+    assert_eq!(nmi_at(source, 2).1, 0x0312);
+    assert_eq!(nmi_at(source, 3).1, 0x0312);
+}
+
+#[test]
+fn cli_sei_and_plp_poll_before_changing_i()
+{
+    let source = "
+            .org $0200
+    start:  cli
+            nop
+            nop
+    ";
+    let (handler, ret, _) = irq_held(source, FLAG_I);
+    assert_eq!((handler, ret), (0x0400, 0x0202), "one instruction after cli");
+
+    // an IRQ that's there when sei starts still gets in, with I set
+    // in what it pushes
+    let source = "
+            .org $0200
+    start:  sei
+            nop
+    ";
+    let (handler, ret, p) = irq_held(source, 0);
+    assert_eq!((handler, ret), (0x0400, 0x0201));
+    assert_ne!(p & FLAG_I, 0);
+
+    // plp is the same both ways
+    let source = "
+            .org $0200
+    start:  lda #0
+            pha
+            plp
+            nop
+            nop
+    ";
+    assert_eq!(irq_held(source, FLAG_I).1, 0x0205);
+}
+
+#[test]
+fn nmi_hijacks_brk()
+{
+    // in before the vector is picked, brk goes to the NMI handler
+    // with B set in what it pushed
+    let source = "
+            .org $0200
+    start:  brk
+            .byte 0
+    ";
+    let (handler, ret, p) = nmi_at(source, 3);
+    assert_eq!((handler, ret), (0x0300, 0x0202));
+    assert_ne!(p & FLAG_B, 0);
+
+    // too late for that and it's a plain brk
+    let (cpu, ram) = load(Variant::Ricoh2A03, &format!("{}{}", source, VECTORS));
+    let mut bus = NmiAt { ram, cycle: 0, at: 6 };
+    let mut cpu = cpu;
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0400);
+}
+
+// flat memory where everything from $8000 up counts as rom, so the
+// decode cache takes it, with what was on the bus every cycle
+struct Cycles
+{
+    ram: FlatRam,
+    access: Option<BusAccess>,
+    log: Vec<Option<BusAccess>>,
+}
+
+impl Bus for Cycles
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        let data = self.ram.read(addr);
+        self.access = Some(Read(addr, data));
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        self.ram.write(addr, data);
+        self.access = Some(Write(addr, data));
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        self.ram.peek(addr)
+    }
+
+    fn rom_offset(&self, addr: u16) -> Option<usize>
+    {
+        (addr >= 0x8000).then_some(addr as usize)
+    }
+
+    fn cycle(&mut self)
+    {
+        self.log.push(self.access.take());
+    }
+}
+
+fn registers(cpu: &CPU) -> (u8, u8, u8, u8, u8, u16, u64)
+{
+    (cpu.a, cpu.x, cpu.y, cpu.s, cpu.p, cpu.pc, cpu.cycles)
+}
+
+#[test]
+fn step_cached_matches_step()
+{
+    let source = "
+            .org $8000
+    start:  ldx #0
+    copy:   lda code,x
+            sta $0300,x
+            inx
+            cpx #code_end - code
+            bne copy
+            jsr $0300
+            lda #2          ; the code in ram changes
+            sta $0301
+            jsr $0300
+            ldy #$ff
+            jmp edge
+    code:   lda #1
+            sta $10
+            rts
+    code_end:
+
+            .org $80fe
+    edge:   lda $10f1,y     ; the operand is on the next page
+            sta $11
+            jmp window
+
+            .org $9ffe
+    window: ldx $0112,y     ; and in the next 8K
+    loop:   dey
+            bne loop
+    done:   jmp done
+
+            .org $11f0
+            .byte $5a
+    ";
+    let (mut plain, ram) = load(Variant::Ricoh2A03, source);
+    let mut plain_bus = Cycles { ram, access: None, log: Vec::new() };
+    let (mut cached, ram) = load(Variant::Ricoh2A03, source);
+    let mut cached_bus = Cycles { ram, access: None, log: Vec::new() };
+    let mut cache = DecodeCache::new(Variant::Ricoh2A03);
+
+    loop
+    {
+        let pc = plain.pc;
+        plain.step(&mut plain_bus);
+        cached.step_cached(&mut cached_bus, &mut cache);
+        assert_eq!(registers(&plain), registers(&cached), "after ${:04X}", pc);
+
+        // cycle for cycle the same, except that an instruction out of
+        // the cache isn't fetched again
+        let (want, got) = (&plain_bus.log, &cached_bus.log);
+        assert_eq!(want.len(), got.len());
+        for (&want, &got) in want.iter().zip(got)
+        {
+            match (want, got)
+            {
+                (Some(Read(addr, _)), None) =>
+                    assert!(addr >= 0x8000 && addr.wrapping_sub(pc) < 3,
+                            "${:04X} skipped a read of ${:04X}", pc, addr),
+                _ => assert_eq!(want, got, "at ${:04X}", pc),
+            }
+        }
+        plain_bus.log.clear();
+        cached_bus.log.clear();
+
+        if plain.pc == pc
+        {
+            break;
+        }
+    }
+    assert_eq!(plain_bus.ram.mem, cached_bus.ram.mem);
+    assert_eq!((plain_bus.ram.mem[0x10], plain_bus.ram.mem[0x11]), (2, 0x5a));
+    assert!(cache.hits > 500, "{:?}", cache);
+    assert!(cache.misses > 10, "{:?}", cache);
+}
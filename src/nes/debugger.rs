@@ -0,0 +1,514 @@
+// Breakpoints, watchpoints and stepping on top of NES. Watchpoints work
+// by wrapping the bus the cpu is lent for each step, so every access
+// is seen, dummy reads included. The REPL in repl.rs drives all this.
+
+pub mod repl;
+
+use crate::nes::NES;
+use crate::nes::cpu::{ CPU, Diagnostic };
+use crate::nes::bus::{ Bus, BusAccess, Fault, FaultPolicy };
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg
+{
+    A,
+    X,
+    Y,
+    S,
+    P,
+    PC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp
+{
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// reg <cmp> value, checked when a breakpoint is reached
+#[derive(Debug, Clone, Copy)]
+pub struct Condition
+{
+    pub reg: Reg,
+    pub cmp: Cmp,
+    pub value: u16,
+}
+
+impl Condition
+{
+    pub fn holds(&self, cpu: &CPU) -> bool
+    {
+        let reg = match self.reg
+        {
+            Reg::A => cpu.a as u16,
+            Reg::X => cpu.x as u16,
+            Reg::Y => cpu.y as u16,
+            Reg::S => cpu.s as u16,
+            Reg::P => cpu.p as u16,
+            Reg::PC => cpu.pc,
+        };
+
+        match self.cmp
+        {
+            Cmp::Eq => reg == self.value,
+            Cmp::Ne => reg != self.value,
+            Cmp::Lt => reg < self.value,
+            Cmp::Le => reg <= self.value,
+            Cmp::Gt => reg > self.value,
+            Cmp::Ge => reg >= self.value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Breakpoint
+{
+    pub addr: u16,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind
+{
+    Read,
+    Write,
+    Access, // either one
+}
+
+// watches every address in start..=end
+#[derive(Debug, Clone, Copy)]
+pub struct Watchpoint
+{
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint
+{
+    pub fn matches(&self, access: BusAccess) -> bool
+    {
+        let (addr, kind) = match access
+        {
+            BusAccess::Read(addr, _) => (addr, WatchKind::Read),
+            BusAccess::Write(addr, _) => (addr, WatchKind::Write),
+        };
+        (self.start..=self.end).contains(&addr)
+            && (self.kind == kind || self.kind == WatchKind::Access)
+    }
+}
+
+// why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Break
+{
+    Stepped,
+    Breakpoint(u16),
+    // the instruction that did the access has finished, pc is past it
+    Watchpoint(BusAccess),
+    // something off with the call stack, see cpu/calls.rs
+    Stack(Diagnostic),
+    // an access with nothing on the other end, with the Trap or
+    // Error policy. the instruction has finished
+    Fault(Fault),
+    Frame(u64),
+    Halted,
+}
+
+// the bus the cpu gets while watchpoints are set. the first hit of
+// an instruction is kept, the instruction itself still completes
+struct Watched<'a>
+{
+    bus: &'a mut dyn Bus,
+    watchpoints: &'a [Watchpoint],
+    hit: Option<BusAccess>,
+}
+
+impl<'a> Watched<'a>
+{
+    fn check(&mut self, access: BusAccess)
+    {
+        if self.hit.is_none() && self.watchpoints.iter().any(|w| w.matches(access))
+        {
+            self.hit = Some(access);
+        }
+    }
+}
+
+impl<'a> Bus for Watched<'a>
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        let data = self.bus.read(addr);
+        self.check(BusAccess::Read(addr, data));
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        self.bus.write(addr, data);
+        self.check(BusAccess::Write(addr, data));
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        self.bus.peek(addr)
+    }
+
+    fn rom_offset(&self, addr: u16) -> Option<usize>
+    {
+        self.bus.rom_offset(addr)
+    }
+
+    fn bank_switches(&self) -> u64
+    {
+        self.bus.bank_switches()
+    }
+
+    fn take_fault(&mut self) -> Option<Fault>
+    {
+        self.bus.take_fault()
+    }
+
+    fn cycle(&mut self)
+    {
+        self.bus.cycle();
+    }
+
+    fn nmi(&self) -> Option<bool>
+    {
+        self.bus.nmi()
+    }
+
+    fn take_dma(&mut self) -> Option<u8>
+    {
+        self.bus.take_dma()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger
+{
+    // removing one leaves a hole so the numbers people were
+    // given for the others stay the same
+    breakpoints: Vec<Option<Breakpoint>>,
+    watchpoints: Vec<Option<Watchpoint>>,
+
+    // flattened copy of the above for the bus to go through
+    active_watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger
+{
+    pub fn new() -> Debugger
+    {
+        Debugger::default()
+    }
+
+    // returns the number to remove it with
+    pub fn add_breakpoint(&mut self, addr: u16, condition: Option<Condition>) -> usize
+    {
+        self.breakpoints.push(Some(Breakpoint { addr, condition }));
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint>
+    {
+        self.breakpoints.get_mut(id)?.take()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)>
+    {
+        self.breakpoints.iter().enumerate()
+            .filter_map(|(i, b)| Some((i, b.as_ref()?)))
+    }
+
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) -> usize
+    {
+        self.watchpoints.push(Some(Watchpoint { start, end, kind }));
+        self.update_watchpoints();
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint>
+    {
+        let removed = self.watchpoints.get_mut(id)?.take();
+        self.update_watchpoints();
+        removed
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)>
+    {
+        self.watchpoints.iter().enumerate()
+            .filter_map(|(i, w)| Some((i, w.as_ref()?)))
+    }
+
+    fn update_watchpoints(&mut self)
+    {
+        self.active_watchpoints = self.watchpoints.iter().flatten().copied().collect();
+    }
+
+    fn breakpoint_hit(&self, cpu: &CPU) -> bool
+    {
+        self.breakpoints.iter().flatten()
+            .any(|b| b.addr == cpu.pc
+                 && b.condition.is_none_or(|c| c.holds(cpu)))
+    }
+
+    // runs one instruction, returns the access that tripped a watchpoint.
+    // faults end up in the console's log, run_until looks there
+    fn execute(&mut self, nes: &mut NES) -> Option<BusAccess>
+    {
+        if self.active_watchpoints.is_empty()
+        {
+            let _ = nes.step();
+            return None;
+        }
+
+        // never out of the decode cache, the fetches it skips are
+        // accesses a watchpoint has to see
+        let watchpoints = &self.active_watchpoints;
+        let mut hit = None;
+        let _ = nes.step_with(&mut |cpu, bus|
+        {
+            let mut bus = Watched { bus, watchpoints, hit: None };
+            cpu.step(&mut bus);
+            hit = bus.hit;
+        });
+        hit
+    }
+
+    // steps until done says so or something else stops us. done gets
+    // to look at the console after each instruction, along with the
+    // opcode that was just run. breakpoints are checked once we get
+    // to them, so the one we're stopped at doesn't stop us again
+    fn run_until(&mut self, nes: &mut NES, mut done: impl FnMut(&NES, u8) -> bool)
+        -> Option<Break>
+    {
+        loop
+        {
+            if nes.cpu().is_halted()
+            {
+                return Some(Break::Halted);
+            }
+
+            let pc = nes.cpu().pc;
+            let opcode = nes.bus().peek(pc);
+            let problems = nes.cpu().calls().count();
+            let faults = nes.faults().count();
+            if let Some(access) = self.execute(nes)
+            {
+                return Some(Break::Watchpoint(access));
+            }
+            if nes.cpu().calls().count() != problems
+            {
+                let last = nes.cpu().calls().diagnostics().last().copied();
+                return last.map(Break::Stack);
+            }
+            if nes.faults().count() != faults
+                && matches!(nes.fault_policy(), FaultPolicy::Trap | FaultPolicy::Error)
+            {
+                return nes.faults().recent().back().copied().map(Break::Fault);
+            }
+            if self.breakpoint_hit(nes.cpu())
+            {
+                return Some(Break::Breakpoint(nes.cpu().pc));
+            }
+            if done(nes, opcode)
+            {
+                return None;
+            }
+        }
+    }
+
+    pub fn step_into(&mut self, nes: &mut NES) -> Break
+    {
+        self.run_until(nes, |_, _| true)
+            .unwrap_or(Break::Stepped)
+    }
+
+    // a JSR is run until it returns, anything else is a single step
+    pub fn step_over(&mut self, nes: &mut NES) -> Break
+    {
+        let cpu = nes.cpu();
+        let (pc, s) = (cpu.pc, cpu.s);
+        if nes.bus().peek(pc) != JSR
+        {
+            return self.step_into(nes);
+        }
+
+        let ret = pc.wrapping_add(3);
+        self.run_until(nes, |nes, _| nes.cpu().pc == ret && nes.cpu().s >= s)
+            .unwrap_or(Break::Stepped)
+    }
+
+    // runs until an RTS or RTI pops the stack above where it is now
+    pub fn step_out(&mut self, nes: &mut NES) -> Break
+    {
+        let s = nes.cpu().s;
+        self.run_until(nes, |nes, op| (op == RTS || op == RTI) && nes.cpu().s > s)
+            .unwrap_or(Break::Stepped)
+    }
+
+    pub fn cont(&mut self, nes: &mut NES) -> Break
+    {
+        self.run_until(nes, |_, _| false)
+            .unwrap_or(Break::Stepped)
+    }
+
+    // like cont, but gives up after count instructions so the caller
+    // gets a chance to do something else, like listen for a stop
+    pub fn cont_for(&mut self, nes: &mut NES, count: u64) -> Break
+    {
+        let mut left = count;
+        self.run_until(nes, |_, _| { left -= 1; left == 0 })
+            .unwrap_or(Break::Stepped)
+    }
+
+    // runs until the frame counter reaches frame
+    pub fn run_to_frame(&mut self, nes: &mut NES, frame: u64) -> Break
+    {
+        self.run_until(nes, |nes, _| nes.frame() >= frame)
+            .unwrap_or(Break::Frame(nes.frame()))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::harness;
+
+    // calls sub over and over with y counting the calls
+    const CALLS: &str = "
+            .org $8000
+    reset:  ldx #$ff        ; $8000
+            txs             ; $8002
+            ldy #0          ; $8003
+    loop:   jsr sub         ; $8005
+            iny             ; $8008
+            jmp loop        ; $8009
+    sub:    lda $10         ; $800C
+            sta $11         ; $800E
+            inc $12         ; $8010
+            rts             ; $8012
+            .org $fffc
+            .word reset
+    ";
+
+    fn nes() -> NES
+    {
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(CALLS).unwrap());
+        nes.reset();
+        nes
+    }
+
+    #[test]
+    fn breakpoints()
+    {
+        let mut nes = nes();
+        let mut debugger = Debugger::new();
+        let sub = debugger.add_breakpoint(0x800c, None);
+        let third = debugger.add_breakpoint(0x8008, Some(Condition { reg: Reg::Y, cmp: Cmp::Eq, value: 3 }));
+        assert_eq!(debugger.cont(&mut nes), Break::Breakpoint(0x800c));
+        assert_eq!(debugger.cont(&mut nes), Break::Breakpoint(0x800c), "not again without moving");
+
+        // the numbers stay put when one goes
+        assert!(debugger.remove_breakpoint(sub).is_some());
+        assert!(debugger.remove_breakpoint(sub).is_none());
+        assert_eq!(debugger.breakpoints().map(|(id, _)| id).collect::<Vec<_>>(), [third]);
+
+        // only when the condition holds
+        assert_eq!(debugger.cont(&mut nes), Break::Breakpoint(0x8008));
+        assert_eq!(nes.cpu().y, 3);
+        assert_eq!(nes.bus().peek(0x12), 4);
+    }
+
+    #[test]
+    fn conditions()
+    {
+        let mut cpu = CPU::new();
+        cpu.a = 0x10;
+        cpu.pc = 0x8000;
+        let holds = |reg, cmp, value| Condition { reg, cmp, value }.holds(&cpu);
+        assert!(holds(Reg::A, Cmp::Eq, 0x10));
+        assert!(!holds(Reg::A, Cmp::Ne, 0x10));
+        assert!(holds(Reg::A, Cmp::Le, 0x10));
+        assert!(!holds(Reg::A, Cmp::Lt, 0x10));
+        assert!(holds(Reg::A, Cmp::Gt, 0x0f));
+        assert!(holds(Reg::PC, Cmp::Ge, 0x8000));
+        assert!(!holds(Reg::PC, Cmp::Gt, 0x8000));
+    }
+
+    #[test]
+    fn watchpoints()
+    {
+        let mut nes = nes();
+        let mut debugger = Debugger::new();
+
+        // stopped after the instruction that did it
+        nes.poke(0x10, 0x42);
+        let read = debugger.add_watchpoint(0x10, 0x10, WatchKind::Read);
+        assert_eq!(debugger.cont(&mut nes), Break::Watchpoint(BusAccess::Read(0x10, 0x42)));
+        assert_eq!(nes.cpu().pc, 0x800e);
+        debugger.remove_watchpoint(read);
+
+        // a range on writes
+        let writes = debugger.add_watchpoint(0x10, 0x12, WatchKind::Write);
+        assert_eq!(debugger.cont(&mut nes), Break::Watchpoint(BusAccess::Write(0x11, 0x42)));
+        // inc writes the old value back first
+        assert_eq!(debugger.cont(&mut nes), Break::Watchpoint(BusAccess::Write(0x12, 0)));
+        assert_eq!(nes.bus().peek(0x12), 1);
+        // round the loop again, the read of $10 isn't a write
+        assert_eq!(debugger.cont(&mut nes), Break::Watchpoint(BusAccess::Write(0x11, 0x42)));
+        debugger.remove_watchpoint(writes);
+
+        debugger.add_watchpoint(0x12, 0x12, WatchKind::Access);
+        assert_eq!(debugger.cont(&mut nes), Break::Watchpoint(BusAccess::Read(0x12, 1)));
+    }
+
+    #[test]
+    fn stepping()
+    {
+        let mut nes = nes();
+        let mut debugger = Debugger::new();
+        for _ in 0..3
+        {
+            assert_eq!(debugger.step_into(&mut nes), Break::Stepped);
+        }
+
+        // the whole call, back to where it returns to
+        assert_eq!(nes.cpu().pc, 0x8005);
+        assert_eq!(debugger.step_over(&mut nes), Break::Stepped);
+        assert_eq!((nes.cpu().pc, nes.cpu().s), (0x8008, 0xff));
+        assert_eq!(nes.bus().peek(0x12), 1);
+
+        // anything else is one instruction
+        assert_eq!(debugger.step_over(&mut nes), Break::Stepped);
+        assert_eq!(nes.cpu().pc, 0x8009);
+
+        // into the call and out of it again
+        debugger.step_into(&mut nes);
+        debugger.step_into(&mut nes);
+        assert_eq!(nes.cpu().pc, 0x800c);
+        debugger.step_into(&mut nes);
+        assert_eq!(debugger.step_out(&mut nes), Break::Stepped);
+        assert_eq!((nes.cpu().pc, nes.cpu().s), (0x8008, 0xff));
+
+        // a breakpoint inside still stops a step over
+        debugger.step_into(&mut nes);
+        debugger.step_into(&mut nes);
+        debugger.add_breakpoint(0x8010, None);
+        assert_eq!(debugger.step_over(&mut nes), Break::Breakpoint(0x8010));
+    }
+}
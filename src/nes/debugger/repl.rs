@@ -0,0 +1,412 @@
+// A line based front end for the debugger. Numbers are hex with a $
+// or 0x in front, decimal otherwise. An empty line repeats the last
+// command, like gdb does.
+
+use super::{ Debugger, Break, Condition, Reg, Cmp, WatchKind };
+use crate::nes::NES;
+use crate::nes::bus::{ Bus, BusAccess, FaultPolicy };
+use crate::nes::disasm;
+
+use std::io::{ self, BufRead, Write };
+
+const HELP: &str = "\
+s, step [n]            step into, n times
+n, next                step over a JSR
+f, finish              run until the current subroutine returns
+c, continue            run until something stops us
+frame [n]              run n frames (1 by default)
+back [n]               go back n frames, if rewinding is on
+b, break addr [if reg op value]
+                       breakpoint, reg is a/x/y/s/p/pc, op is == != < <= > >=
+w, watch addr[-end] [r|w|rw]
+                       watchpoint, on writes by default
+d, delete n            remove breakpoint n
+dw n                   remove watchpoint n
+i, info                list breakpoints and watchpoints
+bt, backtrace          show the call stack, innermost first
+stack                  show what went wrong with the stack lately
+faults [policy]        show the last bad accesses, or set what happens on
+                       one: ignore, log, trap or error
+r, regs                show the registers
+x addr [n]             dump n bytes of memory
+l, list [addr] [n]     disassemble n instructions
+q, quit";
+
+// reads commands from input until it runs out or we're told to quit
+pub fn run(nes: &mut NES, input: impl BufRead, mut out: impl Write) -> io::Result<()>
+{
+    let mut debugger = Debugger::new();
+    let mut last = String::new();
+
+    show(nes, &mut out)?;
+    write!(out, "> ")?;
+    out.flush()?;
+    for line in input.lines()
+    {
+        let line = line?;
+        let line = if line.trim().is_empty() { last.clone() } else { line };
+
+        match command(&mut debugger, nes, &line, &mut out)
+        {
+            Ok(true) => return Ok(()),
+            Ok(false) => (),
+            Err(e) => writeln!(out, "{}", e)?,
+        }
+        last = line;
+
+        write!(out, "> ")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// runs one command, returns true if it was quit
+fn command(debugger: &mut Debugger, nes: &mut NES, line: &str, out: &mut impl Write)
+    -> Result<bool, String>
+{
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&cmd, args)) = words.split_first()
+    else { return Ok(false) };
+
+    let result = match cmd
+    {
+        "s" | "step" =>
+        {
+            let count = args.first().map_or(Ok(1), |n| number(n))?;
+            let mut stop = Break::Stepped;
+            for _ in 0..count
+            {
+                stop = debugger.step_into(nes);
+                if stop != Break::Stepped
+                {
+                    break;
+                }
+            }
+            Some(stop)
+        }
+
+        "n" | "next" => Some(debugger.step_over(nes)),
+        "f" | "finish" => Some(debugger.step_out(nes)),
+        "c" | "continue" => Some(debugger.cont(nes)),
+
+        "frame" =>
+        {
+            let count = args.first().map_or(Ok(1), |n| number(n))?;
+            let frame = nes.frame() + count as u64;
+            Some(debugger.run_to_frame(nes, frame))
+        }
+
+        "back" =>
+        {
+            let count = args.first().map_or(Ok(1), |n| number(n))?;
+            for _ in 0..count
+            {
+                if !nes.step_back().map_err(|e| format!("{:?}", e))?
+                {
+                    say(out, String::from("can't go back any further"))?;
+                    break;
+                }
+            }
+            Some(Break::Frame(nes.frame()))
+        }
+
+        "b" | "break" =>
+        {
+            let addr = number(args.first().ok_or("break where?")?)?;
+            let condition = match args.get(1..)
+            {
+                Some(["if", cond @ ..]) => Some(condition(&cond.concat())?),
+                Some([]) | None => None,
+                _ => return Err(String::from("expected 'if' after the address")),
+            };
+            let id = debugger.add_breakpoint(addr, condition);
+            say(out, format!("breakpoint {} at ${:04X}", id, addr))?;
+            None
+        }
+
+        "w" | "watch" =>
+        {
+            let range = args.first().ok_or("watch what?")?;
+            let (start, end) = match range.split_once('-')
+            {
+                Some((start, end)) => (number(start)?, number(end)?),
+                None => (number(range)?, number(range)?),
+            };
+            let kind = match args.get(1).copied()
+            {
+                Some("r") => WatchKind::Read,
+                Some("w") | None => WatchKind::Write,
+                Some("rw") => WatchKind::Access,
+                Some(other) => return Err(format!("unknown watch kind '{}'", other)),
+            };
+            let id = debugger.add_watchpoint(start, end, kind);
+            say(out, format!("watchpoint {} on ${:04X}-${:04X}", id, start, end))?;
+            None
+        }
+
+        "d" | "delete" =>
+        {
+            let id = number(args.first().ok_or("delete which one?")?)?;
+            debugger.remove_breakpoint(id as usize)
+                .ok_or(format!("no breakpoint {}", id))?;
+            None
+        }
+
+        "dw" =>
+        {
+            let id = number(args.first().ok_or("delete which one?")?)?;
+            debugger.remove_watchpoint(id as usize)
+                .ok_or(format!("no watchpoint {}", id))?;
+            None
+        }
+
+        "i" | "info" =>
+        {
+            for (id, b) in debugger.breakpoints()
+            {
+                match b.condition
+                {
+                    Some(c) => say(out, format!("breakpoint {} at ${:04X} if {:?} {:?} ${:X}",
+                                                id, b.addr, c.reg, c.cmp, c.value))?,
+                    None => say(out, format!("breakpoint {} at ${:04X}", id, b.addr))?,
+                }
+            }
+            for (id, w) in debugger.watchpoints()
+            {
+                say(out, format!("watchpoint {} on ${:04X}-${:04X} ({:?})",
+                                 id, w.start, w.end, w.kind))?;
+            }
+            None
+        }
+
+        "bt" | "backtrace" =>
+        {
+            let frames = nes.cpu().calls().frames();
+            if frames.is_empty()
+            {
+                say(out, String::from("not in a subroutine"))?;
+            }
+            for (depth, frame) in frames.iter().rev().enumerate()
+            {
+                say(out, format!("#{:<3} {}", depth, frame))?;
+            }
+            None
+        }
+
+        "stack" =>
+        {
+            let calls = nes.cpu().calls();
+            if calls.count() == 0
+            {
+                say(out, String::from("no problems so far"))?;
+            }
+            for problem in calls.diagnostics()
+            {
+                say(out, format!("{}", problem))?;
+            }
+            None
+        }
+
+        "faults" =>
+        {
+            match args.first().copied()
+            {
+                Some(name) => nes.set_fault_policy(match name
+                {
+                    "ignore" => FaultPolicy::Ignore,
+                    "log" => FaultPolicy::Log,
+                    "trap" => FaultPolicy::Trap,
+                    "error" => FaultPolicy::Error,
+                    other => return Err(format!("unknown policy '{}'", other)),
+                }),
+                None =>
+                {
+                    let faults = nes.faults();
+                    say(out, format!("{} so far, policy is {:?}",
+                                     faults.count(), nes.fault_policy()))?;
+                    for fault in faults.recent()
+                    {
+                        say(out, format!("{}", fault))?;
+                    }
+                }
+            }
+            None
+        }
+
+        "r" | "regs" =>
+        {
+            say(out, format!("{}", nes.cpu()))?;
+            None
+        }
+
+        "x" =>
+        {
+            let addr = number(args.first().ok_or("dump where?")?)?;
+            let count = args.get(1).map_or(Ok(16), |n| number(n))?;
+            let bus = nes.bus();
+            for row in (0..count).step_by(16)
+            {
+                let start = addr.wrapping_add(row);
+                let bytes: Vec<String> = (row..count.min(row + 16))
+                    .map(|i| format!("{:02X}", bus.peek(addr.wrapping_add(i))))
+                    .collect();
+                say(out, format!("{:04X}: {}", start, bytes.join(" ")))?;
+            }
+            None
+        }
+
+        "l" | "list" =>
+        {
+            let mut addr = args.first().map_or(Ok(nes.cpu().pc), |a| number(a))?;
+            let count = args.get(1).map_or(Ok(8), |n| number(n))?;
+            let table = nes.cpu().variant().opcodes();
+            let bus = nes.bus();
+            for _ in 0..count
+            {
+                let instr = disasm::decode_with(table, addr, |a| bus.peek(a));
+                say(out, format!("{:04X}  {}", addr, instr))?;
+                addr = addr.wrapping_add(instr.len());
+            }
+            None
+        }
+
+        "h" | "help" =>
+        {
+            say(out, String::from(HELP))?;
+            None
+        }
+
+        "q" | "quit" => return Ok(true),
+
+        other => return Err(format!("unknown command '{}', try help", other)),
+    };
+
+    if let Some(stop) = result
+    {
+        match stop
+        {
+            Break::Stepped => (),
+            Break::Breakpoint(addr) => say(out, format!("breakpoint at ${:04X}", addr))?,
+            Break::Watchpoint(BusAccess::Read(addr, data)) =>
+                say(out, format!("watchpoint: read ${:02X} from ${:04X}", data, addr))?,
+            Break::Watchpoint(BusAccess::Write(addr, data)) =>
+                say(out, format!("watchpoint: wrote ${:02X} to ${:04X}", data, addr))?,
+            Break::Stack(problem) => say(out, format!("stack: {}", problem))?,
+            Break::Fault(fault) => say(out, format!("fault: {}", fault))?,
+            Break::Frame(frame) => say(out, format!("frame {}", frame))?,
+            Break::Halted => say(out, String::from("cpu is halted"))?,
+        }
+        show(nes, out).map_err(|e| e.to_string())?;
+    }
+    Ok(false)
+}
+
+// the instruction we're stopped at, in the trace format
+fn show(nes: &mut NES, out: &mut impl Write) -> io::Result<()>
+{
+    writeln!(out, "{}", nes.trace_line())
+}
+
+fn say(out: &mut impl Write, text: String) -> Result<(), String>
+{
+    writeln!(out, "{}", text).map_err(|e| e.to_string())
+}
+
+fn number(text: &str) -> Result<u16, String>
+{
+    let parsed = if let Some(hex) = text.strip_prefix('$').or(text.strip_prefix("0x"))
+        { u16::from_str_radix(hex, 16) }
+    else
+        { text.parse() };
+    parsed.map_err(|_| format!("bad number '{}'", text))
+}
+
+// like a==$10 or pc>=$8000, spaces already taken out
+fn condition(text: &str) -> Result<Condition, String>
+{
+    // two character operators first so <= isn't read as <
+    let ops = [("==", Cmp::Eq), ("!=", Cmp::Ne), ("<=", Cmp::Le),
+               (">=", Cmp::Ge), ("<", Cmp::Lt), (">", Cmp::Gt)];
+    let (reg, cmp, value) = ops.iter()
+        .find_map(|(op, cmp)| text.split_once(op).map(|(reg, value)| (reg, *cmp, value)))
+        .ok_or(format!("no comparison in '{}'", text))?;
+
+    let reg = match reg.to_lowercase().as_str()
+    {
+        "a" => Reg::A,
+        "x" => Reg::X,
+        "y" => Reg::Y,
+        "s" => Reg::S,
+        "p" => Reg::P,
+        "pc" => Reg::PC,
+        other => return Err(format!("unknown register '{}'", other)),
+    };
+
+    Ok(Condition { reg, cmp, value: number(value)? })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::harness;
+
+    #[test]
+    fn numbers()
+    {
+        assert_eq!(number("$1f"), Ok(0x1f));
+        assert_eq!(number("0x1F"), Ok(0x1f));
+        assert_eq!(number("31"), Ok(31));
+        assert_eq!(number("$ffff"), Ok(0xffff));
+        assert_eq!(number("$"), Err(String::from("bad number '$'")));
+        assert!(number("1f").is_err(), "hex needs its prefix");
+        assert!(number("$10000").is_err());
+        assert!(number("-1").is_err());
+    }
+
+    #[test]
+    fn conditions()
+    {
+        let parsed = |text| condition(text).map(|c| (c.reg, c.cmp, c.value));
+        assert_eq!(parsed("a==$10"), Ok((Reg::A, Cmp::Eq, 0x10)));
+        assert_eq!(parsed("X!=3"), Ok((Reg::X, Cmp::Ne, 3)));
+        assert_eq!(parsed("y<=0x10"), Ok((Reg::Y, Cmp::Le, 0x10)));
+        assert_eq!(parsed("y<16"), Ok((Reg::Y, Cmp::Lt, 16)));
+        assert_eq!(parsed("pc>=$8000"), Ok((Reg::PC, Cmp::Ge, 0x8000)));
+        assert_eq!(parsed("s>$f0"), Ok((Reg::S, Cmp::Gt, 0xf0)));
+        assert_eq!(parsed("p=1"), Err(String::from("no comparison in 'p=1'")));
+        assert_eq!(parsed("q==1"), Err(String::from("unknown register 'q'")));
+        assert_eq!(parsed("a==zz"), Err(String::from("bad number 'zz'")));
+    }
+
+    #[test]
+    fn session()
+    {
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom("
+                .org $8000
+        reset:  ldy #0
+        loop:   iny             ; $8002
+                jmp loop
+                .org $fffc
+                .word reset
+        ").unwrap());
+        nes.reset();
+
+        let input = "b $8002 when y==2\nb $zz\nb $8002 if y == 2\nc\ns\n\ni\nd 0\nd 0\nq\ns\n";
+        let mut out = Vec::new();
+        run(&mut nes, input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // spaces in the condition are fine, the empty line steps again
+        // and nothing is run after the quit
+        assert_eq!(nes.cpu().pc, 0x8002);
+        assert_eq!(nes.cpu().y, 3);
+        for line in ["expected 'if' after the address", "bad number '$zz'",
+                     "breakpoint 0 at $8002\n", "breakpoint at $8002\n",
+                     "breakpoint 0 at $8002 if Y Eq $2\n", "no breakpoint 0\n"]
+        {
+            assert!(out.contains(line), "no '{}' in:\n{}", line, out);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+// Turns machine code back into assembly text. Works on plain byte
+// slices (like a prg bank straight out of the rom) or on whatever is
+// at an address on a bus.
+
+use crate::nes::cpu::{ AddrMode, Opcode, OPCODES };
+use crate::nes::bus::Bus;
+
+use std::fmt::{ Display, Formatter };
+
+#[derive(Debug, Clone)]
+pub struct Instruction
+{
+    pub addr: u16, // where it lives, branch targets are relative to this
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: AddrMode,
+    pub official: bool,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl Instruction
+{
+    pub fn len(&self) -> u16
+    {
+        self.bytes.len() as u16
+    }
+
+    // the operand as a number, zero for the modes that have none
+    pub fn operand(&self) -> u16
+    {
+        match self.bytes.len()
+        {
+            2 => self.bytes[1] as u16,
+            3 => self.bytes[1] as u16 | (self.bytes[2] as u16) << 8,
+            _ => 0,
+        }
+    }
+
+    // where control goes if this is a branch, jump or call with a
+    // known destination
+    pub fn target(&self) -> Option<u16>
+    {
+        match self.mode
+        {
+            AddrMode::Rel =>
+            {
+                let offset = self.bytes[1] as i8;
+                Some(self.addr.wrapping_add(2).wrapping_add(offset as u16))
+            }
+
+            AddrMode::ZpgRel =>
+            {
+                let offset = self.bytes[2] as i8;
+                Some(self.addr.wrapping_add(3).wrapping_add(offset as u16))
+            }
+
+            AddrMode::Abs if self.mnemonic == "JMP" || self.mnemonic == "JSR" =>
+                Some(self.operand()),
+
+            _ => None,
+        }
+    }
+
+    pub fn operand_text(&self) -> String
+    {
+        let value = self.operand();
+        match self.mode
+        {
+            AddrMode::Imp => String::new(),
+            AddrMode::Acc => String::from("A"),
+            AddrMode::Imm => format!("#${:02X}", value),
+            AddrMode::Zpg => format!("${:02X}", value),
+            AddrMode::ZpgX => format!("${:02X},X", value),
+            AddrMode::ZpgY => format!("${:02X},Y", value),
+            AddrMode::Abs => format!("${:04X}", value),
+            AddrMode::AbsX => format!("${:04X},X", value),
+            AddrMode::AbsY => format!("${:04X},Y", value),
+            AddrMode::Ind => format!("(${:04X})", value),
+            AddrMode::IndX => format!("(${:02X},X)", value),
+            AddrMode::IndY => format!("(${:02X}),Y", value),
+            AddrMode::Rel => format!("${:04X}", self.target().unwrap()),
+            AddrMode::IndZpg => format!("(${:02X})", value),
+            AddrMode::IndAbsX => format!("(${:04X},X)", value),
+            AddrMode::ZpgRel => format!("${:02X},${:04X}", value & 0xff, self.target().unwrap()),
+        }
+    }
+}
+
+impl Display for Instruction
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        if self.mode == AddrMode::Imp
+            { write!(f, "{}", self.mnemonic) }
+        else
+            { write!(f, "{} {}", self.mnemonic, self.operand_text()) }
+    }
+}
+
+// decodes the instruction at addr, getting the bytes from fetch
+pub fn decode(addr: u16, fetch: impl Fn(u16) -> u8) -> Instruction
+{
+    decode_with(&OPCODES, addr, fetch)
+}
+
+// same, for a chip with a different decode table
+pub fn decode_with(table: &[Opcode; 256], addr: u16, fetch: impl Fn(u16) -> u8)
+    -> Instruction
+{
+    let opcode = table[fetch(addr) as usize];
+    let bytes = (0..=opcode.mode.operand_len())
+        .map(|i| fetch(addr.wrapping_add(i)))
+        .collect();
+
+    Instruction {
+        addr, bytes,
+        mnemonic: opcode.mnemonic,
+        mode: opcode.mode,
+        official: opcode.official,
+    }
+}
+
+// the instruction at the start of bytes, which are mapped at addr.
+// an operand cut off by the end of the slice reads as zeros
+pub fn disassemble(bytes: &[u8], addr: u16) -> Instruction
+{
+    decode(addr, |a| *bytes.get(a.wrapping_sub(addr) as usize).unwrap_or(&0))
+}
+
+// every instruction in bytes, back to back, starting at base
+pub fn disassemble_all(bytes: &[u8], base: u16) -> Vec<Instruction>
+{
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len()
+    {
+        let instr = disassemble(&bytes[offset..], base.wrapping_add(offset as u16));
+        offset += instr.len() as usize;
+        out.push(instr);
+    }
+    out
+}
+
+// the instruction at addr in the cpu's address space
+pub fn disassemble_at(bus: &dyn Bus, addr: u16) -> Instruction
+{
+    decode(addr, |a| bus.peek(a))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::cpu::CMOS_OPCODES;
+
+    fn text(bytes: &[u8]) -> String
+    {
+        disassemble(bytes, 0x8000).to_string()
+    }
+
+    #[test]
+    fn addressing_modes()
+    {
+        assert_eq!(text(&[0xea]), "NOP");
+        assert_eq!(text(&[0x0a]), "ASL A");
+        assert_eq!(text(&[0xa9, 0x05]), "LDA #$05");
+        assert_eq!(text(&[0xa5, 0x10]), "LDA $10");
+        assert_eq!(text(&[0xb5, 0x10]), "LDA $10,X");
+        assert_eq!(text(&[0xb6, 0x10]), "LDX $10,Y");
+        assert_eq!(text(&[0xad, 0x34, 0x12]), "LDA $1234");
+        assert_eq!(text(&[0xbd, 0x34, 0x12]), "LDA $1234,X");
+        assert_eq!(text(&[0xb9, 0x34, 0x12]), "LDA $1234,Y");
+        assert_eq!(text(&[0x6c, 0xfc, 0xff]), "JMP ($FFFC)");
+        assert_eq!(text(&[0xa1, 0x20]), "LDA ($20,X)");
+        assert_eq!(text(&[0xb1, 0x20]), "LDA ($20),Y");
+
+        // the byte count goes with the mode
+        let lens: Vec<u16> = disassemble_all(&[0xea, 0xa9, 0x05, 0xad, 0x34, 0x12], 0x8000)
+            .iter().map(Instruction::len).collect();
+        assert_eq!(lens, [1, 2, 3]);
+    }
+
+    #[test]
+    fn cmos_modes()
+    {
+        let cmos = |bytes: &[u8]|
+            decode_with(&CMOS_OPCODES, 0x8000, |a| bytes[(a - 0x8000) as usize]).to_string();
+        assert_eq!(cmos(&[0xb2, 0x20]), "LDA ($20)");
+        assert_eq!(cmos(&[0x7c, 0x34, 0x12]), "JMP ($1234,X)");
+        assert_eq!(cmos(&[0x89, 0x40]), "BIT #$40");
+        assert_eq!(cmos(&[0x9c, 0x00, 0x02]), "STZ $0200");
+        assert_eq!(cmos(&[0x80, 0xfe]), "BRA $8000");
+        assert_eq!(cmos(&[0x0f, 0x10, 0x03]), "BBR0 $10,$8006");
+        assert_eq!(cmos(&[0x8f, 0x10, 0xfd]), "BBS0 $10,$8000");
+
+        // the same bytes on the nmos table
+        assert_eq!(text(&[0xb2]), "JAM");
+        assert_eq!(text(&[0x80, 0xfe]), "NOP #$FE");
+    }
+
+    #[test]
+    fn branch_targets()
+    {
+        // from the address after the branch
+        assert_eq!(text(&[0xd0, 0x00]), "BNE $8002");
+        assert_eq!(text(&[0xd0, 0x7f]), "BNE $8081");
+        assert_eq!(text(&[0xd0, 0x80]), "BNE $7F82");
+        assert_eq!(text(&[0xf0, 0xfe]), "BEQ $8000");
+
+        // and round the end of the address space
+        assert_eq!(disassemble(&[0x90, 0x10], 0xfff8).target(), Some(0x000a));
+        assert_eq!(disassemble(&[0xb0, 0xf0], 0x0004).to_string(), "BCS $FFF6");
+
+        // jumps and calls have a target too, indirect ones don't
+        assert_eq!(disassemble(&[0x20, 0x34, 0x12], 0x8000).target(), Some(0x1234));
+        assert_eq!(disassemble(&[0x4c, 0x34, 0x12], 0x8000).target(), Some(0x1234));
+        assert_eq!(disassemble(&[0x6c, 0x34, 0x12], 0x8000).target(), None);
+        assert_eq!(disassemble(&[0xad, 0x34, 0x12], 0x8000).target(), None);
+    }
+
+    #[test]
+    fn undocumented()
+    {
+        let unofficial = |bytes: &[u8]|
+        {
+            let instr = disassemble(bytes, 0x8000);
+            (instr.official, instr.to_string())
+        };
+        assert_eq!(unofficial(&[0xa7, 0x10]), (false, "LAX $10".into()));
+        assert_eq!(unofficial(&[0xb3, 0x10]), (false, "LAX ($10),Y".into()));
+        assert_eq!(unofficial(&[0x03, 0x10]), (false, "SLO ($10,X)".into()));
+        assert_eq!(unofficial(&[0xdb, 0x34, 0x12]), (false, "DCP $1234,Y".into()));
+        assert_eq!(unofficial(&[0xe7, 0x10]), (false, "ISB $10".into()));
+        assert_eq!(unofficial(&[0x87, 0x10]), (false, "SAX $10".into()));
+        assert_eq!(unofficial(&[0xeb, 0x10]), (false, "SBC #$10".into()));
+        assert_eq!(unofficial(&[0x1c, 0x34, 0x12]), (false, "NOP $1234,X".into()));
+        assert_eq!(unofficial(&[0x1a]), (false, "NOP".into()));
+        assert_eq!(unofficial(&[0x02]), (false, "JAM".into()));
+        assert_eq!(unofficial(&[0xea]), (true, "NOP".into()));
+    }
+
+    #[test]
+    fn cut_off_operand()
+    {
+        // what's missing at the end of the slice reads as zero
+        let instr = disassemble(&[0xad, 0x34], 0x8000);
+        assert_eq!(instr.bytes, [0xad, 0x34, 0x00]);
+        assert_eq!(instr.to_string(), "LDA $0034");
+    }
+}
@@ -0,0 +1,571 @@
+// A stub for the GDB remote serial protocol, so an external debugger
+// can attach over TCP. Registers go out in the order a, x, y, s, p, pc,
+// one byte each except pc which is two, little endian. The same layout
+// is described in target.xml for clients that ask for it.
+//
+// Supported: ? g G p P m M c s Z0-Z4 z0-z4 k D, qSupported and
+// qXfer:features:read. Everything else gets the empty "not supported"
+// reply, like the protocol expects.
+
+use crate::nes::NES;
+use crate::nes::bus::{ Bus, BusAccess };
+use crate::nes::debugger::{ Debugger, Break, WatchKind };
+
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ self, Read, Write, ErrorKind };
+use std::net::{ TcpListener, TcpStream, ToSocketAddrs };
+
+// instructions run between looking for a ^C while continuing
+const CHUNK: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.sentiw.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8" regnum="1"/>
+    <reg name="y" bitsize="8" regnum="2"/>
+    <reg name="s" bitsize="8" regnum="3"/>
+    <reg name="p" bitsize="8" regnum="4"/>
+    <reg name="pc" bitsize="16" regnum="5" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// waits for one connection on addr and serves it until it goes away
+pub fn serve(nes: &mut NES, addr: impl ToSocketAddrs) -> io::Result<()>
+{
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Stub::new(stream).run(nes)
+}
+
+struct Stub
+{
+    stream: TcpStream,
+    debugger: Debugger,
+
+    // what the client calls them (Z type and address) to our ids
+    breakpoints: HashMap<(u8, u16), usize>,
+
+    // what came in while looking for a ^C, for receive to go through
+    // before reading any more
+    pending: VecDeque<u8>,
+}
+
+impl Stub
+{
+    fn new(stream: TcpStream) -> Stub
+    {
+        Stub {
+            stream,
+            debugger: Debugger::new(),
+            breakpoints: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn run(&mut self, nes: &mut NES) -> io::Result<()>
+    {
+        while let Some(packet) = self.receive()?
+        {
+            let reply = match packet.as_bytes().first()
+            {
+                Some(b'?') => format!("S{:02x}", SIGTRAP),
+                Some(b'g') => self.read_registers(nes),
+                Some(b'G') => self.write_registers(nes, &packet[1..]),
+                Some(b'p') => self.read_register(nes, &packet[1..]),
+                Some(b'P') => self.write_register(nes, &packet[1..]),
+                Some(b'm') => self.read_memory(nes, &packet[1..]),
+                Some(b'M') => self.write_memory(nes, &packet[1..]),
+                Some(b's') => self.resume(nes, &packet[1..], true)?,
+                Some(b'c') => self.resume(nes, &packet[1..], false)?,
+                Some(b'Z') => self.breakpoint(&packet[1..], true),
+                Some(b'z') => self.breakpoint(&packet[1..], false),
+                Some(b'q') => self.query(&packet[1..]),
+                Some(b'k') => return Ok(()),
+                Some(b'D') =>
+                {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ => String::new(),
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    // reads up to the next packet and acks it. None when the
+    // connection is closed
+    fn receive(&mut self) -> io::Result<Option<String>>
+    {
+        loop
+        {
+            // anything outside a packet is an ack or a stray ^C
+            loop
+            {
+                match self.next_byte()?
+                {
+                    None => return Ok(None),
+                    Some(b'$') => break,
+                    Some(_) => (),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop
+            {
+                match self.next_byte()?
+                {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+
+            let (Some(hi), Some(lo)) = (self.next_byte()?, self.next_byte()?)
+            else { return Ok(None) };
+            let expected = std::str::from_utf8(&[hi, lo]).ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+
+            if expected == Some(checksum(&data))
+            {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()>
+    {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // the next byte from the client, None when the connection is closed
+    fn next_byte(&mut self) -> io::Result<Option<u8>>
+    {
+        if let Some(byte) = self.pending.pop_front()
+        {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0u8];
+        Ok((self.stream.read(&mut byte)? == 1).then_some(byte[0]))
+    }
+
+    // true if the client sent a ^C while we were running, or went away.
+    // anything else it sent is kept for receive
+    fn interrupted(&mut self) -> io::Result<bool>
+    {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = loop
+        {
+            match self.stream.read(&mut byte)
+            {
+                Ok(1) if byte[0] == 0x03 => break Ok(true),
+                Ok(1) => self.pending.push_back(byte[0]),
+                Ok(_) => break Ok(true),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    fn read_registers(&self, nes: &NES) -> String
+    {
+        let cpu = nes.cpu();
+        let bytes = [cpu.a, cpu.x, cpu.y, cpu.s, cpu.p, cpu.pc as u8, (cpu.pc >> 8) as u8];
+        hex(&bytes)
+    }
+
+    fn write_registers(&self, nes: &mut NES, args: &str) -> String
+    {
+        match unhex(args).as_deref()
+        {
+            Some(&[a, x, y, s, p, lo, hi]) =>
+            {
+                let cpu = nes.cpu_mut();
+                (cpu.a, cpu.x, cpu.y, cpu.s, cpu.p) = (a, x, y, s, p);
+                cpu.pc = lo as u16 | (hi as u16) << 8;
+                String::from("OK")
+            }
+            _ => String::from("E01"),
+        }
+    }
+
+    fn read_register(&self, nes: &NES, args: &str) -> String
+    {
+        let cpu = nes.cpu();
+        match u8::from_str_radix(args, 16)
+        {
+            Ok(0) => hex(&[cpu.a]),
+            Ok(1) => hex(&[cpu.x]),
+            Ok(2) => hex(&[cpu.y]),
+            Ok(3) => hex(&[cpu.s]),
+            Ok(4) => hex(&[cpu.p]),
+            Ok(5) => hex(&cpu.pc.to_le_bytes()),
+            _ => String::from("E01"),
+        }
+    }
+
+    fn write_register(&self, nes: &mut NES, args: &str) -> String
+    {
+        let Some((reg, value)) = args.split_once('=')
+        else { return String::from("E01") };
+
+        let cpu = nes.cpu_mut();
+        match (u8::from_str_radix(reg, 16), unhex(value).as_deref())
+        {
+            (Ok(0), Some(&[v])) => cpu.a = v,
+            (Ok(1), Some(&[v])) => cpu.x = v,
+            (Ok(2), Some(&[v])) => cpu.y = v,
+            (Ok(3), Some(&[v])) => cpu.s = v,
+            (Ok(4), Some(&[v])) => cpu.p = v,
+            (Ok(5), Some(&[lo, hi])) => cpu.pc = lo as u16 | (hi as u16) << 8,
+            _ => return String::from("E01"),
+        }
+        String::from("OK")
+    }
+
+    // m addr,length
+    fn read_memory(&self, nes: &mut NES, args: &str) -> String
+    {
+        let Some((addr, len)) = addr_len(args)
+        else { return String::from("E01") };
+
+        let bus = nes.bus();
+        let bytes: Vec<u8> = (0..len)
+            .map(|i| bus.peek(addr.wrapping_add(i)))
+            .collect();
+        hex(&bytes)
+    }
+
+    // M addr,length:bytes
+    fn write_memory(&self, nes: &mut NES, args: &str) -> String
+    {
+        let Some((range, data)) = args.split_once(':')
+        else { return String::from("E01") };
+        let (Some((addr, len)), Some(data)) = (addr_len(range), unhex(data))
+        else { return String::from("E01") };
+        if data.len() != len as usize
+        {
+            return String::from("E01");
+        }
+
+        // all of it or none of it
+        if !(0..len).all(|i| nes.pokeable(addr.wrapping_add(i)))
+        {
+            return String::from("E02");
+        }
+        for (i, byte) in data.into_iter().enumerate()
+        {
+            nes.poke(addr.wrapping_add(i as u16), byte);
+        }
+        String::from("OK")
+    }
+
+    // s and c can take an address to resume from
+    fn resume(&mut self, nes: &mut NES, args: &str, step: bool) -> io::Result<String>
+    {
+        if let Ok(addr) = u16::from_str_radix(args, 16)
+        {
+            nes.cpu_mut().pc = addr;
+        }
+
+        let stop = if step
+        {
+            self.debugger.step_into(nes)
+        }
+        else
+        {
+            loop
+            {
+                let stop = self.debugger.cont_for(nes, CHUNK);
+                if stop != Break::Stepped
+                {
+                    break stop;
+                }
+                if self.interrupted()?
+                {
+                    return Ok(format!("S{:02x}", SIGINT));
+                }
+            }
+        };
+
+        Ok(match stop
+        {
+            Break::Watchpoint(access) =>
+            {
+                // reported by the address the client asked to watch
+                let addr = match access
+                {
+                    BusAccess::Read(addr, _) | BusAccess::Write(addr, _) => addr,
+                };
+                let (kind, start) = self.debugger.watchpoints()
+                    .find(|(_, w)| w.matches(access))
+                    .map_or(("awatch", addr), |(_, w)| match w.kind
+                    {
+                        WatchKind::Write => ("watch", w.start),
+                        WatchKind::Read => ("rwatch", w.start),
+                        WatchKind::Access => ("awatch", w.start),
+                    });
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, start)
+            }
+            Break::Halted => format!("S{:02x}", SIGILL),
+            Break::Stack(_) => format!("S{:02x}", SIGSEGV),
+            Break::Fault(_) => format!("S{:02x}", SIGBUS),
+            _ => format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    // Z type,addr,kind to insert, z to remove. 0 and 1 are software
+    // and hardware breakpoints, which are the same thing here. 2, 3
+    // and 4 are write, read and access watchpoints
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String
+    {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else { return String::from("E01") };
+        let (Ok(kind), Ok(addr), Ok(len)) = (kind.parse::<u8>(),
+                                            u16::from_str_radix(addr, 16),
+                                            u16::from_str_radix(len, 16))
+        else { return String::from("E01") };
+
+        let watch = match kind
+        {
+            0 | 1 => None,
+            2 => Some(WatchKind::Write),
+            3 => Some(WatchKind::Read),
+            4 => Some(WatchKind::Access),
+            _ => return String::new(),
+        };
+
+        if insert
+        {
+            // the same one again is still just the one, so a single z
+            // takes it away
+            if self.breakpoints.contains_key(&(kind, addr))
+            {
+                return String::from("OK");
+            }
+            let id = match watch
+            {
+                None => self.debugger.add_breakpoint(addr, None),
+                Some(watch) =>
+                {
+                    let end = addr.wrapping_add(len.max(1) - 1);
+                    self.debugger.add_watchpoint(addr, end, watch)
+                }
+            };
+            self.breakpoints.insert((kind, addr), id);
+        }
+        else if let Some(id) = self.breakpoints.remove(&(kind, addr))
+        {
+            match watch
+            {
+                None => self.debugger.remove_breakpoint(id).map(|_| ()),
+                Some(_) => self.debugger.remove_watchpoint(id).map(|_| ()),
+            };
+        }
+        String::from("OK")
+    }
+
+    fn query(&self, args: &str) -> String
+    {
+        if args.starts_with("Supported")
+        {
+            return String::from("PacketSize=4000;qXfer:features:read+");
+        }
+
+        // qXfer:features:read:target.xml:offset,length
+        if let Some(rest) = args.strip_prefix("Xfer:features:read:target.xml:")
+        {
+            let Some((offset, len)) = rest.split_once(',')
+                .and_then(|(o, l)| Some((usize::from_str_radix(o, 16).ok()?,
+                                         usize::from_str_radix(l, 16).ok()?)))
+            else { return String::from("E01") };
+
+            let xml = TARGET_XML.as_bytes();
+            let start = offset.min(xml.len());
+            let end = (start + len).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+
+        if args == "Attached"
+        {
+            return String::from("1");
+        }
+
+        String::new()
+    }
+}
+
+fn checksum(data: &[u8]) -> u8
+{
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>>
+{
+    if !text.len().is_multiple_of(2)
+    {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// addr,length in hex
+fn addr_len(text: &str) -> Option<(u16, u16)>
+{
+    let (addr, len) = text.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, u16::from_str_radix(len, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::harness;
+
+    use std::net::Shutdown;
+
+    // $8000 ldx, $8002 txs, $8003 lda, $8005 sta, $8007 inc, $8009 jmp
+    const LOOP: &str = "
+            .org $8000
+    reset:  ldx #$ff
+            txs
+            lda #$12
+            sta $10
+    loop:   inc $11
+            jmp loop
+            .org $fffc
+            .word reset
+    ";
+
+    fn nes() -> NES
+    {
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(LOOP).unwrap());
+        nes.reset();
+        nes
+    }
+
+    fn packet(data: &str) -> String
+    {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    // everything the client sends is there before the stub starts, and
+    // then the client hangs up. what comes back, acks and all
+    fn session(nes: &mut NES, sent: &str) -> String
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(sent.as_bytes()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        Stub::new(stream).run(nes).unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        received
+    }
+
+    // the replies to packets, each checked against its checksum
+    fn replies(nes: &mut NES, packets: &[&str]) -> Vec<String>
+    {
+        let sent: String = packets.iter().map(|p| packet(p)).collect();
+        let received = session(nes, &sent);
+        received.split('$').skip(1)
+            .map(|reply|
+            {
+                let (data, sum) = reply.split_once('#').unwrap();
+                assert_eq!(&sum[..2], format!("{:02x}", checksum(data.as_bytes())));
+                data.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksums()
+    {
+        let mut nes = nes();
+        // a bad one is nacked and dropped, the next one still works
+        let received = session(&mut nes, &format!("$p0#00{}", packet("p1")));
+        assert_eq!(received, format!("-+{}", packet(&hex(&[nes.cpu().x]))));
+    }
+
+    #[test]
+    fn registers()
+    {
+        let mut nes = nes();
+        assert_eq!(replies(&mut nes, &["G010203fd24cdab", "g", "p5", "P0=7f", "p0",
+                                       "P5=0080", "G0102", "p9"]),
+                   ["OK", "010203fd24cdab", "cdab", "OK", "7f", "OK", "E01", "E01"]);
+        let cpu = nes.cpu();
+        assert_eq!((cpu.a, cpu.x, cpu.y, cpu.s, cpu.p, cpu.pc), (0x7f, 2, 3, 0xfd, 0x24, 0x8000));
+    }
+
+    #[test]
+    fn memory()
+    {
+        let mut nes = nes();
+        assert_eq!(replies(&mut nes, &["M10,2:abcd", "m10,3", "M7ffe,2:0102", "M1fff,2:5566",
+                                       "m1fff,1", "M10,2:ab", "m8000,2"]),
+                   ["OK", "abcd00", "OK", "E02", "00", "E01", "a2ff"]);
+        // the range runs into the ppu, so none of it was written
+        assert_eq!(nes.bus().peek(0x1fff), 0x00);
+        assert_eq!(nes.bus().peek(0x7fff), 0x02);
+    }
+
+    #[test]
+    fn breakpoints_and_stepping()
+    {
+        let mut nes = nes();
+        assert_eq!(replies(&mut nes, &["Z0,8009,1", "c", "p5", "s", "p5", "m11,1",
+                                       "z0,8009,1", "Z2,11,1", "c", "p5"]),
+                   ["OK", "S05", "0980", "S05", "0780", "01",
+                    "OK", "OK", "T05watch:0011;", "0980"]);
+    }
+
+    #[test]
+    fn inserting_twice_is_one_breakpoint()
+    {
+        let mut nes = nes();
+        // the single z takes it away, so only the ^C stops the c
+        let sent: String = ["Z0,8009,1", "Z0,8009,1", "z0,8009,1", "c"]
+            .iter().map(|p| packet(p)).collect();
+        let received = session(&mut nes, &format!("{}\x03", sent));
+        assert!(received.ends_with(&format!("+{}", packet("S02"))), "{}", received);
+    }
+
+    #[test]
+    fn packets_while_running_are_kept()
+    {
+        let mut nes = nes();
+        // the m comes in before the ^C, while the stub is looking for one
+        let sent = format!("{}{}\x03", packet("c"), packet("m10,1"));
+        let received = session(&mut nes, &sent);
+        assert_eq!(received, format!("+{}+{}", packet("S02"), packet("12")));
+    }
+}
@@ -0,0 +1,358 @@
+// Runs the cpu on its own against 64K of plain RAM with nothing NES
+// specific mapped, which is what generic 6502 test suites expect.
+// Klaus Dormann's functional test traps (jumps to itself) when it's
+// done or when something fails, and Tom Harte's tests are JSON files
+// describing the state before and after a single instruction along
+// with every bus access it does.
+//
+// There's also a quick way to make a cart out of assembled code, for
+// running things on the whole console.
+
+mod json;
+
+use crate::nes::cpu::{ CPU, Variant };
+use crate::nes::bus::{ Bus, BusAccess };
+use crate::nes::ines::{ self, INesRom };
+use crate::nes::asm;
+
+use json::Value;
+
+pub struct FlatRam
+{
+    pub mem: Vec<u8>,
+    pub log: Option<Vec<BusAccess>>, // every access is recorded here when set
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Stop
+{
+    Trap(u16),  // pc stopped changing, at this address
+    CycleLimit,
+    Halted,     // hit a JAM
+}
+
+#[derive(Debug, Default)]
+pub struct Report
+{
+    pub passed: usize,
+    pub failed: Vec<(String, String)>, // test name and what was wrong
+}
+
+impl Default for FlatRam
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl FlatRam
+{
+    pub fn new() -> FlatRam
+    {
+        FlatRam {
+            mem: vec![0; 0x10000],
+            log: None,
+        }
+    }
+
+    // copies image into RAM starting at addr
+    pub fn load(&mut self, addr: u16, image: &[u8])
+    {
+        let start = addr as usize;
+        let end = (start + image.len()).min(self.mem.len());
+        self.mem[start..end].copy_from_slice(&image[..end-start]);
+    }
+}
+
+impl Bus for FlatRam
+{
+    fn read(&mut self, addr: u16) -> u8
+    {
+        let data = self.mem[addr as usize];
+        if let Some(log) = &mut self.log
+        {
+            log.push(BusAccess::Read(addr, data));
+        }
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8)
+    {
+        self.mem[addr as usize] = data;
+        if let Some(log) = &mut self.log
+        {
+            log.push(BusAccess::Write(addr, data));
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8
+    {
+        self.mem[addr as usize]
+    }
+}
+
+// steps until an instruction leaves pc where it was, or until
+// max_cycles more cycles have gone by
+pub fn run_until_trap(cpu: &mut CPU, bus: &mut dyn Bus, max_cycles: u64) -> Stop
+{
+    let limit = cpu.cycles + max_cycles;
+    loop
+    {
+        if cpu.is_halted()
+        {
+            return Stop::Halted;
+        }
+        if cpu.cycles >= limit
+        {
+            return Stop::CycleLimit;
+        }
+
+        let pc = cpu.pc;
+        cpu.step(bus);
+        if cpu.pc == pc
+        {
+            return Stop::Trap(pc);
+        }
+    }
+}
+
+// runs every test in one of the per-opcode JSON files on the given chip
+pub fn run_json_tests(text: &str, variant: Variant) -> Result<Report, String>
+{
+    let tests = json::parse(text)?;
+    let tests = tests.as_array()
+        .ok_or("expected an array of tests")?;
+
+    let mut report = Report::default();
+    for test in tests
+    {
+        let name = test.get("name")
+            .and_then(Value::as_str)
+            .unwrap_or("unnamed")
+            .to_string();
+
+        match run_json_test(test, variant)
+        {
+            Ok(()) => report.passed += 1,
+            Err(reason) => report.failed.push((name, reason)),
+        }
+    }
+    Ok(report)
+}
+
+fn field(value: &Value, key: &str) -> Result<u64, String>
+{
+    value.get(key)
+        .and_then(Value::as_u64)
+        .ok_or(format!("missing '{}'", key))
+}
+
+// [[addr, value], ...]
+fn ram_entries(state: &Value) -> Result<Vec<(u16, u8)>, String>
+{
+    let entries = state.get("ram")
+        .and_then(Value::as_array)
+        .ok_or("missing 'ram'")?;
+
+    entries.iter()
+        .map(|entry| match entry.as_array()
+        {
+            Some([addr, data]) => Ok((addr.as_u64().unwrap_or(0) as u16,
+                                      data.as_u64().unwrap_or(0) as u8)),
+            _ => Err(String::from("bad ram entry")),
+        })
+        .collect()
+}
+
+// [[addr, value, "read" or "write"], ...]
+fn bus_entries(test: &Value) -> Result<Vec<BusAccess>, String>
+{
+    let entries = test.get("cycles")
+        .and_then(Value::as_array)
+        .ok_or("missing 'cycles'")?;
+
+    entries.iter()
+        .map(|entry| match entry.as_array()
+        {
+            Some([addr, data, kind]) =>
+            {
+                let addr = addr.as_u64().unwrap_or(0) as u16;
+                let data = data.as_u64().unwrap_or(0) as u8;
+                match kind.as_str()
+                {
+                    Some("read") => Ok(BusAccess::Read(addr, data)),
+                    Some("write") => Ok(BusAccess::Write(addr, data)),
+                    _ => Err(String::from("bad cycle kind")),
+                }
+            }
+            _ => Err(String::from("bad cycle entry")),
+        })
+        .collect()
+}
+
+// a cart with nothing on it yet, prg_count 16K banks of prg and
+// chr_count 8K banks of chr. code goes in with Assembly::patch
+pub fn blank_cart(mapper: u8, prg_count: u8, chr_count: u8) -> Result<INesRom, ines::Error>
+{
+    let len = 16 + prg_count as usize * 0x4000 + chr_count as usize * 0x2000;
+    let mut image = vec![0; len];
+    image[..4].copy_from_slice(b"NES\x1a");
+    image[4] = prg_count;
+    image[5] = chr_count;
+    image[6] = mapper << 4;
+    image[7] = mapper & 0xf0;
+    INesRom::new(&mut image.as_slice())
+}
+
+// an NROM cart with 32K of prg assembled from source, which should
+// .org itself somewhere in $8000-$FFFF and set the vectors, and 8K of
+// empty chr
+pub fn nrom(source: &str) -> Result<INesRom, asm::Error>
+{
+    let assembly = asm::assemble(source)?;
+    let mut cart = blank_cart(0, 2, 1)
+        .unwrap_or_else(|_| unreachable!("NROM is always supported"));
+    let prg = cart.prg_offset..cart.chr_offset;
+    assembly.patch(&mut cart.buffer[prg], 0x8000)?;
+    Ok(cart)
+}
+
+fn run_json_test(test: &Value, variant: Variant) -> Result<(), String>
+{
+    let initial = test.get("initial").ok_or("missing 'initial'")?;
+    let expected = test.get("final").ok_or("missing 'final'")?;
+
+    let mut ram = FlatRam::new();
+    for (addr, data) in ram_entries(initial)?
+    {
+        ram.mem[addr as usize] = data;
+    }
+
+    let mut cpu = CPU::with_variant(variant);
+    cpu.pc = field(initial, "pc")? as u16;
+    cpu.s = field(initial, "s")? as u8;
+    cpu.a = field(initial, "a")? as u8;
+    cpu.x = field(initial, "x")? as u8;
+    cpu.y = field(initial, "y")? as u8;
+    cpu.p = field(initial, "p")? as u8;
+    ram.log = Some(Vec::new());
+
+    cpu.step(&mut ram);
+
+    let regs = [
+        ("pc", cpu.pc as u64), ("s", cpu.s as u64), ("a", cpu.a as u64),
+        ("x", cpu.x as u64), ("y", cpu.y as u64), ("p", cpu.p as u64),
+    ];
+    for (name, got) in regs
+    {
+        let want = field(expected, name)?;
+        if got != want
+        {
+            return Err(format!("{} is {:02x}, expected {:02x}", name, got, want));
+        }
+    }
+
+    for (addr, want) in ram_entries(expected)?
+    {
+        let got = ram.peek(addr);
+        if got != want
+        {
+            return Err(format!("${:04x} is {:02x}, expected {:02x}", addr, got, want));
+        }
+    }
+
+    let log = ram.log.take().unwrap();
+    let want = bus_entries(test)?;
+    if log != want
+    {
+        return Err(format!("bus activity was {:x?}, expected {:x?}", log, want));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // LDA #$01 in the per-opcode file format
+    const LDA_IMM: &str = r#"[
+        {
+            "name": "a9 01 ee",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38,
+                         "ram": [[512, 169], [513, 1], [514, 238]] },
+            "final": { "pc": 514, "s": 253, "a": 1, "x": 0, "y": 0, "p": 36,
+                       "ram": [[512, 169], [513, 1], [514, 238]] },
+            "cycles": [[512, 169, "read"], [513, 1, "read"]]
+        },
+        {
+            "name": "wrong on purpose",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                         "ram": [[512, 169], [513, 1]] },
+            "final": { "pc": 514, "s": 253, "a": 2, "x": 0, "y": 0, "p": 36,
+                       "ram": [[512, 169], [513, 1]] },
+            "cycles": [[512, 169, "read"], [513, 1, "read"]]
+        }
+    ]"#;
+
+    #[test]
+    fn json_tests()
+    {
+        let report = run_json_tests(LDA_IMM, Variant::Ricoh2A03).unwrap();
+        assert_eq!(report.passed, 1);
+        assert_eq!(report.failed.len(), 1);
+        let (name, reason) = &report.failed[0];
+        assert_eq!(name, "wrong on purpose");
+        assert_eq!(reason, "a is 01, expected 02");
+    }
+
+    #[test]
+    fn json_test_bus_activity_is_checked()
+    {
+        let text = LDA_IMM.replacen(r#"[513, 1, "read"]]"#, r#"[513, 1, "write"]]"#, 1);
+        let report = run_json_tests(&text, Variant::Ricoh2A03).unwrap();
+        assert_eq!(report.passed, 0);
+        assert!(report.failed[0].1.starts_with("bus activity"));
+    }
+
+    #[test]
+    fn bad_json_tests()
+    {
+        assert!(run_json_tests("{}", Variant::Ricoh2A03).is_err());
+        let report = run_json_tests(r#"[{ "name": "empty" }]"#, Variant::Ricoh2A03).unwrap();
+        assert_eq!(report.failed, [("empty".to_string(), "missing 'initial'".to_string())]);
+    }
+
+    #[test]
+    fn trap()
+    {
+        // lda #1, then jmp * at $0202
+        let mut ram = FlatRam::new();
+        ram.load(0x0200, &[0xa9, 0x01, 0x4c, 0x02, 0x02]);
+        let mut cpu = CPU::new();
+        cpu.pc = 0x0200;
+        assert_eq!(run_until_trap(&mut cpu, &mut ram, 100), Stop::Trap(0x0202));
+        assert_eq!(cpu.a, 1);
+        assert_eq!(cpu.cycles, 2 + 3);
+    }
+
+    #[test]
+    fn trap_limits()
+    {
+        // a two instruction loop never leaves pc where it was
+        let mut ram = FlatRam::new();
+        ram.load(0x0200, &[0xea, 0x4c, 0x00, 0x02]);
+        let mut cpu = CPU::new();
+        cpu.pc = 0x0200;
+        assert_eq!(run_until_trap(&mut cpu, &mut ram, 100), Stop::CycleLimit);
+        assert!(cpu.cycles >= 100);
+
+        // JAM
+        ram.load(0x0200, &[0x02]);
+        let mut cpu = CPU::new();
+        cpu.pc = 0x0200;
+        assert_eq!(run_until_trap(&mut cpu, &mut ram, 100), Stop::Halted);
+    }
+}
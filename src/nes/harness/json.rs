@@ -0,0 +1,258 @@
+// Just enough of a JSON reader for the per-opcode test files.
+// Numbers are kept as f64, which is plenty for addresses and bytes.
+
+#[derive(Debug)]
+pub enum Value
+{
+    Null,
+    #[allow(dead_code)]
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value
+{
+    pub fn get(&self, key: &str) -> Option<&Value>
+    {
+        match self
+        {
+            Value::Object(fields) => fields.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]>
+    {
+        match self
+        {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64>
+    {
+        match self
+        {
+            Value::Number(n) if *n >= 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str>
+    {
+        match self
+        {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String>
+{
+    let mut parser = Parser { text: text.as_bytes(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.text.len()
+    {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a>
+{
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a>
+{
+    fn error(&self, what: &str) -> String
+    {
+        format!("{} at byte {}", what, self.pos)
+    }
+
+    fn skip_whitespace(&mut self)
+    {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace()
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8>
+    {
+        self.skip_whitespace();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String>
+    {
+        if self.peek() == Some(c)
+        {
+            self.pos += 1;
+            Ok(())
+        }
+        else
+        {
+            Err(self.error(&format!("expected '{}'", c as char)))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String>
+    {
+        if self.text[self.pos..].starts_with(word.as_bytes())
+        {
+            self.pos += word.len();
+            Ok(value)
+        }
+        else
+        {
+            Err(self.error("unknown literal"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String>
+    {
+        match self.peek()
+        {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(_) => self.number(),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String>
+    {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}')
+        {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop
+        {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            match self.peek()
+            {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; return Ok(Value::Object(fields)) }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String>
+    {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']')
+        {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop
+        {
+            items.push(self.value()?);
+            match self.peek()
+            {
+                Some(b',') => self.pos += 1,
+                Some(b']') => { self.pos += 1; return Ok(Value::Array(items)) }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    // escapes other than \" and \\ don't show up in the test files
+    fn string(&mut self) -> Result<String, String>
+    {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        while let Some(&c) = self.text.get(self.pos)
+        {
+            self.pos += 1;
+            match c
+            {
+                b'"' => return String::from_utf8(out).map_err(|_| self.error("bad utf-8")),
+                b'\\' =>
+                {
+                    let escaped = *self.text.get(self.pos)
+                        .ok_or_else(|| self.error("unexpected end"))?;
+                    self.pos += 1;
+                    out.push(match escaped
+                    {
+                        b'n' => b'\n',
+                        b't' => b'\t',
+                        other => other,
+                    });
+                }
+                _ => out.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn number(&mut self) -> Result<Value, String>
+    {
+        let start = self.pos;
+        while self.pos < self.text.len()
+            && matches!(self.text[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Value::Number)
+            .ok_or_else(|| self.error("bad number"))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn parses_nested_values()
+    {
+        let value = parse(r#" { "name": "a9 \"x\"", "n": [1, 2.5, -3e2], "t": true,
+                                "none": null, "empty": {}, "list": [] } "#).unwrap();
+        assert_eq!(value.get("name").and_then(Value::as_str), Some("a9 \"x\""));
+        let n = value.get("n").and_then(Value::as_array).unwrap();
+        assert_eq!(n.len(), 3);
+        assert_eq!(n[0].as_u64(), Some(1));
+        assert!(matches!(n[1], Value::Number(x) if x == 2.5));
+        assert_eq!(n[2].as_u64(), None, "negative");
+        assert!(matches!(value.get("t"), Some(Value::Bool(true))));
+        assert!(matches!(value.get("none"), Some(Value::Null)));
+        assert!(matches!(value.get("empty"), Some(Value::Object(f)) if f.is_empty()));
+        assert_eq!(value.get("list").and_then(Value::as_array).map(<[_]>::len), Some(0));
+        assert!(value.get("missing").is_none());
+    }
+
+    #[test]
+    fn rejects_broken_json()
+    {
+        for text in ["", "[1, 2", "{\"a\" 1}", "\"open", "[1] 2", "nul", "[1,]"]
+        {
+            assert!(parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+}
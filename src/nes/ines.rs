@@ -2,7 +2,6 @@
 // it doesn't do mapping it doesn't do anything else!
 // only reads and parses .nes files
 
-use std::fs::{ File };
 use std::io::{ Read };
 use std::cmp::{ Ordering };
 use std::result::Result::{ self, Ok, Err };
@@ -33,12 +32,14 @@ pub enum HeaderKind
     INES2,   // iNes2.0
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring
 {
     Horizontal,
     Vertical,
-    FourScreen
+    FourScreen,
+    SingleLower, // one nametable for all four, only mappers pick these
+    SingleUpper,
 }
 
 pub struct INesRom
@@ -86,7 +87,7 @@ impl INesRom
         &self.buffer[0..16]
     }
 
-    pub fn new(file: &mut File) -> Result<Self, Error>
+    pub fn new(file: &mut impl Read) -> Result<Self, Error>
     {
         let mut out = Self::new_empty();
         match file.read_to_end(&mut out.buffer)
@@ -97,7 +98,7 @@ impl INesRom
         // should it call another function like
         // parse_header or something after this?
 
-        if (&out.header()[0..4]).cmp(MAGIC_NUM) != Ordering::Equal
+        if out.buffer.len() < 16 || out.header()[0..4].cmp(MAGIC_NUM) != Ordering::Equal
         {
             return Err(Error::HeaderNotFound);
         }
@@ -140,8 +141,8 @@ impl INesRom
         out.chr_count = out.buffer[5];
         out.has_trainer = out.buffer[6] & 4 != 0;
 
-        out.prg_offset = 0
-            + if out.header_kind != HeaderKind::NoHeader { 16 } else { 0 }
+        out.prg_offset =
+            if out.header_kind != HeaderKind::NoHeader { 16 } else { 0 }
             + if out.has_trainer { 512 } else { 0 };
                 
         out.chr_offset = out.prg_offset + out.prg_size();
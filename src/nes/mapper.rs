@@ -0,0 +1,265 @@
+// The registers on the cart that decide which banks of prg and chr the
+// console sees, and how the nametables are mirrored. NROM has none of
+// them, everything is where the rom file says. MMC1 takes its
+// registers five bits at a time through writes to $8000-$FFFF.
+//
+// Every change to where prg is mapped bumps a counter, so whatever
+// keeps things by cpu address, like the decode cache, knows it has to
+// look again. See Bus::bank_switches.
+
+use crate::nes::ines::Mirroring;
+use crate::nes::state::{ self, Reader, Writer };
+
+const PRG_BANK: usize = 0x4000;
+const CHR_BANK: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapper
+{
+    kind: u16, // the ines mapper number
+    shift: u8, // MMC1's bits so far, with a 1 marking where they end
+    control: u8,
+    chr: [u8; 2],
+    prg: u8,
+    switches: u64,
+}
+
+impl Mapper
+{
+    pub fn new(kind: u16) -> Mapper
+    {
+        Mapper {
+            kind,
+            shift: 0x10,
+            // MMC1 starts with the last bank at $C000, where the
+            // reset vector has to be
+            control: 0x0c,
+            chr: [0; 2],
+            prg: 0,
+            switches: 0,
+        }
+    }
+
+    // how many times prg banks have been switched
+    pub fn switches(&self) -> u64
+    {
+        self.switches
+    }
+
+    // a cpu write to $8000-$FFFF. false if there's no register there
+    // and it was a write to rom
+    pub fn write(&mut self, addr: u16, data: u8) -> bool
+    {
+        if self.kind != 1
+        {
+            return false;
+        }
+        if data & 0x80 != 0
+        {
+            self.shift = 0x10;
+            self.set(0x8000, self.control | 0x0c);
+            return true;
+        }
+
+        let done = self.shift & 1 != 0;
+        self.shift = self.shift >> 1 | (data & 1) << 4;
+        if done
+        {
+            self.set(addr, self.shift);
+            self.shift = 0x10;
+        }
+        true
+    }
+
+    // one of MMC1's registers, by which quarter of rom it was written to
+    fn set(&mut self, addr: u16, data: u8)
+    {
+        match addr & 0xe000
+        {
+            0x8000 =>
+            {
+                let prg_mode = (self.control ^ data) & 0x0c != 0;
+                self.control = data;
+                self.switches += prg_mode as u64;
+            }
+            0xa000 => self.chr[0] = data,
+            0xc000 => self.chr[1] = data,
+            _ =>
+            {
+                let switched = (self.prg ^ data) & 0x0f != 0;
+                self.prg = data;
+                self.switches += switched as u64;
+            }
+        }
+    }
+
+    // where in prg the byte the cpu sees at addr ($8000-$FFFF) is.
+    // prg_size is never 0
+    pub fn prg_offset(&self, addr: u16, prg_size: usize) -> usize
+    {
+        let addr = addr as usize & 0x7fff;
+        let banks = prg_size / PRG_BANK;
+        let bank = match (self.kind, self.control >> 2 & 3)
+        {
+            // NROM, a 16K rom is in both halves
+            (1, 0 | 1) => (self.prg as usize & 0x0e) + addr / PRG_BANK,
+            (1, 2) if addr < PRG_BANK => 0,
+            (1, 2) => self.prg as usize & 0x0f,
+            (1, _) if addr < PRG_BANK => self.prg as usize & 0x0f,
+            (1, _) => banks - 1,
+            _ => return addr % prg_size,
+        };
+        bank % banks * PRG_BANK + addr % PRG_BANK
+    }
+
+    // where in chr the byte the ppu sees at addr ($0000-$1FFF) is
+    pub fn chr_offset(&self, addr: u16, chr_size: usize) -> usize
+    {
+        let addr = addr as usize & 0x1fff;
+        if self.kind != 1 || chr_size == 0
+        {
+            return addr;
+        }
+        let banks = (chr_size / CHR_BANK).max(1);
+        let bank = if self.control & 0x10 == 0
+            { (self.chr[0] as usize & 0x1e) + addr / CHR_BANK }
+        else
+            { self.chr[addr / CHR_BANK] as usize };
+        bank % banks * CHR_BANK + addr % CHR_BANK
+    }
+
+    // the header's mirroring, unless the mapper picks it itself
+    pub fn mirroring(&self, header: Mirroring) -> Mirroring
+    {
+        if self.kind != 1
+        {
+            return header;
+        }
+        match self.control & 3
+        {
+            0 => Mirroring::SingleLower,
+            1 => Mirroring::SingleUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer)
+    {
+        if self.kind == 1
+        {
+            w.bytes(&[self.shift, self.control, self.chr[0], self.chr[1], self.prg]);
+        }
+    }
+
+    // this mapper with the registers from a state, counting as a
+    // switch since the banks may well be different now. a state
+    // without any is from before there were mappers that have them
+    pub fn load_state(&self, r: &mut Reader) -> Result<Mapper, state::Error>
+    {
+        let mut mapper = Mapper::new(self.kind);
+        mapper.switches = self.switches + 1;
+        if self.kind == 1 && !r.is_empty()
+        {
+            [mapper.shift, mapper.control, mapper.chr[0], mapper.chr[1], mapper.prg] = r.array()?;
+        }
+        Ok(mapper)
+    }
+}
+
+impl Default for Mapper
+{
+    fn default() -> Self
+    {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // the five writes MMC1 takes a register in, low bit first
+    fn mmc1(mapper: &mut Mapper, addr: u16, value: u8)
+    {
+        for i in 0..5
+        {
+            assert!(mapper.write(addr, value >> i & 1));
+        }
+    }
+
+    #[test]
+    fn nrom()
+    {
+        let mut mapper = Mapper::new(0);
+        assert!(!mapper.write(0x8000, 0x01));
+        assert_eq!(mapper.prg_offset(0xc123, 0x4000), 0x0123);
+        assert_eq!(mapper.prg_offset(0xc123, 0x8000), 0x4123);
+        assert_eq!(mapper.chr_offset(0x1234, 0x2000), 0x1234);
+        assert_eq!(mapper.mirroring(Mirroring::Vertical), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn mmc1_prg_banks()
+    {
+        let mut mapper = Mapper::new(1);
+        let size = 8 * PRG_BANK;
+        // the last bank is fixed at $C000 from power on
+        assert_eq!(mapper.prg_offset(0xc000, size), 7 * PRG_BANK);
+
+        mmc1(&mut mapper, 0xe000, 3);
+        assert_eq!(mapper.switches(), 1);
+        assert_eq!(mapper.prg_offset(0x8001, size), 3 * PRG_BANK + 1);
+        assert_eq!(mapper.prg_offset(0xffff, size), 8 * PRG_BANK - 1);
+
+        // the same bank again doesn't switch anything
+        mmc1(&mut mapper, 0xe000, 3);
+        assert_eq!(mapper.switches(), 1);
+
+        // fixed first bank, $C000 switched
+        mmc1(&mut mapper, 0x8000, 0x08);
+        assert_eq!(mapper.switches(), 2);
+        assert_eq!(mapper.prg_offset(0x8000, size), 0);
+        assert_eq!(mapper.prg_offset(0xc000, size), 3 * PRG_BANK);
+
+        // 32K at a time, the low bit is ignored
+        mmc1(&mut mapper, 0x8000, 0x00);
+        assert_eq!(mapper.prg_offset(0x8000, size), 2 * PRG_BANK);
+        assert_eq!(mapper.prg_offset(0xc000, size), 3 * PRG_BANK);
+    }
+
+    #[test]
+    fn mmc1_reset_bit()
+    {
+        let mut mapper = Mapper::new(1);
+        mmc1(&mut mapper, 0x8000, 0x02);
+        // a write with bit 7 throws away the bits so far and fixes
+        // the last bank again
+        mapper.write(0x8000, 1);
+        mapper.write(0x8000, 1);
+        mapper.write(0x8000, 0x80);
+        mmc1(&mut mapper, 0xe000, 1);
+        assert_eq!(mapper.prg_offset(0x8000, 4 * PRG_BANK), PRG_BANK);
+        assert_eq!(mapper.prg_offset(0xc000, 4 * PRG_BANK), 3 * PRG_BANK);
+        assert_eq!(mapper.mirroring(Mirroring::Horizontal), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn mmc1_chr_and_mirroring()
+    {
+        let mut mapper = Mapper::new(1);
+        let size = 8 * CHR_BANK;
+        mmc1(&mut mapper, 0xa000, 5);
+        // 8K at a time
+        assert_eq!(mapper.chr_offset(0x0000, size), 4 * CHR_BANK);
+        assert_eq!(mapper.chr_offset(0x1000, size), 5 * CHR_BANK);
+
+        // two 4K banks, one screen from the upper nametable
+        mmc1(&mut mapper, 0x8000, 0x1d);
+        mmc1(&mut mapper, 0xc000, 2);
+        assert_eq!(mapper.chr_offset(0x0010, size), 5 * CHR_BANK + 0x10);
+        assert_eq!(mapper.chr_offset(0x1010, size), 2 * CHR_BANK + 0x10);
+        assert_eq!(mapper.mirroring(Mirroring::Vertical), Mirroring::SingleUpper);
+    }
+}
@@ -8,6 +8,7 @@ pub enum Error
     SegmentOverlap,
     AddressNotMapped,
     SegmentNotLoaded,
+    ReadOnly,
 }
 
 #[derive(Debug)]
@@ -31,7 +32,7 @@ pub struct Segment<'a>
     start: u16,
     end: u16,
     size: u16, // this is not always implicit since there might be mirroring
-    kind: Kind,
+    pub kind: Kind,
     buffer: Buffer<'a>,
     pub ready: bool, // does it have the reference or not
 }
@@ -165,9 +166,9 @@ pub struct Segment<'a>
                     }
 
                     Buffer::ReadOnly(_) =>
-                        panic!("trying to write to a read only segment"),
+                        Err(Error::ReadOnly),
                     Buffer::NotEnabled =>
-                        panic!("the segment is active but no buffer!"),
+                        Err(Error::SegmentBufferNotLoaded),
                 }
             }
         }
@@ -181,6 +182,14 @@ pub struct MemoryMap<'a>
 }
 
 
+impl<'a> Default for MemoryMap<'a>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
 impl<'a> MemoryMap<'a>
 {
     pub fn new() -> MemoryMap<'a>
@@ -208,8 +217,8 @@ impl<'a> MemoryMap<'a>
 
     // returns the segment that contains this address,
     // returns None if the address isn't mapped
-    pub fn map(&'a self, addr: u16)
-        -> Result<(&Segment, u16), Error>
+    pub fn map(&self, addr: u16)
+        -> Result<(&Segment<'a>, u16), Error>
     {
         for seg in &self.segs
         {
@@ -224,8 +233,8 @@ impl<'a> MemoryMap<'a>
 
     // returns a mutable reference to the segment that
     // contains this address
-    pub fn map_mut<'b>(&'b mut self, addr: u16)
-        -> Result<(&'b mut Segment<'a>, u16), Error>
+    pub fn map_mut(&mut self, addr: u16)
+        -> Result<(&mut Segment<'a>, u16), Error>
     {
         for seg in &mut self.segs
         {
@@ -242,7 +251,7 @@ impl<'a> MemoryMap<'a>
     {
         match self.map(addr)
         {
-            Ok((seg, offset)) => Ok(seg.read(offset).unwrap()),
+            Ok((seg, offset)) => seg.read(offset),
             Err(e) => Err(e)
         }
     }
@@ -252,16 +261,12 @@ impl<'a> MemoryMap<'a>
     {
         match self.map_mut(addr)
         {
-            Ok((seg, offset)) =>
-            {
-                seg.write(offset, data);
-                Ok(())
-            }
+            Ok((seg, offset)) => seg.write(offset, data),
             Err(e) => Err(e)
         }
     }
 
-    pub fn enable_seg_rw<'b>(&'b mut self, addr: u16, slice: &'a mut [u8])
+    pub fn enable_seg_rw(&mut self, addr: u16, slice: &'a mut [u8])
         -> Result<(), Error>
     {
         match self.map_mut(addr)
@@ -281,7 +286,7 @@ impl<'a> MemoryMap<'a>
         }
     }
 
-    pub fn disable_seg<'b>(&'b mut self, addr: u16)
+    pub fn disable_seg(&mut self, addr: u16)
         -> Result<(), Error>
     {
         match self.map_mut(addr)
@@ -1,89 +1,887 @@
 pub mod cpu;
 pub mod ppu;
+pub mod bus;
 pub mod memory_map;
 pub mod ines;
+pub mod asm;
+pub mod disasm;
+pub mod harness;
+pub mod trace;
+pub mod debugger;
+pub mod gdb;
+pub mod state;
+pub mod rewind;
+pub mod profiler;
+pub mod cdl;
+pub mod mapper;
 
 use ppu::PPU;
-use cpu::CPU;
-use ines::{ INesRom, Error };
-use memory_map::{ Kind, MemoryMap };
+use cpu::{ CPU, DecodeCache };
+use bus::Bus;
+use bus::{ CpuBus, Fault, FaultPolicy, FaultLog };
+use ines::INesRom;
+use trace::Tracer;
+use state::{ State, Writer };
+use rewind::Rewind;
+use profiler::Profiler;
+use cdl::CodeDataLogger;
+use mapper::Mapper;
+
+use std::io::{ self, Write };
+use std::fs;
+use std::path::Path;
 
 #[derive(Debug)]
-pub struct NES<'a>
+pub struct NES
 {
-    cpu: CPU<'a>,
+    cpu: CPU,
     ppu: PPU,
-    ram: [u8; 0x800], 
+    ram: [u8; 0x800],
     vram: [u8; 0x1000],
+    prg_ram: [u8; 0x2000], // on the cart really, at $6000
     cart: Option<INesRom>,
+    mapper: Mapper,
+    tracer: Option<Tracer>,
+    trace_error: Option<io::Error>, // why the tracer was dropped, if it was
+    rewind: Option<Rewind>,
+    profiler: Option<Profiler>,
+    cdl: Option<CodeDataLogger>,
+    cache: Option<Box<DecodeCache>>, // boxed, it gets taken out for every step
+    drawn: Vec<usize>, // chr the ppu drew with during a step, for the cdl
+    fault_policy: FaultPolicy,
+    faults: FaultLog,
     // cart: Cart
     // apu
     // controller
 }
 
-impl<'a> NES<'a>
+impl Default for NES
 {
-    pub fn new() -> NES<'a>
+    fn default() -> Self
     {
-        let mut cpu = CPU::new();
-        let mut ppu = PPU::new();
-
-        // Setting up the memory for all the devices
-        cpu.mem.add_seg(0x0000, 0x1FFF, 0x800,  Kind::RAM);
-        cpu.mem.add_seg(0x8000, 0xBFFF, 0x4000, Kind::ROM);
-        cpu.mem.add_seg(0xC000, 0xFFFF, 0x4000, Kind::ROM);
-
+        Self::new()
+    }
+}
 
+impl NES
+{
+    pub fn new() -> NES
+    {
         NES {
-            cpu, ppu,
+            cpu: CPU::new(),
+            ppu: PPU::new(),
             cart: None,
+            mapper: Mapper::new(0),
+            tracer: None,
+            trace_error: None,
+            rewind: None,
+            profiler: None,
+            cdl: None,
+            cache: None,
+            drawn: Vec::new(),
+            fault_policy: FaultPolicy::Log,
+            faults: FaultLog::new(),
             ram: [0; 0x800],
             vram: [0; 0x1000],
+            prg_ram: [0; 0x2000],
         }
     }
 
-    pub fn run(&'a mut self)
+    // only ever stops for a fault, and only with FaultPolicy::Error
+    pub fn run(&mut self) -> Result<(), Fault>
     {
-        self.cpu.mem.enable_seg_rw(0x0000, &mut self.ram);
-        self.cpu.mem.enable_seg_rw(0x8000, &mut self.vram);
-        // ok i got it. i'm borrowing mem immutebly so i can give back a
-        // mutable range from it. i shouldn't do that maybe? or at least
-        // define the life times in better terms. right now I can only
-        // map_mut once because it borrows mem and keeps it for ever
-        
-        //let (seg, _) = self.cpu.mem.map_mut(0x8000).unwrap();
-
-        self.cpu.reset();
+        self.reset();
         loop
         {
-            println!("{}", &self.cpu);
-            self.cpu.step();
+            self.step()?;
         }
     }
 
+    // runs one instruction, the devices are lent to the cpu
+    // through the bus only for as long as it takes. the instruction
+    // is always finished, even if it comes back with a fault
+    pub fn step(&mut self) -> Result<(), Fault>
+    {
+        match self.cache.take()
+        {
+            Some(mut cache) =>
+            {
+                let result = self.step_with(&mut |cpu, bus| cpu.step_cached(bus, &mut cache));
+                self.cache = Some(cache);
+                result
+            }
+            None => self.step_with(&mut |cpu, bus| cpu.step(bus)),
+        }
+    }
 
-    pub fn reset(&mut self)
+    // what happens when the cpu accesses something that isn't there,
+    // like a write to rom. see bus.rs
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy)
     {
-        todo!();
+        self.fault_policy = policy;
     }
 
-    // do we need one more layer of abstraction here?
-    // inesrom -> cart -> nes instead of directly
-    pub fn load_cart<'b>(&'b mut self, cart: INesRom)
+    pub fn fault_policy(&self) -> FaultPolicy
+    {
+        self.fault_policy
+    }
+
+    pub fn faults(&self) -> &FaultLog
+    {
+        &self.faults
+    }
+
+    // runs rom code out of a decode cache from now on, see cpu/cache.rs.
+    // it counts the same cycles, it's just quicker about it
+    pub fn use_decode_cache(&mut self, enabled: bool)
+    {
+        self.cache = enabled.then(|| Box::new(DecodeCache::new(self.cpu.variant())));
+    }
+
+    pub fn decode_cache(&self) -> Option<&DecodeCache>
+    {
+        self.cache.as_deref()
+    }
+
+    // same as step, but exec gets to run the cpu on the bus itself,
+    // so it can wrap the bus or look at the cpu around the step
+    pub fn step_with(&mut self, exec: &mut dyn FnMut(&mut CPU, &mut dyn Bus))
+        -> Result<(), Fault>
+    {
+        let mut bus = CpuBus::new(&mut self.ram, &mut self.prg_ram, &mut self.ppu,
+                                  &mut self.vram, self.cart.as_ref(), &mut self.mapper);
+        bus.drawn = self.cdl.is_some().then_some(&mut self.drawn);
+
+        if let Some(tracer) = &mut self.tracer
+        {
+            // a sink that stops taking lines, like a pipe into head
+            // that's been closed, ends the trace and not the game
+            if let Err(e) = tracer.trace(&self.cpu, &bus, bus.ppu)
+            {
+                self.tracer = None;
+                self.trace_error = Some(e);
+            }
+        }
+
+        let start = self.profiler.as_ref().map(|p| p.start(&self.cpu, bus.ppu));
+        match &mut self.cdl
+        {
+            // a step spent waiting or halted doesn't run anything
+            Some(cdl) if !self.cpu.is_waiting() && !self.cpu.is_halted() =>
+            {
+                let pc = self.cpu.pc;
+                let opcode = self.cpu.variant().opcodes()[bus.peek(pc) as usize];
+                exec(&mut self.cpu, &mut cdl.watch(&mut bus));
+                cdl.log(pc, opcode, |addr| bus.prg_file_offset(addr));
+            }
+            _ => exec(&mut self.cpu, &mut bus),
+        }
+        let fault = bus.take_fault();
+        let chr_read = bus.chr_read;
+        if let Some(cdl) = &mut self.cdl
+        {
+            if let Some(offset) = chr_read
+            {
+                cdl.mark_chr(offset, cdl::READ);
+            }
+            for offset in self.drawn.drain(..)
+            {
+                cdl.mark_chr(offset, cdl::DRAWN);
+            }
+        }
+        if let (Some(profiler), Some(start)) = (&mut self.profiler, start)
+        {
+            profiler.record(start, &self.cpu);
+        }
+
+        let frame = self.frame();
+        if self.rewind.as_ref().is_some_and(|r| r.due(frame))
+        {
+            let state = self.save_state();
+            if let Some(rewind) = &mut self.rewind
+            {
+                rewind.push(frame, state);
+            }
+        }
+
+        let Some(fault) = fault
+        else { return Ok(()) };
+        if self.fault_policy == FaultPolicy::Ignore
+        {
+            return Ok(());
+        }
+        self.faults.push(fault);
+        match self.fault_policy
+        {
+            FaultPolicy::Error => Err(fault),
+            _ => Ok(()),
+        }
+    }
+
+    // starts marking the cart's bytes as code or data, see cdl.rs.
+    // false if there's no cart to mark
+    pub fn log_code_data(&mut self) -> bool
+    {
+        self.cdl = self.cart.as_ref().map(CodeDataLogger::new);
+        self.cdl.is_some()
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLogger>
+    {
+        self.cdl.as_ref()
+    }
+
+    pub fn cdl_mut(&mut self) -> Option<&mut CodeDataLogger>
+    {
+        self.cdl.as_mut()
+    }
+
+    // starts counting cycles from the next instruction on
+    pub fn profile(&mut self, profiler: Profiler)
+    {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler>
+    {
+        self.profiler.as_ref()
+    }
+
+    // writes what the profiler found, see Profiler::report
+    pub fn profile_report(&mut self, out: &mut dyn Write, top: usize) -> io::Result<()>
+    {
+        let Some(profiler) = &self.profiler
+        else { return Ok(()) };
+        let bus = CpuBus::new(&mut self.ram, &mut self.prg_ram, &mut self.ppu,
+                                  &mut self.vram, self.cart.as_ref(), &mut self.mapper);
+        profiler.report(out, top, self.cpu.variant().opcodes(), &bus)
+    }
+
+    // starts keeping a snapshot every interval frames, in at most
+    // budget bytes. the oldest ones go first when it runs out
+    pub fn enable_rewind(&mut self, interval: u64, budget: usize)
+    {
+        self.rewind = Some(Rewind::new(interval, budget));
+    }
+
+    pub fn rewind(&self) -> Option<&Rewind>
+    {
+        self.rewind.as_ref()
+    }
+
+    // goes back to the start of the previous frame, by loading the last
+    // snapshot before it and running up to it again. false once the
+    // history runs out, or if rewinding was never enabled
+    pub fn step_back(&mut self) -> Result<bool, state::Error>
+    {
+        let Some(target) = self.frame().checked_sub(1)
+        else { return Ok(false) };
+        let Some(mut rewind) = self.rewind.take()
+        else { return Ok(false) };
+
+        // anything after the target is the future now
+        while rewind.latest().is_some_and(|(frame, _)| frame > target)
+        {
+            rewind.pop();
+        }
+        let loaded = match rewind.latest()
+        {
+            Some((_, state)) => self.load_state(state).map(|_| true),
+            None => Ok(false),
+        };
+        self.rewind = Some(rewind);
+        if !loaded?
+        {
+            return Ok(false);
+        }
+
+        // all of it happened the first time already. what went wrong
+        // went wrong then too, and nothing watching should see it twice
+        let tracer = self.tracer.take();
+        let profiler = self.profiler.take();
+        let cdl = self.cdl.take();
+        let faults = std::mem::take(&mut self.faults);
+        while self.frame() < target
+        {
+            let _ = self.step();
+        }
+        (self.tracer, self.profiler, self.cdl, self.faults) = (tracer, profiler, cdl, faults);
+        Ok(true)
+    }
+
+    // the trace line for the instruction that's about to run
+    pub fn trace_line(&mut self) -> String
+    {
+        let bus = CpuBus::new(&mut self.ram, &mut self.prg_ram, &mut self.ppu,
+                                  &mut self.vram, self.cart.as_ref(), &mut self.mapper);
+        trace::line(&self.cpu, &bus, bus.ppu)
+    }
+
+    // starts writing a trace line for every instruction to sink
+    pub fn trace_to(&mut self, sink: Box<dyn Write>)
+    {
+        self.tracer = Some(Tracer::new(sink));
+        self.trace_error = None;
+    }
+
+    // pauses or resumes the trace without dropping the sink
+    pub fn set_tracing(&mut self, enabled: bool)
+    {
+        if let Some(tracer) = &mut self.tracer
+        {
+            tracer.enabled = enabled;
+        }
+    }
+
+    // what went wrong writing the trace, if that's why it stopped
+    pub fn trace_error(&self) -> Option<&io::Error>
+    {
+        self.trace_error.as_ref()
+    }
+
+
+    pub fn cpu(&self) -> &CPU
+    {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU
+    {
+        &mut self.cpu
+    }
+
+    pub fn ppu(&self) -> &PPU
     {
-        self.cart = Some(cart);
+        &self.ppu
+    }
+
+    pub fn frame(&self) -> u64
+    {
+        self.ppu.frame()
+    }
+
+    // the last frame the ppu finished, 256x240 indices into the nes'
+    // palette of 64 colours. it changes once a frame, at vblank
+    pub fn framebuffer(&self) -> &[u8; ppu::WIDTH * ppu::HEIGHT]
+    {
+        self.ppu.picture()
+    }
+
+    // lends out the cpu's view of the console, for poking at it
+    // from outside while nothing is running
+    pub fn bus(&mut self) -> CpuBus<'_>
+    {
+        CpuBus::new(&mut self.ram, &mut self.prg_ram, &mut self.ppu,
+                    &mut self.vram, self.cart.as_ref(), &mut self.mapper)
+    }
+
+    // writes straight into ram or the cart's prg ram from outside, for
+    // debuggers. false for anything else, the registers would do things
+    // a debugger doesn't mean and rom isn't writable at all
+    pub fn poke(&mut self, addr: u16, data: u8) -> bool
+    {
+        match addr
+        {
+            0x0000..=0x1fff => self.ram[addr as usize % 0x800] = data,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => return false,
+        }
+        true
+    }
+
+    // whether poke would write to addr, to check a range before any
+    // of it is written
+    pub fn pokeable(&self, addr: u16) -> bool
+    {
+        matches!(addr, 0x0000..=0x1fff | 0x6000..=0x7fff)
+    }
+
+    // the whole machine as it is right now, see state.rs for the format.
+    // the cart itself isn't in there, only its bank registers and ram
+    pub fn save_state(&self) -> Vec<u8>
+    {
+        let mut w = Writer::new();
+        w.chunk(b"CPU ", |w| self.cpu.save_state(w));
+        w.chunk(b"PPU ", |w| self.ppu.save_state(w));
+        w.chunk(b"RAM ", |w| w.bytes(&self.ram));
+        w.chunk(b"VRAM", |w| w.bytes(&self.vram));
         if let Some(cart) = &self.cart
         {
-            let sl = &cart.buffer[0..10];
-            match cart.mapper
+            w.chunk(b"CART", |w|
             {
-                0..=1 =>
-                    self.cpu.mem.enable_seg_ro(0x8000, sl),
+                w.u16(cart.mapper);
+                w.u32(cart.prg_size() as u32);
+                w.u32(cart.chr_size() as u32);
+                // the bank registers, nothing at all for NROM
+                let mut mapper = Writer::default();
+                self.mapper.save_state(&mut mapper);
+                let mapper = mapper.finish();
+                w.u32(mapper.len() as u32);
+                w.bytes(&mapper);
+                w.bytes(&self.prg_ram);
+            });
+        }
+        w.finish()
+    }
+
+    // puts the machine back the way a state says. the same cart has to
+    // be loaded. nothing is changed unless the whole state is good
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), state::Error>
+    {
+        let state = State::parse(data)?;
+        let cpu = CPU::load_state(&mut state.chunk(b"CPU ")?)?;
+        let ppu = PPU::load_state(&mut state.chunk(b"PPU ")?)?;
+        let ram = state.chunk(b"RAM ")?.array()?;
+        let vram = state.chunk(b"VRAM")?.array()?;
 
-                _ => todo!()
-            };
+        let (mapper, prg_ram) = match (&self.cart, state.has(b"CART"))
+        {
+            (Some(cart), true) =>
+            {
+                let mut r = state.chunk(b"CART")?;
+                let (mapper, prg, chr) = (r.u16()?, r.u32()?, r.u32()?);
+                if (mapper, prg as usize, chr as usize)
+                    != (cart.mapper, cart.prg_size(), cart.chr_size())
+                {
+                    return Err(state::Error::WrongCart);
+                }
+                let len = r.u32()? as usize;
+                let mapper = self.mapper.load_state(&mut r.sub(len)?)?;
+                (mapper, r.array()?)
+            }
+            (None, false) => (self.mapper, [0; 0x2000]),
+            _ => return Err(state::Error::WrongCart),
         };
+
+        (self.cpu, self.ppu, self.ram, self.vram) = (cpu, ppu, ram, vram);
+        (self.mapper, self.prg_ram) = (mapper, prg_ram);
+        Ok(())
+    }
+
+    pub fn save_state_to(&self, path: impl AsRef<Path>) -> Result<(), state::Error>
+    {
+        Ok(fs::write(path, self.save_state())?)
+    }
+
+    pub fn load_state_from(&mut self, path: impl AsRef<Path>) -> Result<(), state::Error>
+    {
+        self.load_state(&fs::read(path)?)
+    }
+
+    pub fn reset(&mut self)
+    {
+        let mut bus = CpuBus::new(&mut self.ram, &mut self.prg_ram, &mut self.ppu,
+                                  &mut self.vram, self.cart.as_ref(), &mut self.mapper);
+        self.cpu.reset(&mut bus);
+    }
+
+    // do we need one more layer of abstraction here?
+    // inesrom -> cart -> nes instead of directly
+    pub fn load_cart(&mut self, cart: INesRom)
+    {
+        match cart.mapper
+        {
+            0..=1 =>
+            {
+                // none of the history or the decoded code is any good
+                // with another cart in, and the log has to start over
+                // at the new cart's size
+                if let Some(rewind) = &mut self.rewind
+                {
+                    rewind.clear();
+                }
+                if self.cdl.is_some()
+                {
+                    self.cdl = Some(CodeDataLogger::new(&cart));
+                }
+                if let Some(cache) = &mut self.cache
+                {
+                    cache.clear();
+                }
+                self.mapper = Mapper::new(cart.mapper);
+                self.cart = Some(cart);
+            }
+            _ => todo!(),
+        }
     }
 
 }
 
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::{ asm, harness };
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // a trace sink that's been closed on the other end
+    struct Closed;
+
+    impl Write for Closed
+    {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize>
+        {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_sink_failing_ends_the_trace()
+    {
+        let mut nes = NES::new();
+        nes.set_fault_policy(FaultPolicy::Ignore);
+        nes.trace_to(Box::new(Closed));
+        assert!(nes.step().is_ok());
+        assert_eq!(nes.trace_error().map(io::Error::kind), Some(io::ErrorKind::BrokenPipe));
+        assert!(nes.step().is_ok());
+
+        nes.trace_to(Box::new(io::sink()));
+        assert!(nes.step().is_ok());
+        assert!(nes.trace_error().is_none());
+    }
+
+    #[test]
+    fn poke_ram_and_prg_ram()
+    {
+        let mut nes = NES::new();
+        assert!(nes.poke(0x0801, 0x12));
+        assert!(nes.poke(0x7fff, 0x34));
+        let bus = nes.bus();
+        assert_eq!(bus.peek(0x0001), 0x12);
+        assert_eq!(bus.peek(0x7fff), 0x34);
+
+        assert!(!nes.poke(0x2000, 0x80));
+        assert!(!nes.poke(0x4014, 0x02));
+        assert!(!nes.poke(0x8000, 0x00));
+    }
+
+    // steps until pc gets to addr
+    fn run_to(nes: &mut NES, addr: u16)
+    {
+        for _ in 0..100_000
+        {
+            if nes.cpu().pc == addr
+            {
+                return;
+            }
+            nes.step().unwrap();
+        }
+        panic!("never got to ${:04X}", addr);
+    }
+
+    // MMC1 with 64K, the same address in two banks does two different
+    // things. the code in the fixed bank calls into both of them and
+    // ends at the address that comes back with the cart
+    fn banked() -> (INesRom, u16)
+    {
+        let mut cart = harness::blank_cart(1, 4, 0).unwrap();
+        for (bank, value) in [(0, 1), (1, 2)]
+        {
+            let code = asm::assemble(&format!(".org $8000\n lda #{}\n rts", value)).unwrap();
+            code.patch(cart.prg_bank_mut(bank), 0x8000).unwrap();
+        }
+        let fixed = asm::assemble("
+                .org $c000
+        reset:  ldx #$ff
+                txs
+                jsr $8000
+                sta $00
+                lda #1          ; bank 1 at $8000, a bit at a time
+                sta $e000
+                lsr a
+                sta $e000
+                sta $e000
+                sta $e000
+                sta $e000
+                jsr $8000
+                sta $01
+        done:   jmp done
+                .org $fffc
+                .word reset
+        ").unwrap();
+        fixed.patch(cart.prg_bank_mut(3), 0xc000).unwrap();
+        (cart, fixed.label("done").unwrap())
+    }
+
+    #[test]
+    fn decode_cache_follows_bank_switches()
+    {
+        let (cart, done) = banked();
+        let mut nes = NES::new();
+        nes.set_fault_policy(FaultPolicy::Error);
+        nes.load_cart(cart);
+        nes.use_decode_cache(true);
+        nes.reset();
+        run_to(&mut nes, done);
+        for _ in 0..10
+        {
+            nes.step().unwrap();
+        }
+
+        let bus = nes.bus();
+        assert_eq!((bus.peek(0x00), bus.peek(0x01)), (1, 2));
+        assert_eq!(bus.bank_switches(), 1);
+        let cache = nes.decode_cache().unwrap();
+        // every instruction in the fixed bank once, and both banks'
+        // lda and rts. only jmp done comes out of the cache
+        assert_eq!(cache.misses, 14 + 2 + 2);
+        assert_eq!(cache.hits, 9);
+    }
+
+    // the backdrop colour set to $21, and that's the whole picture
+    const BACKDROP: &str = "
+            .org $8000
+    reset:  lda #$3f
+            sta $2006
+            lda #$00
+            sta $2006
+            lda #$21
+            sta $2007
+            lda #$00        ; off the palette, or it shows $3F01
+            sta $2006
+            sta $2006
+    loop:   jmp loop
+            .org $fffc
+            .word reset
+    ";
+
+    fn run_frames(nes: &mut NES, frames: u64)
+    {
+        let end = nes.frame() + frames;
+        while nes.frame() < end
+        {
+            nes.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn step_back_keeps_the_picture()
+    {
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(BACKDROP).unwrap());
+        nes.enable_rewind(1, 1 << 20);
+        nes.reset();
+        run_frames(&mut nes, 5);
+        assert!(nes.framebuffer().iter().all(|&index| index == 0x21));
+
+        // the snapshot for frame 4 is loaded as it is, with nothing
+        // run after it
+        assert!(nes.step_back().unwrap());
+        assert_eq!(nes.frame(), 4);
+        assert!(nes.framebuffer().iter().all(|&index| index == 0x21));
+    }
+
+    // a trace sink the test can still look at
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn step_back_replays_unwatched()
+    {
+        // a write to rom every time around the loop, for some faults
+        let source = BACKDROP.replace("loop:   jmp loop", "loop:   sta $8000\n jmp loop");
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(&source).unwrap());
+        // every other frame, so going back has some replaying to do
+        nes.enable_rewind(2, 1 << 20);
+        let trace = Shared::default();
+        nes.trace_to(Box::new(trace.clone()));
+        nes.profile(Profiler::new());
+        nes.reset();
+        run_frames(&mut nes, 6);
+
+        let lines = trace.0.borrow().iter().filter(|&&c| c == b'\n').count();
+        let mut report = Vec::new();
+        nes.profile_report(&mut report, 1).unwrap();
+        let faults = nes.faults().count();
+        assert!(faults > 0);
+
+        assert!(nes.step_back().unwrap());
+        assert_eq!(nes.frame(), 5);
+        assert_eq!(trace.0.borrow().iter().filter(|&&c| c == b'\n').count(), lines);
+        let mut again = Vec::new();
+        nes.profile_report(&mut again, 1).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), String::from_utf8(report).unwrap());
+        assert_eq!(nes.faults().count(), faults);
+    }
+
+    #[test]
+    fn save_state_round_trip()
+    {
+        let (cart, done) = banked();
+        let mut nes = NES::new();
+        nes.load_cart(cart);
+        nes.reset();
+        run_to(&mut nes, done);
+        assert!(nes.poke(0x6123, 0x45));
+        run_frames(&mut nes, 2);
+        let state = nes.save_state();
+        for _ in 0..1000
+        {
+            nes.step().unwrap();
+        }
+        let later = nes.save_state();
+
+        // on another console with the same cart in, it goes on just
+        // the same from there
+        let mut other = NES::new();
+        other.load_cart(banked().0);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+        assert_eq!(other.bus().peek(0x8001), 2, "bank 1 is still in");
+        assert_eq!(other.bus().peek(0x6123), 0x45);
+        for _ in 0..1000
+        {
+            other.step().unwrap();
+        }
+        assert_eq!(other.save_state(), later);
+    }
+
+    #[test]
+    fn bad_states()
+    {
+        let (cart, _) = banked();
+        let mut nes = NES::new();
+        nes.load_cart(cart);
+        let state = nes.save_state();
+
+        let mut other = NES::new();
+        assert!(matches!(other.load_state(&state), Err(state::Error::WrongCart)));
+        other.load_cart(harness::nrom(BACKDROP).unwrap());
+        assert!(matches!(other.load_state(&state), Err(state::Error::WrongCart)));
+
+        assert!(matches!(nes.load_state(&state[..state.len() - 1]), Err(state::Error::Truncated)));
+        assert!(matches!(nes.load_state(b"SNTX\x01\x00"), Err(state::Error::NotASaveState)));
+        let mut newer = state.clone();
+        newer[4] = state::VERSION as u8 + 1;
+        assert!(matches!(nes.load_state(&newer), Err(state::Error::TooNew(_))));
+        assert!(nes.load_state(&state).is_ok());
+    }
+
+    // a state like an older version wrote them, around the ppu chunk
+    // that version had. the other chunks haven't changed
+    fn old_state(version: u16, ppu: impl FnOnce(&mut Writer)) -> Vec<u8>
+    {
+        let mut w = Writer::default();
+        w.chunk(b"CPU ", |w|
+        {
+            w.bytes(&[1, 2, 3, 0xfd, 0x24]);
+            w.u16(0x8123);
+            w.u64(1234);
+            w.u8(0);
+            w.bytes(&[0; 9]);
+        });
+        w.chunk(b"PPU ", ppu);
+        w.chunk(b"RAM ", |w| w.bytes(&[0x11; 0x800]));
+        w.chunk(b"VRAM", |w| w.bytes(&[0x22; 0x1000]));
+        w.chunk(b"CART", |w|
+        {
+            w.u16(0);
+            w.u32(0x8000);
+            w.u32(0x2000);
+            w.u32(0);
+            w.bytes(&[0x33; 0x2000]);
+        });
+        [b"SNTW".as_slice(), &version.to_le_bytes(), &w.finish()].concat()
+    }
+
+    #[test]
+    fn old_states_still_load()
+    {
+        let regs = |w: &mut Writer| w.bytes(&[0x81, 0x1e, 0x80, 0x10]);
+        // w, the read buffer, the latch, OAM and the palette, from 2 on
+        let internal = |w: &mut Writer|
+        {
+            w.bool(true);
+            w.bytes(&[0x44, 0x55]);
+            w.bytes(&[0x66; 0x100]);
+            w.bytes(&[0x0f; 0x20]);
+        };
+        let v3 = |w: &mut Writer|
+        {
+            regs(w);
+            w.u16(0x2345);
+            w.u16(0x24e5);
+            w.u8(5);
+            internal(w);
+        };
+        let position = |w: &mut Writer|
+        {
+            w.u16(100);
+            w.u16(200);
+            w.u64(42);
+        };
+        let pipeline = |w: &mut Writer|
+        {
+            w.bytes(&[1, 2, 3, 4]);
+            w.bytes(&[0; 8]);
+        };
+        let sprites = |w: &mut Writer|
+        {
+            w.bytes(&[0xff; 0x20]);
+            w.u8(0);
+            w.bool(false);
+            w.bytes(&[0; 32]);
+        };
+
+        let states = [
+            old_state(1, |w| { regs(w); w.bytes(&[0; 4]); position(w) }),
+            // a plain address, and x and y scroll for t and fine x
+            old_state(2, |w| { regs(w); w.u16(0x2345); w.bytes(&[0x2d, 0x3a]); internal(w); position(w) }),
+            old_state(3, |w| { v3(w); position(w) }),
+            old_state(4, |w| { v3(w); position(w); pipeline(w) }),
+            old_state(5, |w| { v3(w); position(w); pipeline(w); sprites(w) }),
+        ];
+        for (state, version) in states.iter().zip(1..)
+        {
+            let mut nes = NES::new();
+            nes.load_cart(harness::nrom(BACKDROP).unwrap());
+            nes.load_state(state).unwrap_or_else(|e| panic!("version {}: {:?}", version, e));
+
+            let cpu = nes.cpu();
+            assert_eq!((cpu.a, cpu.x, cpu.y, cpu.pc, cpu.cycles), (1, 2, 3, 0x8123, 1234));
+            let ppu = nes.ppu();
+            assert_eq!((ppu.ctrl(), ppu.mask(), ppu.status()), (0x81, 0x1e, 0x80));
+            assert_eq!((ppu.position(), ppu.frame()), ((100, 200), 42));
+            assert!(nes.framebuffer().iter().all(|&index| index == 0));
+            if version >= 2
+            {
+                assert_eq!((ppu.vram_addr(), ppu.temp_addr(), ppu.fine_x()), (0x2345, 0x24e5, 5),
+                           "version {}", version);
+                assert!(ppu.write_toggle());
+                assert_eq!(ppu.oam(), &[0x66; 0x100]);
+                assert_eq!(ppu.palette(), &[0x0f; 0x20]);
+            }
+            let bus = nes.bus();
+            assert_eq!((bus.peek(0x0000), bus.peek(0x6000)), (0x11, 0x33));
+
+            // and it saves as the current version, without losing any of it
+            let again = nes.save_state();
+            assert_eq!(&again[4..6], state::VERSION.to_le_bytes());
+            let mut other = NES::new();
+            other.load_cart(harness::nrom(BACKDROP).unwrap());
+            other.load_state(&again).unwrap();
+            assert_eq!(other.save_state(), again);
+        }
+    }
+}
@@ -1,37 +1,684 @@
+// The ppu as the cpu sees it through $2000-$2007. Like the cpu it
+// doesn't own its memory, the pattern tables and nametables are behind
+// a bus it gets lent for every register access (see PpuBus). Only the
+// palette and OAM are inside the chip.
+
+use crate::nes::bus::Bus;
+use crate::nes::state::{ self, Writer, Reader };
+
 
 // registers
-const PPU_CRTL: usize = 0;
-const PPU_MASK: usize = 1;
-const PPU_STATUS: usize = 2;
-const OAM_ADDR: usize = 3;
-const OAM_DATA: usize = 4;
-const PPU_SCROLL: usize = 5;
-const PPU_ADDR: usize = 6;
-const PPU_DATA: usize = 7;
+pub const PPU_CRTL: usize = 0;
+pub const PPU_MASK: usize = 1;
+pub const PPU_STATUS: usize = 2;
+pub const OAM_ADDR: usize = 3;
+pub const OAM_DATA: usize = 4;
+pub const PPU_SCROLL: usize = 5;
+pub const PPU_ADDR: usize = 6;
+pub const PPU_DATA: usize = 7;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+const DOTS_PER_LINE: u16 = 341;
+const LINES_PER_FRAME: u16 = 262;
+const VBLANK_LINE: u16 = 241;
+const PRE_RENDER_LINE: u16 = 261;
+
+// PPUCTRL
+const CTRL_NAMETABLE: u8 = 0x03;
+const CTRL_INCREMENT: u8 = 0x04; // PPUDATA goes down a row instead of right
+const CTRL_SPRITE_TABLE: u8 = 0x08; // 8x8 sprite patterns come from $1000
+const CTRL_BG_TABLE: u8 = 0x10;  // background patterns come from $1000
+const CTRL_TALL_SPRITES: u8 = 0x20; // 8x16
+const CTRL_NMI: u8 = 0x80;
+
+// PPUSTATUS, the low 5 bits aren't driven and read back as open bus
+const STATUS_OVERFLOW: u8 = 0x20;
+const STATUS_SPRITE0: u8 = 0x40;
+const STATUS_VBLANK: u8 = 0x80;
+
+// PPUMASK
+const MASK_GREYSCALE: u8 = 0x01;
+const MASK_BG_LEFT: u8 = 0x02; // background in the leftmost 8 pixels too
+const MASK_SPRITES_LEFT: u8 = 0x04;
+const MASK_BACKGROUND: u8 = 0x08;
+const MASK_SPRITES: u8 = 0x10;
+
+// sprite attributes
+const ATTR_PALETTE: u8 = 0x03;
+const ATTR_BEHIND: u8 = 0x20; // only shows where the background is transparent
+const ATTR_FLIP_X: u8 = 0x40;
+const ATTR_FLIP_Y: u8 = 0x80;
+
+const PALETTE: u16 = 0x3f00;
+
+// the parts of v and t, see the scrolling page on the nesdev wiki.
+// yyy NN YYYYY XXXXX, fine y, nametable, coarse y and coarse x
+const COARSE_X: u16 = 0x001f;
+const COARSE_Y: u16 = 0x03e0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
+const HORIZONTAL: u16 = NAMETABLE_X | COARSE_X;
+const VERTICAL: u16 = FINE_Y | NAMETABLE_Y | COARSE_Y;
 
 #[derive(Debug)]
 enum Version
 {
     NTSC,
+    #[allow(dead_code)]
     PAL,
 }
 
 #[derive(Debug)]
 pub struct PPU
 {
-    regs: [u8; 8],
-    version: Version
+    version: Version,
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+
+    // the scroll and address registers, loopy's names for them. v is
+    // where the ppu is fetching from or where PPUDATA goes, t is what
+    // PPUSCROLL and PPUADDR write into before it's copied over
+    v: u16,
+    t: u16,
+    x: u8,   // fine x scroll, 3 bits
+    w: bool, // which write of PPUSCROLL or PPUADDR is next, they share it
+    buffer: u8,       // what the last PPUDATA read fetched, the next one gets it
+    latch: u8,        // the data bus, reads of write only registers see it
+
+    oam: [u8; 0x100],
+    palette: [u8; 0x20],
+
+    // the background pipeline. a tile is fetched over 8 dots and then
+    // goes into the low half of the shift registers, the high half
+    // being the one that's drawn
+    next_tile: u8,
+    next_attr: u8,
+    next_lo: u8,
+    next_hi: u8,
+    bg_lo: u16,
+    bg_hi: u16,
+    attr_lo: u16,
+    attr_hi: u16,
+
+    // the up to 8 sprites on the next line, found by evaluate, and
+    // their patterns once they're fetched
+    secondary: [u8; 0x20],
+    sprite_count: usize,
+    sprite0: bool, // whether sprite 0 is one of them, it's always the first
+    sprites: [Sprite; 8],
+
+    // palette indices, the one being drawn and the last finished one
+    pixels: Box<[u8; WIDTH * HEIGHT]>,
+    picture: Box<[u8; WIDTH * HEIGHT]>,
+
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    // PPUSTATUS was read the dot before vblank starts, which keeps it
+    // from starting at all. that dot is run in the same cpu cycle as
+    // the read, so this is never set between steps to need saving
+    no_vblank: bool,
+}
+
+
+// a sprite on the line being drawn
+#[derive(Debug, Default, Clone, Copy)]
+struct Sprite
+{
+    x: u8,
+    attr: u8,
+    lo: u8, // pattern bits, already flipped if it's flipped
+    hi: u8,
 }
 
+impl Default for PPU
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
 
 impl PPU
 {
     pub fn new() -> PPU
     {
         PPU {
-            regs: [0u8; 8],
-            version: Version::NTSC
+            version: Version::NTSC,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            buffer: 0,
+            latch: 0,
+            oam: [0; 0x100],
+            palette: [0; 0x20],
+            next_tile: 0,
+            next_attr: 0,
+            next_lo: 0,
+            next_hi: 0,
+            bg_lo: 0,
+            bg_hi: 0,
+            attr_lo: 0,
+            attr_hi: 0,
+            secondary: [0xff; 0x20],
+            sprite_count: 0,
+            sprite0: false,
+            sprites: [Sprite::default(); 8],
+            pixels: Box::new([0; WIDTH * HEIGHT]),
+            picture: Box::new([0; WIDTH * HEIGHT]),
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            no_vblank: false,
+        }
+    }
+
+    // advances one dot, three of these happen every cpu cycle. bus
+    // is where the tiles get fetched from
+    pub fn tick(&mut self, bus: &mut dyn Bus)
+    {
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE || (self.dot == DOTS_PER_LINE - 1 && self.skips_dot())
+        {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == LINES_PER_FRAME
+            {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+
+        match (self.scanline, self.dot)
+        {
+            (VBLANK_LINE, 1) =>
+            {
+                if !self.no_vblank
+                {
+                    self.status |= STATUS_VBLANK;
+                }
+                self.no_vblank = false;
+                std::mem::swap(&mut self.pixels, &mut self.picture);
+            }
+            (PRE_RENDER_LINE, 1) =>
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE0 | STATUS_OVERFLOW),
+            _ => (),
+        }
+
+        let visible = (self.scanline as usize) < HEIGHT;
+        if visible || self.scanline == PRE_RENDER_LINE
+        {
+            if self.rendering()
+            {
+                self.fetch(bus);
+                self.sprites(bus);
+                self.scroll();
+            }
+            if visible && (1..=WIDTH as u16).contains(&self.dot)
+            {
+                self.output();
+            }
+        }
+    }
+
+    // the background fetches, every tile takes a nametable, an
+    // attribute and two pattern bytes. the first two tiles of a line
+    // are fetched at the end of the one before
+    fn fetch(&mut self, bus: &mut dyn Bus)
+    {
+        let dot = self.dot;
+        if !(2..=257).contains(&dot) && !(321..=337).contains(&dot)
+        {
+            return;
+        }
+
+        self.bg_lo <<= 1;
+        self.bg_hi <<= 1;
+        self.attr_lo <<= 1;
+        self.attr_hi <<= 1;
+
+        let v = self.v;
+        let pattern = ((self.ctrl & CTRL_BG_TABLE) as u16) << 8
+            | (self.next_tile as u16) << 4 | (v >> 12) & 7;
+        match (dot - 1) % 8
+        {
+            0 =>
+            {
+                self.bg_lo |= self.next_lo as u16;
+                self.bg_hi |= self.next_hi as u16;
+                // one palette for the whole tile
+                self.attr_lo |= if self.next_attr & 1 != 0 { 0xff } else { 0 };
+                self.attr_hi |= if self.next_attr & 2 != 0 { 0xff } else { 0 };
+                self.next_tile = bus.read(0x2000 | v & 0x0fff);
+            }
+            2 =>
+            {
+                // a byte covers 4x4 tiles, two bits for each 2x2 of them
+                let attr = bus.read(0x23c0 | v & 0x0c00 | (v >> 4) & 0x38 | (v >> 2) & 0x07);
+                let shift = (v >> 4) & 4 | v & 2;
+                self.next_attr = (attr >> shift) & 3;
+            }
+            4 => self.next_lo = bus.read(pattern),
+            6 => self.next_hi = bus.read(pattern + 8),
+            _ => (),
+        }
+    }
+
+    // sprites for the next line are looked for once this one is drawn,
+    // and then their patterns are fetched, 8 dots for each of the 8.
+    // OAMADDR is held at 0 all the while
+    fn sprites(&mut self, bus: &mut dyn Bus)
+    {
+        let dot = self.dot;
+        if !(257..=320).contains(&dot)
+        {
+            return;
+        }
+        self.oam_addr = 0;
+        if dot == 257
+        {
+            if self.scanline == PRE_RENDER_LINE
+            {
+                // nothing is ever drawn on the first line
+                self.secondary = [0xff; 0x20];
+                self.sprite_count = 0;
+                self.sprite0 = false;
+            }
+            else
+            {
+                self.evaluate();
+            }
+        }
+
+        // empty slots fetch tile $FF on the real thing and throw it
+        // away. nothing here watches the ppu's address lines, so that's
+        // left out, and it isn't logged as drawn
+        let slot = (dot - 257) as usize / 8;
+        let phase = (dot - 257) % 8;
+        if phase != 4 && phase != 6 || slot >= self.sprite_count
+        {
+            return;
+        }
+        let entry = &self.secondary[slot * 4..slot * 4 + 4];
+        let (y, tile, attr, x) = (entry[0], entry[1], entry[2], entry[3]);
+        let mut row = self.scanline.wrapping_sub(y as u16) & 0x0f;
+        let addr = if self.ctrl & CTRL_TALL_SPRITES != 0
+        {
+            if attr & ATTR_FLIP_Y != 0
+            {
+                row = 15 - row;
+            }
+            // the bottom bit of the tile picks the table for these
+            let table = (tile & 1) as u16;
+            let tile = (tile & 0xfe) as u16 + (row >> 3);
+            table << 12 | tile << 4 | row & 7
+        }
+        else
+        {
+            if attr & ATTR_FLIP_Y != 0
+            {
+                row = 7 - (row & 7);
+            }
+            ((self.ctrl & CTRL_SPRITE_TABLE) as u16) << 9 | (tile as u16) << 4 | row & 7
+        };
+
+        let mut data = bus.read(if phase == 4 { addr } else { addr + 8 });
+        if attr & ATTR_FLIP_X != 0
+        {
+            data = data.reverse_bits();
+        }
+        let sprite = &mut self.sprites[slot];
+        (sprite.x, sprite.attr) = (x, attr);
+        match phase
+        {
+            4 => sprite.lo = data,
+            _ => sprite.hi = data,
+        }
+    }
+
+    // copies the first 8 sprites that are on the next line into
+    // secondary OAM. looking for a 9th one for the overflow flag goes
+    // wrong like on the real thing: after every miss it looks at the
+    // next byte of the following sprite instead of its y
+    fn evaluate(&mut self)
+    {
+        let height = if self.ctrl & CTRL_TALL_SPRITES != 0 { 16 } else { 8 };
+        let line = self.scanline;
+        let on_line = |y: u8| line.wrapping_sub(y as u16) < height;
+
+        self.secondary = [0xff; 0x20];
+        self.sprite_count = 0;
+        self.sprite0 = false;
+        let mut m = 0;
+        for n in 0..64
+        {
+            if self.sprite_count < 8
+            {
+                if on_line(self.oam[n * 4])
+                {
+                    let slot = self.sprite_count * 4;
+                    self.secondary[slot..slot + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                    self.sprite_count += 1;
+                    self.sprite0 |= n == 0;
+                }
+            }
+            else if on_line(self.oam[n * 4 + m])
+            {
+                self.status |= STATUS_OVERFLOW;
+                break;
+            }
+            else
+            {
+                m = (m + 1) % 4;
+            }
+        }
+    }
+
+    // the first sprite that isn't transparent at x, as (slot, pixel,
+    // attributes)
+    fn sprite_pixel(&self, x: usize) -> Option<(usize, usize, u8)>
+    {
+        self.sprites[..self.sprite_count].iter().enumerate().find_map(|(slot, sprite)|
+        {
+            let column = x.wrapping_sub(sprite.x as usize);
+            if column >= 8
+            {
+                return None;
+            }
+            let bit = 0x80 >> column;
+            let pixel = (sprite.hi & bit != 0) as usize * 2 + (sprite.lo & bit != 0) as usize;
+            (pixel != 0).then_some((slot, pixel, sprite.attr))
+        })
+    }
+
+    // puts the pixel for the current dot into the frame
+    fn output(&mut self)
+    {
+        let x = self.dot as usize - 1;
+        let mut colour = 0;
+        let mut bg = 0;
+        if self.mask & MASK_BACKGROUND != 0 && (x >= 8 || self.mask & MASK_BG_LEFT != 0)
+        {
+            let bit = 0x8000 >> self.x;
+            bg = (self.bg_hi & bit != 0) as usize * 2 + (self.bg_lo & bit != 0) as usize;
+            let palette = (self.attr_hi & bit != 0) as usize * 2 + (self.attr_lo & bit != 0) as usize;
+            if bg != 0
+            {
+                colour = palette * 4 + bg;
+            }
         }
+
+        let sprite = if self.mask & MASK_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITES_LEFT != 0)
+            { self.sprite_pixel(x) }
+        else
+            { None };
+        if let Some((slot, pixel, attr)) = sprite
+        {
+            // sprite 0 hits whatever its priority, just never on the
+            // last pixel of a line
+            if slot == 0 && self.sprite0 && bg != 0 && x != WIDTH - 1
+            {
+                self.status |= STATUS_SPRITE0;
+            }
+            if bg == 0 || attr & ATTR_BEHIND == 0
+            {
+                colour = 0x10 + (attr & ATTR_PALETTE) as usize * 4 + pixel;
+            }
+        }
+
+        // with rendering off the backdrop is shown, unless v points
+        // into the palette, then it's that entry
+        let addr = self.v & 0x3fff;
+        let mut index = if !self.rendering() && addr >= PALETTE
+            { self.palette[palette_index(addr)] }
+        else
+            { self.palette[colour] };
+        if self.mask & MASK_GREYSCALE != 0
+        {
+            index &= 0x30;
+        }
+        self.pixels[self.scanline as usize * WIDTH + x] = index;
+    }
+
+    // the last finished frame, a row at a time from the top. every
+    // pixel is an index into the nes' 64 colours
+    pub fn picture(&self) -> &[u8; WIDTH * HEIGHT]
+    {
+        &self.picture
+    }
+
+    // what rendering does to v on a visible or the pre-render line. it
+    // moves along a tile every 8 dots as tiles get fetched, down a line
+    // at the end of the visible part, and then starts over from t
+    fn scroll(&mut self)
+    {
+        let dot = self.dot;
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot)
+        {
+            if dot.is_multiple_of(8)
+            {
+                self.increment_x();
+            }
+            if dot == 256
+            {
+                self.increment_y();
+            }
+        }
+        else if dot == 257
+        {
+            self.v = self.v & !HORIZONTAL | self.t & HORIZONTAL;
+        }
+        else if self.scanline == PRE_RENDER_LINE && (280..=304).contains(&dot)
+        {
+            self.v = self.v & !VERTICAL | self.t & VERTICAL;
+        }
+    }
+
+    // over to the next nametable at the right edge
+    fn increment_x(&mut self)
+    {
+        if self.v & COARSE_X == COARSE_X
+        {
+            self.v = (self.v & !COARSE_X) ^ NAMETABLE_X;
+        }
+        else
+        {
+            self.v += 1;
+        }
+    }
+
+    // fine y first, then coarse y. row 29 is the last one of a
+    // nametable, 30 and 31 are the attributes and just wrap around
+    fn increment_y(&mut self)
+    {
+        if self.v & FINE_Y != FINE_Y
+        {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !FINE_Y;
+        let y = match (self.v & COARSE_Y) >> 5
+        {
+            29 =>
+            {
+                self.v ^= NAMETABLE_Y;
+                0
+            }
+            31 => 0,
+            y => y + 1,
+        };
+        self.v = self.v & !COARSE_Y | y << 5;
+    }
+
+    fn rendering(&self) -> bool
+    {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    // odd frames are a dot shorter while rendering, the last dot of
+    // the pre-render line is skipped and the next frame starts early
+    fn skips_dot(&self) -> bool
+    {
+        self.scanline == PRE_RENDER_LINE && self.frame & 1 == 1 && self.rendering()
+    }
+
+    // the NMI line going to the cpu. the cpu only looks for it going
+    // up, so setting CTRL_NMI during vblank makes one late, and a
+    // PPUSTATUS read that clears vblank as it starts makes none
+    pub fn nmi(&self) -> bool
+    {
+        self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI != 0
+    }
+
+    // a cpu read of $2000 + reg, bus is where PPUDATA goes
+    pub fn read(&mut self, reg: usize, bus: &mut dyn Bus) -> u8
+    {
+        match reg
+        {
+            PPU_STATUS =>
+            {
+                if (self.scanline, self.dot) == (VBLANK_LINE, 0)
+                {
+                    // it reads as clear, and then never gets set
+                    self.no_vblank = true;
+                }
+                self.latch = self.status & 0xe0 | self.latch & 0x1f;
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+            }
+            OAM_DATA => self.latch = self.oam[self.oam_addr as usize],
+            PPU_DATA =>
+            {
+                // the palette is answered right away, the buffer gets
+                // the nametable byte that's underneath it
+                let addr = self.v & 0x3fff;
+                self.latch = if addr >= PALETTE
+                    { self.palette[palette_index(addr)] | self.latch & 0xc0 }
+                else
+                    { self.buffer };
+                self.buffer = bus.read(addr);
+                self.increment();
+            }
+            _ => (),
+        }
+        self.latch
+    }
+
+    // what read would return, without anything it does to the ppu
+    pub fn peek(&self, reg: usize) -> u8
+    {
+        match reg
+        {
+            PPU_STATUS => self.status & 0xe0 | self.latch & 0x1f,
+            OAM_DATA => self.oam[self.oam_addr as usize],
+            PPU_DATA if self.v & 0x3fff >= PALETTE =>
+                self.palette[palette_index(self.v)] | self.latch & 0xc0,
+            PPU_DATA => self.buffer,
+            _ => self.latch,
+        }
+    }
+
+    // a cpu write of $2000 + reg
+    pub fn write(&mut self, reg: usize, data: u8, bus: &mut dyn Bus)
+    {
+        self.latch = data;
+        match reg
+        {
+            PPU_CRTL =>
+            {
+                self.ctrl = data;
+                self.t = self.t & !(NAMETABLE_X | NAMETABLE_Y)
+                    | ((data & CTRL_NAMETABLE) as u16) << 10;
+            }
+            PPU_MASK => self.mask = data,
+            OAM_ADDR => self.oam_addr = data,
+            OAM_DATA =>
+            {
+                // bits 2-4 of the attributes aren't there at all
+                let data = if self.oam_addr & 3 == 2 { data & 0xe3 } else { data };
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            PPU_SCROLL =>
+            {
+                let data = data as u16;
+                if !self.w
+                {
+                    self.t = self.t & !COARSE_X | data >> 3;
+                    self.x = data as u8 & 7;
+                }
+                else
+                {
+                    self.t = self.t & !(FINE_Y | COARSE_Y) | (data & 7) << 12 | (data >> 3) << 5;
+                }
+                self.w = !self.w;
+            }
+            PPU_ADDR =>
+            {
+                // the first write also clears the top bit of fine y
+                if !self.w
+                {
+                    self.t = (data as u16 & 0x3f) << 8 | self.t & 0xff;
+                }
+                else
+                {
+                    self.t = self.t & 0xff00 | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            PPU_DATA =>
+            {
+                let addr = self.v & 0x3fff;
+                if addr >= PALETTE
+                {
+                    self.palette[palette_index(addr)] = data & 0x3f;
+                }
+                else
+                {
+                    bus.write(addr, data);
+                }
+                self.increment();
+            }
+            _ => (), // PPUSTATUS is read only
+        }
+    }
+
+    // PPUDATA moves on by one or by a row of 32 after each access.
+    // while rendering it bumps v both ways instead, like a tile fetch
+    fn increment(&mut self)
+    {
+        if self.rendering() && (self.scanline < 240 || self.scanline == PRE_RENDER_LINE)
+        {
+            self.increment_x();
+            self.increment_y();
+            return;
+        }
+        let step = if self.ctrl & CTRL_INCREMENT != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7fff;
+    }
+
+    // (scanline, dot) the ppu is currently at
+    pub fn position(&self) -> (u16, u16)
+    {
+        (self.scanline, self.dot)
+    }
+
+    // how many frames have been finished since power on
+    pub fn frame(&self) -> u64
+    {
+        self.frame
     }
 
     // puts the ppu in the start of power up state
@@ -51,8 +698,663 @@ impl PPU
         }
     }
 
-    pub fn regs_slice(&mut self) -> &mut [u8]
+    pub fn save_state(&self, w: &mut Writer)
+    {
+        w.u8(self.ctrl);
+        w.u8(self.mask);
+        w.u8(self.status);
+        w.u8(self.oam_addr);
+        w.u16(self.v);
+        w.u16(self.t);
+        w.u8(self.x);
+        w.bool(self.w);
+        w.u8(self.buffer);
+        w.u8(self.latch);
+        w.bytes(&self.oam);
+        w.bytes(&self.palette);
+        w.u16(self.scanline);
+        w.u16(self.dot);
+        w.u64(self.frame);
+        w.bytes(&[self.next_tile, self.next_attr, self.next_lo, self.next_hi]);
+        for shifter in [self.bg_lo, self.bg_hi, self.attr_lo, self.attr_hi]
+        {
+            w.u16(shifter);
+        }
+        w.bytes(&self.secondary);
+        w.u8(self.sprite_count as u8);
+        w.bool(self.sprite0);
+        for sprite in &self.sprites
+        {
+            w.bytes(&[sprite.x, sprite.attr, sprite.lo, sprite.hi]);
+        }
+        w.bytes(self.picture.as_slice());
+    }
+
+    pub fn load_state(r: &mut Reader) -> Result<PPU, state::Error>
+    {
+        let mut ppu = PPU::new();
+        if r.version() < 2
+        {
+            // just the eight bytes the cpu had written, or seen
+            let regs: [u8; 8] = r.array()?;
+            (ppu.ctrl, ppu.mask, ppu.status, ppu.oam_addr) = (regs[0], regs[1], regs[2], regs[3]);
+        }
+        else
+        {
+            ppu.ctrl = r.u8()?;
+            ppu.mask = r.u8()?;
+            ppu.status = r.u8()?;
+            ppu.oam_addr = r.u8()?;
+            if r.version() < 3
+            {
+                // a plain address and the two scroll writes
+                let addr = r.u16()?;
+                let (x, y) = (r.u8()? as u16, r.u8()? as u16);
+                ppu.v = addr & 0x3fff;
+                ppu.t = ((ppu.ctrl & CTRL_NAMETABLE) as u16) << 10
+                    | (y & 7) << 12 | (y >> 3) << 5 | x >> 3;
+                ppu.x = x as u8 & 7;
+            }
+            else
+            {
+                ppu.v = r.u16()? & 0x7fff;
+                ppu.t = r.u16()? & 0x7fff;
+                ppu.x = r.u8()? & 7;
+            }
+            ppu.w = r.bool()?;
+            ppu.buffer = r.u8()?;
+            ppu.latch = r.u8()?;
+            ppu.oam = r.array()?;
+            ppu.palette = r.array()?;
+        }
+        ppu.scanline = r.u16()?;
+        ppu.dot = r.u16()?;
+        ppu.frame = r.u64()?;
+        if r.version() >= 4
+        {
+            // older ones pick up with an empty pipeline, that's a few
+            // wrong pixels at most
+            [ppu.next_tile, ppu.next_attr, ppu.next_lo, ppu.next_hi] = r.array()?;
+            ppu.next_attr &= 3;
+            [ppu.bg_lo, ppu.bg_hi, ppu.attr_lo, ppu.attr_hi] = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+        }
+        if r.version() >= 5
+        {
+            ppu.secondary = r.array()?;
+            ppu.sprite_count = r.u8()? as usize;
+            ppu.sprite0 = r.bool()?;
+            for sprite in &mut ppu.sprites
+            {
+                let [x, attr, lo, hi] = r.array()?;
+                *sprite = Sprite { x, attr, lo, hi };
+            }
+            if ppu.sprite_count > 8
+            {
+                return Err(state::Error::BadValue("sprite count"));
+            }
+        }
+        if r.version() >= 6
+        {
+            // older ones show black until the next vblank
+            ppu.picture.copy_from_slice(r.bytes(WIDTH * HEIGHT)?);
+        }
+        if ppu.scanline >= LINES_PER_FRAME || ppu.dot >= DOTS_PER_LINE
+        {
+            return Err(state::Error::BadValue("ppu position"));
+        }
+        Ok(ppu)
+    }
+
+    pub fn ctrl(&self) -> u8
+    {
+        self.ctrl
+    }
+
+    pub fn mask(&self) -> u8
+    {
+        self.mask
+    }
+
+    pub fn status(&self) -> u8
+    {
+        self.status
+    }
+
+    // v, where the next PPUDATA access goes, or the next tile comes from
+    pub fn vram_addr(&self) -> u16
+    {
+        self.v
+    }
+
+    // t, what v gets reloaded from
+    pub fn temp_addr(&self) -> u16
+    {
+        self.t
+    }
+
+    pub fn fine_x(&self) -> u8
     {
-        &mut self.regs
+        self.x
+    }
+
+    pub fn write_toggle(&self) -> bool
+    {
+        self.w
+    }
+
+    pub fn oam(&self) -> &[u8; 0x100]
+    {
+        &self.oam
+    }
+
+    pub fn palette(&self) -> &[u8; 0x20]
+    {
+        &self.palette
+    }
+}
+
+// the backdrop entries of the sprite palettes ($3F10, $3F14, ...) are
+// the same bytes as the ones of the background palettes
+fn palette_index(addr: u16) -> usize
+{
+    let i = addr as usize & 0x1f;
+    if i & 0x13 == 0x10 { i & 0x0f } else { i }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::harness::FlatRam;
+
+    // a ppu and 64K of flat memory for it, with tile 0 all colour 3,
+    // tile 1 all colour 1 and tile 2 empty. the nametables are all
+    // tile 0
+    fn setup() -> (PPU, FlatRam)
+    {
+        let mut bus = FlatRam::new();
+        bus.load(0x0000, &[0xff; 0x10]);
+        bus.load(0x0010, &[0xff; 0x08]);
+        let mut ppu = PPU::new();
+        ppu.write(PPU_ADDR, 0x3f, &mut bus);
+        ppu.write(PPU_ADDR, 0x00, &mut bus);
+        // every entry is its own index, except the ones that are all
+        // the backdrop, $3F10 and the like write over $3F00
+        for i in 0..0x20
+        {
+            ppu.write(PPU_DATA, if i % 4 == 0 { 0 } else { i }, &mut bus);
+        }
+        ppu.write(PPU_ADDR, 0x00, &mut bus);
+        ppu.write(PPU_ADDR, 0x00, &mut bus);
+        (ppu, bus)
+    }
+
+    // y, tile, attributes, x for each sprite, the rest are off screen
+    fn set_oam(ppu: &mut PPU, bus: &mut FlatRam, sprites: &[[u8; 4]])
+    {
+        ppu.write(OAM_ADDR, 0, bus);
+        for i in 0..64
+        {
+            for byte in sprites.get(i).copied().unwrap_or([0xf0; 4])
+            {
+                ppu.write(OAM_DATA, byte, bus);
+            }
+        }
+    }
+
+    fn run_to(ppu: &mut PPU, bus: &mut FlatRam, position: (u16, u16))
+    {
+        let mut dots = 0;
+        while ppu.position() != position
+        {
+            ppu.tick(bus);
+            dots += 1;
+            assert!(dots < 100_000, "never got to {:?}", position);
+        }
+    }
+
+    // renders a whole frame from the top and returns it
+    fn frame(ppu: &mut PPU, bus: &mut FlatRam) -> Vec<u8>
+    {
+        run_to(ppu, bus, (VBLANK_LINE, 1));
+        ppu.picture().to_vec()
+    }
+
+    #[test]
+    fn sprites_on_a_transparent_background()
+    {
+        let (mut ppu, mut bus) = setup();
+        // the background is tile 0, make it tile 1's empty plane
+        ppu.write(PPU_CRTL, CTRL_BG_TABLE, &mut bus);
+        ppu.write(PPU_MASK, MASK_BACKGROUND | MASK_SPRITES | MASK_BG_LEFT | MASK_SPRITES_LEFT, &mut bus);
+        set_oam(&mut ppu, &mut bus, &[
+            [10, 1, 0, 20],
+            [10, 0, ATTR_PALETTE, 24],
+        ]);
+        let picture = frame(&mut ppu, &mut bus);
+        let row = |y: usize| &picture[y * WIDTH..][..WIDTH];
+
+        // drawn a line below their y, for 8 lines
+        assert!(row(10).iter().all(|&p| p == 0x00));
+        assert!(row(19).iter().all(|&p| p == 0x00));
+        for y in 11..=18
+        {
+            // sprite 0 is colour 1 of palette 4, sprite 1 is colour
+            // 3 of palette 7 and under sprite 0 where they overlap
+            assert_eq!(&row(y)[18..34], [
+                0x00, 0x00,
+                0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+                0x1f, 0x1f, 0x1f, 0x1f,
+                0x00, 0x00,
+            ]);
+        }
+    }
+
+    #[test]
+    fn eight_sprites_a_line_and_overflow()
+    {
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_MASK, MASK_SPRITES | MASK_SPRITES_LEFT, &mut bus);
+        let sprites: Vec<[u8; 4]> = (0..9).map(|i| [50, 1, 0, i * 10]).collect();
+        set_oam(&mut ppu, &mut bus, &sprites);
+        run_to(&mut ppu, &mut bus, (50, 256));
+        assert_eq!(ppu.status() & STATUS_OVERFLOW, 0);
+        ppu.tick(&mut bus);
+        assert_ne!(ppu.status() & STATUS_OVERFLOW, 0, "set on dot 257 of the line before");
+
+        let picture = frame(&mut ppu, &mut bus);
+        let row = &picture[51 * WIDTH..][..WIDTH];
+        for i in 0..8
+        {
+            assert_eq!(row[i * 10], 0x11);
+        }
+        assert_eq!(row[80], 0x00, "the ninth sprite isn't drawn");
+
+        // cleared on the pre-render line
+        run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, 1));
+        assert_eq!(ppu.status() & STATUS_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn overflow_bug()
+    {
+        // with 8 found, the hardware goes on to the next sprite but
+        // also to its next byte. sprite 9's tile looks like a y on
+        // the line, which sets overflow when there's no ninth sprite
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_MASK, MASK_SPRITES, &mut bus);
+        let mut sprites: Vec<[u8; 4]> = (0..8).map(|i| [50, 1, 0, i * 10]).collect();
+        sprites.push([0xf0, 1, 0, 0]);
+        sprites.push([0xf0, 50, 0, 0]);
+        set_oam(&mut ppu, &mut bus, &sprites);
+        run_to(&mut ppu, &mut bus, (50, 258));
+        assert_ne!(ppu.status() & STATUS_OVERFLOW, 0);
+
+        // and the other way, a real ninth one gets missed because
+        // its tile is what's looked at
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_MASK, MASK_SPRITES, &mut bus);
+        let mut sprites: Vec<[u8; 4]> = (0..8).map(|i| [50, 1, 0, i * 10]).collect();
+        sprites.push([0xf0, 1, 0, 0]);
+        sprites.push([50, 0x80, 0, 0]);
+        set_oam(&mut ppu, &mut bus, &sprites);
+        run_to(&mut ppu, &mut bus, (50, 258));
+        assert_eq!(ppu.status() & STATUS_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn sprite_zero_hit_timing()
+    {
+        // sprite 0 at x=100 over an opaque background, on line 31
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_MASK, MASK_BACKGROUND | MASK_SPRITES, &mut bus);
+        set_oam(&mut ppu, &mut bus, &[[30, 0, 0, 100]]);
+
+        // the pixel at x goes out on dot x + 1
+        run_to(&mut ppu, &mut bus, (31, 100));
+        assert_eq!(ppu.status() & STATUS_SPRITE0, 0);
+        ppu.tick(&mut bus);
+        assert_ne!(ppu.status() & STATUS_SPRITE0, 0);
+
+        // stays set through vblank, cleared on the pre-render line
+        run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, 0));
+        assert_ne!(ppu.status() & STATUS_SPRITE0, 0);
+        ppu.tick(&mut bus);
+        assert_eq!(ppu.status() & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn no_sprite_zero_hit()
+    {
+        let hit = |mask: u8, sprite: [u8; 4]|
+        {
+            let (mut ppu, mut bus) = setup();
+            ppu.write(PPU_MASK, mask, &mut bus);
+            set_oam(&mut ppu, &mut bus, &[sprite]);
+            run_to(&mut ppu, &mut bus, (VBLANK_LINE, 0));
+            ppu.status() & STATUS_SPRITE0 != 0
+        };
+        let both = MASK_BACKGROUND | MASK_SPRITES;
+        assert!(hit(both, [30, 0, 0, 100]));
+        assert!(!hit(both, [30, 2, 0, 100]), "sprite transparent");
+        assert!(!hit(MASK_SPRITES, [30, 0, 0, 100]), "background off");
+        assert!(!hit(both, [30, 0, 0, 255]), "never on x=255");
+        assert!(!hit(both, [30, 0, 0, 0]), "left 8 pixels clipped");
+        assert!(hit(both | MASK_BG_LEFT | MASK_SPRITES_LEFT, [30, 0, 0, 0]));
+    }
+
+    #[test]
+    fn status_read_as_vblank_starts()
+    {
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_CRTL, CTRL_NMI, &mut bus);
+
+        // a dot early it reads clear, and there's no vblank or NMI at
+        // all that frame
+        run_to(&mut ppu, &mut bus, (VBLANK_LINE, 0));
+        assert_eq!(ppu.read(PPU_STATUS, &mut bus) & STATUS_VBLANK, 0);
+        while ppu.position() != (PRE_RENDER_LINE, 1)
+        {
+            ppu.tick(&mut bus);
+            assert_eq!(ppu.status() & STATUS_VBLANK, 0, "at {:?}", ppu.position());
+            assert!(!ppu.nmi(), "at {:?}", ppu.position());
+        }
+
+        // on the dot it's set, or the one after, it reads set and the
+        // NMI it started goes away with it
+        for dot in [1, 2]
+        {
+            run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, 1));
+            run_to(&mut ppu, &mut bus, (VBLANK_LINE, dot));
+            assert!(ppu.nmi());
+            assert_ne!(ppu.read(PPU_STATUS, &mut bus) & STATUS_VBLANK, 0);
+            assert_eq!(ppu.status() & STATUS_VBLANK, 0);
+            assert!(!ppu.nmi());
+        }
+    }
+
+    #[test]
+    fn nmi_enabled_during_vblank()
+    {
+        let (mut ppu, mut bus) = setup();
+        run_to(&mut ppu, &mut bus, (VBLANK_LINE + 5, 100));
+        assert!(!ppu.nmi());
+
+        // the line goes up as soon as it's enabled, and again each
+        // time it's turned back on while vblank lasts
+        ppu.write(PPU_CRTL, CTRL_NMI, &mut bus);
+        assert!(ppu.nmi());
+        ppu.write(PPU_CRTL, 0, &mut bus);
+        assert!(!ppu.nmi());
+        ppu.write(PPU_CRTL, CTRL_NMI, &mut bus);
+        assert!(ppu.nmi());
+
+        run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, 1));
+        assert!(!ppu.nmi());
+    }
+
+    // dots from the start of each of the next few frames to the next
+    fn frame_lengths(ppu: &mut PPU, bus: &mut FlatRam) -> Vec<(u64, u32)>
+    {
+        run_to(ppu, bus, (0, 0));
+        (0..4).map(|_|
+        {
+            let frame = ppu.frame();
+            let mut dots = 0;
+            loop
+            {
+                ppu.tick(bus);
+                dots += 1;
+                if ppu.position() == (0, 0)
+                {
+                    return (frame, dots);
+                }
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot()
+    {
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_MASK, MASK_BACKGROUND, &mut bus);
+        for (frame, dots) in frame_lengths(&mut ppu, &mut bus)
+        {
+            assert_eq!(dots, if frame & 1 == 1 { 89341 } else { 89342 }, "frame {}", frame);
+        }
+
+        // the dot that goes is the last one of the pre-render line
+        while ppu.frame() & 1 == 0
+        {
+            ppu.tick(&mut bus);
+        }
+        run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, DOTS_PER_LINE - 2));
+        ppu.tick(&mut bus);
+        assert_eq!(ppu.position(), (0, 0));
+
+        // and only while rendering
+        ppu.write(PPU_MASK, 0, &mut bus);
+        for (frame, dots) in frame_lengths(&mut ppu, &mut bus)
+        {
+            assert_eq!(dots, 89342, "frame {}", frame);
+        }
+    }
+
+    // both PPUADDR writes
+    fn set_addr(ppu: &mut PPU, bus: &mut FlatRam, addr: u16)
+    {
+        ppu.write(PPU_ADDR, (addr >> 8) as u8, bus);
+        ppu.write(PPU_ADDR, addr as u8, bus);
+    }
+
+    #[test]
+    fn ppudata_reads_are_a_read_behind()
+    {
+        let (mut ppu, mut bus) = setup();
+        bus.load(0x2000, &[0xab, 0xcd]);
+        bus.load(0x3f05, &[0x77]);
+
+        // the first read is whatever was in the buffer
+        set_addr(&mut ppu, &mut bus, 0x2000);
+        ppu.read(PPU_DATA, &mut bus);
+        assert_eq!(ppu.read(PPU_DATA, &mut bus), 0xab);
+        assert_eq!(ppu.read(PPU_DATA, &mut bus), 0xcd);
+
+        // the palette isn't, but the buffer still gets what's under it
+        set_addr(&mut ppu, &mut bus, 0x3f05);
+        assert_eq!(ppu.read(PPU_DATA, &mut bus), 0x05);
+        set_addr(&mut ppu, &mut bus, 0x2000);
+        assert_eq!(ppu.read(PPU_DATA, &mut bus), 0x77);
+    }
+
+    #[test]
+    fn palette_mirrors()
+    {
+        let (mut ppu, mut bus) = setup();
+        // the sprite backdrops are the background's, both ways
+        for (mirror, entry) in [(0x3f10, 0x00), (0x3f14, 0x04), (0x3f18, 0x08), (0x3f1c, 0x0c)]
+        {
+            set_addr(&mut ppu, &mut bus, mirror);
+            ppu.write(PPU_DATA, 0x20 | entry as u8, &mut bus);
+            assert_eq!(ppu.palette()[entry], 0x20 | entry as u8);
+            set_addr(&mut ppu, &mut bus, PALETTE + entry as u16);
+            assert_eq!(ppu.read(PPU_DATA, &mut bus), 0x20 | entry as u8);
+
+            set_addr(&mut ppu, &mut bus, PALETTE + entry as u16);
+            ppu.write(PPU_DATA, 0x30, &mut bus);
+            set_addr(&mut ppu, &mut bus, mirror);
+            assert_eq!(ppu.read(PPU_DATA, &mut bus), 0x30);
+        }
+
+        // the others aren't mirrored, and $3F20 on is the palette again
+        set_addr(&mut ppu, &mut bus, 0x3f11);
+        ppu.write(PPU_DATA, 0x2d, &mut bus);
+        assert_eq!(ppu.palette()[0x01], 0x01);
+        set_addr(&mut ppu, &mut bus, 0x3f31);
+        assert_eq!(ppu.read(PPU_DATA, &mut bus), 0x2d);
+    }
+
+    #[test]
+    fn ppudata_increments()
+    {
+        let (mut ppu, mut bus) = setup();
+        set_addr(&mut ppu, &mut bus, 0x2000);
+        ppu.write(PPU_DATA, 0x01, &mut bus);
+        ppu.read(PPU_DATA, &mut bus);
+        assert_eq!(ppu.vram_addr(), 0x2002);
+
+        // a row of 32 at a time
+        ppu.write(PPU_CRTL, CTRL_INCREMENT, &mut bus);
+        ppu.write(PPU_DATA, 0x02, &mut bus);
+        ppu.read(PPU_DATA, &mut bus);
+        assert_eq!(ppu.vram_addr(), 0x2042);
+        assert_eq!((bus.mem[0x2000], bus.mem[0x2002]), (0x01, 0x02));
+    }
+
+    #[test]
+    fn scroll_and_addr_share_a_toggle()
+    {
+        let (mut ppu, mut bus) = setup();
+        // the PPUSCROLL write leaves the next PPUADDR one as the second
+        ppu.write(PPU_SCROLL, 0x00, &mut bus);
+        assert!(ppu.write_toggle());
+        ppu.write(PPU_ADDR, 0x34, &mut bus);
+        assert!(!ppu.write_toggle());
+        assert_eq!(ppu.vram_addr(), 0x0034);
+
+        // and the other way round, the y half of t is fine y 6 and
+        // coarse y 11 now, over what PPUADDR put there
+        ppu.write(PPU_ADDR, 0x21, &mut bus);
+        ppu.write(PPU_SCROLL, 0x5e, &mut bus);
+        assert!(!ppu.write_toggle());
+        assert_eq!(ppu.temp_addr(), 0x6174);
+    }
+
+    #[test]
+    fn status_read_resets_the_toggle()
+    {
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_ADDR, 0x21, &mut bus);
+        ppu.read(PPU_STATUS, &mut bus);
+        assert!(!ppu.write_toggle());
+        set_addr(&mut ppu, &mut bus, 0x3f00);
+        assert_eq!(ppu.vram_addr(), 0x3f00);
+    }
+
+    #[test]
+    fn loopy_writes()
+    {
+        // the example on the nesdev wiki's scrolling page
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_CRTL, 0x00, &mut bus);
+        ppu.read(PPU_STATUS, &mut bus);
+        ppu.write(PPU_SCROLL, 0x7d, &mut bus);
+        assert_eq!((ppu.temp_addr(), ppu.fine_x()), (0x000f, 5));
+        ppu.write(PPU_SCROLL, 0x5e, &mut bus);
+        assert_eq!(ppu.temp_addr(), 0x616f);
+        ppu.write(PPU_ADDR, 0x3d, &mut bus);
+        assert_eq!(ppu.temp_addr(), 0x3d6f, "fine y's top bit is cleared");
+        ppu.write(PPU_ADDR, 0xf0, &mut bus);
+        assert_eq!((ppu.temp_addr(), ppu.vram_addr()), (0x3df0, 0x3df0));
+
+        // PPUCTRL only has the nametable, and leaves v alone
+        ppu.write(PPU_CRTL, 0x03, &mut bus);
+        assert_eq!((ppu.temp_addr(), ppu.vram_addr()), (0x3df0 | 0x0c00, 0x3df0));
+        ppu.write(PPU_CRTL, 0x01, &mut bus);
+        assert_eq!(ppu.temp_addr(), 0x3df0 & !0x0800 | 0x0400);
+    }
+
+    #[test]
+    fn coarse_x_and_y_wrap()
+    {
+        let mut ppu = PPU::new();
+        let x = |ppu: &mut PPU, v|
+        {
+            ppu.v = v;
+            ppu.increment_x();
+            ppu.v
+        };
+        assert_eq!(x(&mut ppu, 0x0005), 0x0006);
+        assert_eq!(x(&mut ppu, 0x001f), 0x0400, "into the next nametable");
+        assert_eq!(x(&mut ppu, 0x041f), 0x0000, "and back");
+        assert_eq!(x(&mut ppu, 0x73ff), 0x77e0, "y is left alone");
+
+        let y = |ppu: &mut PPU, v|
+        {
+            ppu.v = v;
+            ppu.increment_y();
+            ppu.v
+        };
+        assert_eq!(y(&mut ppu, 0x1005), 0x2005, "fine y first");
+        assert_eq!(y(&mut ppu, 0x7005), 0x0025);
+        assert_eq!(y(&mut ppu, 0x73a5), 0x0805, "row 29 goes to the nametable below");
+        assert_eq!(y(&mut ppu, 0x7ba5), 0x0005);
+        assert_eq!(y(&mut ppu, 0x73c5), 0x03e5, "the attribute rows don't switch");
+        assert_eq!(y(&mut ppu, 0x73e5), 0x0005);
+    }
+
+    #[test]
+    fn rendering_copies_t_into_v()
+    {
+        let (mut ppu, mut bus) = setup();
+        ppu.write(PPU_MASK, MASK_BACKGROUND, &mut bus);
+        ppu.write(PPU_SCROLL, 0x7d, &mut bus);
+        ppu.write(PPU_SCROLL, 0x5e, &mut bus);
+
+        // all of it before the frame starts
+        run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, 310));
+        assert_eq!(ppu.vram_addr(), 0x616f);
+
+        // a tile at a time along the line, then x back from t and y
+        // down a line. by line 10 that's 11 lines down from fine y 6,
+        // coarse y 11
+        run_to(&mut ppu, &mut bus, (10, 100));
+        assert_eq!(ppu.vram_addr() & COARSE_X, 15 + 100 / 8 + 2);
+        run_to(&mut ppu, &mut bus, (10, 260));
+        assert_eq!(ppu.vram_addr(), 0x1000 | 13 << 5 | 15);
+    }
+
+    #[test]
+    fn scrolled_background()
+    {
+        // all four nametables empty, with tile 1 in three places
+        let (mut ppu, mut bus) = setup();
+        for nametable in [0x2000, 0x2400, 0x2800, 0x2c00]
+        {
+            bus.load(nametable, &[2; 0x3c0]);
+        }
+        bus.mem[0x2000 + 3 * 32 + 5] = 1;
+        bus.mem[0x2400] = 1;
+        bus.mem[0x2800 + 5] = 1;
+
+        ppu.write(PPU_MASK, MASK_BACKGROUND | MASK_BG_LEFT, &mut bus);
+        ppu.write(PPU_SCROLL, 3, &mut bus);
+        ppu.write(PPU_SCROLL, 2, &mut bus);
+        // the scroll is only picked up on the pre-render line
+        run_to(&mut ppu, &mut bus, (PRE_RENDER_LINE, 0));
+        let picture = frame(&mut ppu, &mut bus);
+        let drawn: Vec<(usize, usize)> = (0..WIDTH * HEIGHT)
+            .filter(|&i| picture[i] != 0)
+            .map(|i| (i % WIDTH, i / WIDTH))
+            .collect();
+
+        // 3 left and 2 up: column 5 row 3 is at (37, 22), the one to
+        // the right's first tile is cut off at the right edge, and the
+        // one below's first row shows up on the last two lines
+        let mut expected = Vec::new();
+        for y in 0..HEIGHT
+        {
+            for x in 0..WIDTH
+            {
+                if (37..45).contains(&x) && (22..30).contains(&y)
+                    || (253..256).contains(&x) && y < 6
+                    || (37..45).contains(&x) && y >= 238
+                {
+                    expected.push((x, y));
+                }
+            }
+        }
+        assert_eq!(drawn, expected);
+        assert!(picture.iter().all(|&p| p == 0x00 || p == 0x01));
     }
 }
@@ -0,0 +1,317 @@
+// Counts where the cpu spends its cycles, by instruction and by
+// subroutine, going by the call stack the cpu keeps. Optionally also
+// how much of every frame went to actual work, split at the start of
+// vblank. Work is anything that isn't idling, which is a jump or
+// branch to itself, or anything in a range marked with set_idle for
+// the wait loops that are a bit longer than that.
+
+use crate::nes::cpu::{ CPU, Opcode, FrameKind };
+use crate::nes::bus::Bus;
+use crate::nes::disasm;
+use crate::nes::ppu::PPU;
+
+use std::collections::HashMap;
+use std::io::{ self, Write };
+
+const VBLANK_LINE: u16 = 241;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Routine
+{
+    pub kind: Option<FrameKind>, // None before it was ever entered
+    pub calls: u64,
+    pub own: u64,   // cycles in the routine itself
+    pub total: u64, // including everything it called
+}
+
+// work and idle cycles on each side of the start of vblank
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Budget
+{
+    pub frame: u64,
+    pub busy_before: u64,
+    pub idle_before: u64,
+    pub busy_after: u64,
+    pub idle_after: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Start
+{
+    pc: u16,
+    cycles: u64,
+    depth: usize,
+    inner: Option<u16>,
+    frame: u64,
+    line: u16,
+}
+
+#[derive(Debug)]
+pub struct Profiler
+{
+    counts: Vec<u64>, // times each address was executed
+    cycles: Vec<u64>, // and the cycles they took
+    routines: HashMap<u16, Routine>,
+    outside: u64, // cycles not in any subroutine or handler
+    total: u64,
+
+    idle: Option<(u16, u16)>,
+    budgets: Option<Vec<Budget>>,
+}
+
+impl Profiler
+{
+    pub fn new() -> Profiler
+    {
+        Profiler {
+            counts: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            routines: HashMap::new(),
+            outside: 0,
+            total: 0,
+            idle: None,
+            budgets: None,
+        }
+    }
+
+    // also keep a budget for every frame
+    pub fn with_budgets() -> Profiler
+    {
+        Profiler { budgets: Some(Vec::new()), ..Profiler::new() }
+    }
+
+    // cycles spent in start..=end count as idle in the budgets
+    pub fn set_idle(&mut self, start: u16, end: u16)
+    {
+        self.idle = Some((start, end));
+    }
+
+    pub fn budgets(&self) -> &[Budget]
+    {
+        self.budgets.as_deref().unwrap_or(&[])
+    }
+
+    pub fn routines(&self) -> impl Iterator<Item = (u16, &Routine)>
+    {
+        self.routines.iter().map(|(addr, r)| (*addr, r))
+    }
+
+    // where things were before an instruction, for record
+    pub fn start(&self, cpu: &CPU, ppu: &PPU) -> Start
+    {
+        let frames = cpu.calls().frames();
+        Start {
+            pc: cpu.pc,
+            cycles: cpu.cycles,
+            depth: frames.len(),
+            inner: frames.last().map(|f| f.target),
+            frame: ppu.frame(),
+            line: ppu.position().0,
+        }
+    }
+
+    // the instruction start was taken before has run
+    pub fn record(&mut self, start: Start, cpu: &CPU)
+    {
+        let Start { pc, depth, frame, line, .. } = start;
+        let cycles = cpu.cycles - start.cycles;
+        self.counts[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+        self.total += cycles;
+
+        // the instruction belongs to the routine it was in when it
+        // started, an RTS is still part of the one it returns from
+        match start.inner
+        {
+            Some(inner) => self.routines.entry(inner).or_default().own += cycles,
+            None => self.outside += cycles,
+        }
+
+        let frames = cpu.calls().frames();
+        let (callers, entered) = frames.split_at(depth.min(frames.len()));
+        let returned = start.inner.filter(|_| frames.len() < depth);
+        for (i, target) in callers.iter().map(|f| f.target).chain(returned).enumerate()
+        {
+            // recursion shouldn't count twice
+            if !callers[..i.min(callers.len())].iter().any(|f| f.target == target)
+            {
+                self.routines.entry(target).or_default().total += cycles;
+            }
+        }
+        for frame in entered
+        {
+            let routine = self.routines.entry(frame.target).or_default();
+            routine.kind = Some(frame.kind);
+            routine.calls += 1;
+        }
+
+        let Some(budgets) = &mut self.budgets
+        else { return };
+        if budgets.last().is_none_or(|b| b.frame != frame)
+        {
+            budgets.push(Budget { frame, ..Budget::default() });
+        }
+        let idle = cpu.pc == pc
+            || self.idle.is_some_and(|(start, end)| (start..=end).contains(&pc));
+        let budget = budgets.last_mut().unwrap();
+        match (line < VBLANK_LINE, idle)
+        {
+            (true, false) => budget.busy_before += cycles,
+            (true, true) => budget.idle_before += cycles,
+            (false, false) => budget.busy_after += cycles,
+            (false, true) => budget.idle_after += cycles,
+        }
+    }
+
+    // the hottest top instructions and routines, busiest first, then
+    // the frame budgets if there are any. the bus is for disassembly
+    pub fn report(&self, out: &mut dyn Write, top: usize, table: &[Opcode; 256],
+                  bus: &dyn Bus) -> io::Result<()>
+    {
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.total.max(1) as f64;
+
+        writeln!(out, "{} cycles", self.total)?;
+        writeln!(out, "\ninstructions:")?;
+        writeln!(out, "{:>6} {:>12} {:>12} {:>7}", "addr", "count", "cycles", "%")?;
+        let mut addrs: Vec<usize> = (0..0x10000).filter(|&a| self.counts[a] != 0).collect();
+        addrs.sort_by_key(|&a| std::cmp::Reverse(self.cycles[a]));
+        for &addr in addrs.iter().take(top)
+        {
+            let instr = disasm::decode_with(table, addr as u16, |a| bus.peek(a));
+            writeln!(out, " ${:04X} {:>12} {:>12} {:>6.2}%  {}", addr, self.counts[addr],
+                     self.cycles[addr], percent(self.cycles[addr]), instr)?;
+        }
+
+        writeln!(out, "\nroutines:")?;
+        writeln!(out, "{:>10} {:>10} {:>12} {:>7} {:>12} {:>7}",
+                 "", "calls", "own", "%", "total", "%")?;
+        let mut routines: Vec<(u16, &Routine)> = self.routines().collect();
+        routines.sort_by_key(|(_, r)| std::cmp::Reverse(r.own));
+        writeln!(out, "{:>10} {:>10} {:>12} {:>6.2}%", "outside", "", self.outside,
+                 percent(self.outside))?;
+        for (addr, r) in routines.iter().take(top)
+        {
+            let kind = match r.kind
+            {
+                Some(FrameKind::Nmi) => "NMI ",
+                Some(FrameKind::Irq) => "IRQ ",
+                Some(FrameKind::Brk) => "BRK ",
+                _ => "",
+            };
+            writeln!(out, "{:>10} {:>10} {:>12} {:>6.2}% {:>12} {:>6.2}%",
+                     format!("{}${:04X}", kind, addr), r.calls,
+                     r.own, percent(r.own), r.total, percent(r.total))?;
+        }
+
+        if let Some(budgets) = &self.budgets
+        {
+            writeln!(out, "\nframes (busy cycles / all cycles):")?;
+            writeln!(out, "{:>8} {:>17} {:>17}", "frame", "before vblank", "in vblank")?;
+            for b in budgets
+            {
+                let before = b.busy_before + b.idle_before;
+                let after = b.busy_after + b.idle_after;
+                writeln!(out, "{:>8} {:>8}/{:<8} {:>8}/{:<8}",
+                         b.frame, b.busy_before, before, b.busy_after, after)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profiler
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::nes::NES;
+    use crate::nes::{ asm, harness };
+
+    // profiles source from reset until it gets to done and goes round
+    // that loop four more times
+    fn profile(source: &str) -> NES
+    {
+        let done = asm::assemble(source).unwrap().label("done").unwrap();
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(source).unwrap());
+        nes.reset();
+        nes.profile(Profiler::new());
+        while nes.cpu().pc != done
+        {
+            nes.step().unwrap();
+        }
+        for _ in 0..4
+        {
+            nes.step().unwrap();
+        }
+        nes
+    }
+
+    fn routine(profiler: &Profiler, addr: u16) -> (Option<FrameKind>, u64, u64, u64)
+    {
+        let r = profiler.routines[&addr];
+        (r.kind, r.calls, r.own, r.total)
+    }
+
+    #[test]
+    fn nested_calls()
+    {
+        let nes = profile("
+                .org $8000
+        reset:  ldx #$ff        ; 2
+                txs             ; 2
+                jsr outer       ; 6
+        done:   jmp done        ; 3
+        outer:  lda #1          ; 2, $8009
+                jsr inner       ; 6
+                jsr inner       ; 6
+                rts             ; 6
+        inner:  nop             ; 2, $8012
+                rts             ; 6
+                .org $fffc
+                .word reset
+        ");
+        let profiler = nes.profiler().unwrap();
+
+        // a jsr counts for the routine it's in, an rts for the one it
+        // leaves, and everything inner does for outer's total too
+        assert_eq!(routine(profiler, 0x8012), (Some(FrameKind::Call), 2, 16, 16));
+        assert_eq!(routine(profiler, 0x8009), (Some(FrameKind::Call), 1, 20, 36));
+        assert_eq!(profiler.outside, 2 + 2 + 6 + 4 * 3);
+        assert_eq!(profiler.total, 22 + 36);
+
+        assert_eq!((profiler.counts[0x8012], profiler.cycles[0x8012]), (2, 4));
+        assert_eq!((profiler.counts[0x8006], profiler.cycles[0x8006]), (4, 12));
+    }
+
+    #[test]
+    fn recursion_counts_once()
+    {
+        let nes = profile("
+                .org $8000
+        reset:  ldx #$ff
+                txs
+                ldx #2
+                jsr rec
+        done:   jmp done
+        rec:    dex             ; 2, $800B
+                beq out         ; 2, or 3 when it's taken
+                jsr rec         ; 6
+        out:    rts             ; 6
+                .org $fffc
+                .word reset
+        ");
+        let profiler = nes.profiler().unwrap();
+
+        // twice round, the second time out early
+        let own = 2 + 2 + 6 + 2 + 3 + 6 + 6;
+        assert_eq!(routine(profiler, 0x800b), (Some(FrameKind::Call), 2, own, own));
+    }
+}
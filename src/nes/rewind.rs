@@ -0,0 +1,296 @@
+// A rolling history of save states for going back in time. Only the
+// newest state is kept whole, every older one is stored as the xor
+// against the one after it, run length encoded. Most of the machine
+// doesn't change from one frame to the next so those are mostly zeros
+// and pack down to almost nothing. Going back a state undoes one delta,
+// and when the budget runs out the oldest delta is simply dropped.
+
+use std::collections::VecDeque;
+use std::fmt::{ Debug, Formatter };
+
+struct Delta
+{
+    frame: u64,
+    len: usize, // of the state it turns the next one into
+    data: Vec<u8>,
+}
+
+pub struct Rewind
+{
+    interval: u64, // frames between snapshots
+    budget: usize, // bytes, for all of it together
+
+    latest: Option<(u64, Vec<u8>)>,
+    deltas: VecDeque<Delta>, // oldest first
+    used: usize,
+}
+
+impl Rewind
+{
+    pub fn new(interval: u64, budget: usize) -> Rewind
+    {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            latest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.latest = None;
+        self.deltas.clear();
+        self.used = 0;
+    }
+
+    // whether a snapshot should be taken now that we're at frame
+    pub fn due(&self, frame: u64) -> bool
+    {
+        frame.is_multiple_of(self.interval)
+            && self.latest.as_ref().is_none_or(|(last, _)| frame > *last)
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>)
+    {
+        if let Some((last, prev)) = self.latest.take()
+        {
+            let data = encode(&prev, &state);
+            self.used += data.len();
+            self.deltas.push_back(Delta { frame: last, len: prev.len(), data });
+        }
+        self.latest = Some((frame, state));
+
+        let latest = self.latest.as_ref().map_or(0, |(_, s)| s.len());
+        while self.used + latest > self.budget
+        {
+            let Some(oldest) = self.deltas.pop_front()
+            else { break };
+            self.used -= oldest.data.len();
+        }
+    }
+
+    // the newest snapshot and the frame it was taken at
+    pub fn latest(&self) -> Option<(u64, &[u8])>
+    {
+        self.latest.as_ref().map(|(frame, state)| (*frame, state.as_slice()))
+    }
+
+    // throws away the newest snapshot, the one before becomes the newest
+    pub fn pop(&mut self)
+    {
+        let Some((_, state)) = self.latest.take()
+        else { return };
+        if let Some(delta) = self.deltas.pop_back()
+        {
+            self.used -= delta.data.len();
+            self.latest = Some((delta.frame, decode(&state, &delta.data, delta.len)));
+        }
+    }
+
+    // how far back we can go, in frames from the newest snapshot
+    pub fn depth(&self) -> u64
+    {
+        match (&self.latest, self.deltas.front())
+        {
+            (Some((last, _)), Some(oldest)) => last - oldest.frame,
+            _ => 0,
+        }
+    }
+
+    // bytes in use, the uncompressed newest state included
+    pub fn used(&self) -> usize
+    {
+        self.used + self.latest.as_ref().map_or(0, |(_, s)| s.len())
+    }
+}
+
+impl Debug for Rewind
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        write!(f, "Rewind {{ snapshots: {}, used: {} }}",
+               self.deltas.len() + self.latest.is_some() as usize, self.used())
+    }
+}
+
+// old xor new, as runs of (zeros, literal count, literals). the counts
+// are LEB128 so long runs of zeros cost a couple of bytes
+fn encode(old: &[u8], new: &[u8]) -> Vec<u8>
+{
+    let len = old.len().max(new.len());
+    let byte = |i: usize| old.get(i).copied().unwrap_or(0) ^ new.get(i).copied().unwrap_or(0);
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < len
+    {
+        let start = i;
+        while i < len && byte(i) == 0
+        {
+            i += 1;
+        }
+        let zeros = i - start;
+
+        // a couple of zeros in the middle are cheaper kept as literals
+        let start = i;
+        while i < len && (byte(i) != 0 || (i + 2 < len && (byte(i + 1) != 0 || byte(i + 2) != 0)))
+        {
+            i += 1;
+        }
+        write_len(&mut out, zeros);
+        write_len(&mut out, i - start);
+        out.extend((start..i).map(byte));
+    }
+    out
+}
+
+// gets old back from new and what encode made of them
+fn decode(new: &[u8], delta: &[u8], len: usize) -> Vec<u8>
+{
+    let mut out = new.to_vec();
+    out.resize(new.len().max(len), 0);
+
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < delta.len()
+    {
+        i += read_len(delta, &mut pos);
+        let literals = read_len(delta, &mut pos);
+        for &b in &delta[pos..pos + literals]
+        {
+            out[i] ^= b;
+            i += 1;
+        }
+        pos += literals;
+    }
+    out.truncate(len);
+    out
+}
+
+fn write_len(out: &mut Vec<u8>, mut n: usize)
+{
+    while n >= 0x80
+    {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_len(data: &[u8], pos: &mut usize) -> usize
+{
+    let mut n = 0;
+    let mut shift = 0;
+    loop
+    {
+        let b = data[*pos];
+        *pos += 1;
+        n |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0
+        {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn round_trip(old: &[u8], new: &[u8]) -> Vec<u8>
+    {
+        let delta = encode(old, new);
+        assert_eq!(decode(new, &delta, old.len()), old);
+        delta
+    }
+
+    #[test]
+    fn lengths()
+    {
+        for (n, bytes) in [(0, &[0x00][..]), (127, &[0x7f]), (128, &[0x80, 0x01]),
+                           (300, &[0xac, 0x02]), (16384, &[0x80, 0x80, 0x01])]
+        {
+            let mut out = Vec::new();
+            write_len(&mut out, n);
+            assert_eq!(out, bytes, "{}", n);
+            let mut pos = 0;
+            assert_eq!(read_len(&out, &mut pos), n);
+            assert_eq!(pos, out.len());
+        }
+    }
+
+    #[test]
+    fn identical_states_are_one_run_of_zeros()
+    {
+        let state: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        assert_eq!(round_trip(&state, &state), [0xe8, 0x07, 0x00]);
+        assert_eq!(round_trip(&[], &[]), []);
+    }
+
+    #[test]
+    fn changed_runs()
+    {
+        let old = vec![0x55; 1000];
+        let mut new = old.clone();
+        new[10] = 0;
+        new[11] = 1;
+        // two zeros between changes stay in the same run
+        new[14] = 2;
+        new[500] = 3;
+        new[999] = 4;
+        let delta = round_trip(&old, &new);
+        assert_eq!(&delta[..7], [10, 5, 0x55, 0x54, 0, 0, 0x57]);
+
+        // more than 127 changes in a row, and more than 127 unchanged
+        let mut new = old.clone();
+        new[200..500].fill(0xaa);
+        let delta = round_trip(&old, &new);
+        assert_eq!(&delta[..4], [0x80 | 200 & 0x7f, 200 >> 7, 0xac, 0x02]);
+        assert_eq!(delta.len(), 4 + 300 + 3);
+    }
+
+    #[test]
+    fn states_that_change_size()
+    {
+        let old = vec![1; 100];
+        round_trip(&old, &[1; 120]);
+        round_trip(&old, &[2; 80]);
+        round_trip(&[], &old);
+    }
+
+    #[test]
+    fn pop_goes_back_through_every_snapshot()
+    {
+        let states: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 64 + i as usize]).collect();
+        let mut rewind = Rewind::new(1, 1 << 20);
+        for (frame, state) in states.iter().enumerate()
+        {
+            assert!(rewind.due(frame as u64));
+            rewind.push(frame as u64, state.clone());
+        }
+        assert_eq!(rewind.depth(), 4);
+        for (frame, state) in states.iter().enumerate().rev()
+        {
+            assert_eq!(rewind.latest(), Some((frame as u64, state.as_slice())));
+            rewind.pop();
+        }
+        assert_eq!(rewind.latest(), None);
+    }
+
+    #[test]
+    fn budget_drops_the_oldest()
+    {
+        let mut rewind = Rewind::new(1, 300);
+        for frame in 0..10
+        {
+            rewind.push(frame, vec![frame as u8; 100]);
+        }
+        assert!(rewind.used() <= 300);
+        assert_eq!(rewind.latest().map(|(frame, _)| frame), Some(9));
+        assert!(rewind.depth() < 9);
+    }
+}
@@ -0,0 +1,224 @@
+// Save states. A state is a small header and then one chunk for each
+// part of the console, tagged and sized so a loader can skip chunks it
+// doesn't know and find the ones it does in any order. Everything is
+// little endian.
+//
+// When something new needs saving, bump VERSION and have the loader
+// check r.version() before reading it, filling in a default for states
+// older than that. Old states must keep loading.
+
+use std::collections::HashMap;
+use std::io;
+
+const MAGIC: &[u8; 4] = b"SNTW";
+
+// version 1: cpu, ppu registers and timing, ram, vram, cart
+// version 2: the ppu's internal state behind the registers, OAM, palette
+// version 3: the ppu's v, t and fine x instead of an address and scroll
+// version 4: the ppu's background pipeline
+// version 5: the sprites the ppu found for the next line
+// version 6: the last picture the ppu finished
+pub const VERSION: u16 = 6;
+
+#[derive(Debug)]
+pub enum Error
+{
+    Io(io::Error),
+    NotASaveState,
+    TooNew(u16),         // written by a newer version than this one
+    Truncated,
+    MissingChunk(&'static str),
+    BadValue(&'static str),
+    WrongCart,           // saved with another cart, or none, in
+}
+
+impl From<io::Error> for Error
+{
+    fn from(e: io::Error) -> Self
+    {
+        Error::Io(e)
+    }
+}
+
+#[derive(Default)]
+pub struct Writer
+{
+    data: Vec<u8>,
+}
+
+impl Writer
+{
+    pub fn new() -> Writer
+    {
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w
+    }
+
+    pub fn finish(self) -> Vec<u8>
+    {
+        self.data
+    }
+
+    // everything fill writes ends up in the chunk
+    pub fn chunk(&mut self, tag: &[u8; 4], fill: impl FnOnce(&mut Writer))
+    {
+        self.bytes(tag);
+        let start = self.data.len();
+        self.u32(0);
+        fill(self);
+        let len = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, v: u8)
+    {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool)
+    {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16)
+    {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32)
+    {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64)
+    {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8])
+    {
+        self.data.extend_from_slice(v);
+    }
+}
+
+// a state split into its chunks
+pub struct State<'a>
+{
+    version: u16,
+    chunks: HashMap<[u8; 4], &'a [u8]>,
+}
+
+impl<'a> State<'a>
+{
+    pub fn parse(data: &'a [u8]) -> Result<State<'a>, Error>
+    {
+        let mut r = Reader { data, pos: 0, version: 0 };
+        if r.bytes(4).ok() != Some(MAGIC.as_slice())
+        {
+            return Err(Error::NotASaveState);
+        }
+        let version = r.u16()?;
+        if version > VERSION
+        {
+            return Err(Error::TooNew(version));
+        }
+
+        let mut chunks = HashMap::new();
+        while r.pos < data.len()
+        {
+            let tag = r.array()?;
+            let len = r.u32()? as usize;
+            chunks.insert(tag, r.bytes(len)?);
+        }
+        Ok(State { version, chunks })
+    }
+
+    pub fn version(&self) -> u16
+    {
+        self.version
+    }
+
+    pub fn has(&self, tag: &[u8; 4]) -> bool
+    {
+        self.chunks.contains_key(tag)
+    }
+
+    pub fn chunk(&self, tag: &'static [u8; 4]) -> Result<Reader<'a>, Error>
+    {
+        let data = self.chunks.get(tag)
+            .ok_or(Error::MissingChunk(std::str::from_utf8(tag).unwrap_or("?")))?;
+        Ok(Reader { data, pos: 0, version: self.version })
+    }
+}
+
+pub struct Reader<'a>
+{
+    data: &'a [u8],
+    pos: usize,
+    version: u16,
+}
+
+impl<'a> Reader<'a>
+{
+    // the version the state was written by
+    pub fn version(&self) -> u16
+    {
+        self.version
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error>
+    {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error>
+    {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error>
+    {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error>
+    {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error>
+    {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], Error>
+    {
+        let mut out = [0; N];
+        out.copy_from_slice(self.bytes(N)?);
+        Ok(out)
+    }
+
+    // nothing left to read
+    pub fn is_empty(&self) -> bool
+    {
+        self.pos == self.data.len()
+    }
+
+    // a reader for just the next len bytes, for a part that has its
+    // own length in front of it
+    pub fn sub(&mut self, len: usize) -> Result<Reader<'a>, Error>
+    {
+        let data = self.bytes(len)?;
+        Ok(Reader { data, pos: 0, version: self.version })
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error>
+    {
+        let data = self.data.get(self.pos..self.pos + len)
+            .ok_or(Error::Truncated)?;
+        self.pos += len;
+        Ok(data)
+    }
+}
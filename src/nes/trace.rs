@@ -0,0 +1,265 @@
+// Execution trace in the same format as nestest.log, one line per
+// instruction, so a run can be diffed against the reference logs.
+// the line is written before the instruction executes
+
+use crate::nes::cpu::{ CPU, AddrMode };
+use crate::nes::bus::Bus;
+use crate::nes::disasm::{ self, Instruction };
+use crate::nes::ppu::PPU;
+
+use std::io::{ self, Write };
+use std::fmt::{ Debug, Formatter };
+
+pub struct Tracer
+{
+    sink: Box<dyn Write>,
+    pub enabled: bool,
+}
+
+impl Tracer
+{
+    pub fn new(sink: Box<dyn Write>) -> Tracer
+    {
+        Tracer {
+            sink,
+            enabled: true,
+        }
+    }
+
+    pub fn trace(&mut self, cpu: &CPU, bus: &dyn Bus, ppu: &PPU) -> io::Result<()>
+    {
+        if !self.enabled
+        {
+            return Ok(());
+        }
+
+        writeln!(self.sink, "{}", line(cpu, bus, ppu))
+    }
+}
+
+impl Debug for Tracer
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error>
+    {
+        write!(f, "Tracer {{ enabled: {} }}", self.enabled)
+    }
+}
+
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub fn line(cpu: &CPU, bus: &dyn Bus, ppu: &PPU) -> String
+{
+    let instr = disasm::decode_with(cpu.variant().opcodes(), cpu.pc, |addr| bus.peek(addr));
+
+    let bytes = instr.bytes.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let text = format!("{}{}", instr, annotation(cpu, bus, &instr));
+
+    let (scanline, dot) = ppu.position();
+
+    format!("{:04X}  {:<9}{}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+            cpu.pc, bytes,
+            if instr.official { ' ' } else { '*' },
+            text,
+            cpu.a, cpu.x, cpu.y, cpu.p, cpu.s,
+            scanline, dot, cpu.cycles)
+}
+
+// where the operand points and what's there
+fn annotation(cpu: &CPU, bus: &dyn Bus, instr: &Instruction) -> String
+{
+    let value = instr.operand();
+
+    // pointers don't carry into the high byte
+    let peek_word = |addr: u16|
+    {
+        let hi_addr = (addr & 0xff00) | (addr as u8).wrapping_add(1) as u16;
+        bus.peek(addr) as u16 | (bus.peek(hi_addr) as u16) << 8
+    };
+
+    match instr.mode
+    {
+        AddrMode::Zpg =>
+            format!(" = {:02X}", bus.peek(value)),
+
+        AddrMode::ZpgX =>
+        {
+            let addr = (value as u8).wrapping_add(cpu.x);
+            format!(" @ {:02X} = {:02X}", addr, bus.peek(addr as u16))
+        }
+
+        AddrMode::ZpgY =>
+        {
+            let addr = (value as u8).wrapping_add(cpu.y);
+            format!(" @ {:02X} = {:02X}", addr, bus.peek(addr as u16))
+        }
+
+        AddrMode::Abs if instr.target().is_none() =>
+            format!(" = {:02X}", bus.peek(value)),
+
+        AddrMode::AbsX =>
+        {
+            let addr = value.wrapping_add(cpu.x as u16);
+            format!(" @ {:04X} = {:02X}", addr, bus.peek(addr))
+        }
+
+        AddrMode::AbsY =>
+        {
+            let addr = value.wrapping_add(cpu.y as u16);
+            format!(" @ {:04X} = {:02X}", addr, bus.peek(addr))
+        }
+
+        AddrMode::Ind =>
+            format!(" = {:04X}", peek_word(value)),
+
+        AddrMode::IndX =>
+        {
+            let ptr = (value as u8).wrapping_add(cpu.x);
+            let addr = peek_word(ptr as u16);
+            format!(" @ {:02X} = {:04X} = {:02X}", ptr, addr, bus.peek(addr))
+        }
+
+        AddrMode::IndY =>
+        {
+            let base = peek_word(value);
+            let addr = base.wrapping_add(cpu.y as u16);
+            format!(" = {:04X} @ {:04X} = {:02X}", base, addr, bus.peek(addr))
+        }
+
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::nes::NES;
+    use crate::nes::harness;
+
+    // the lines source gives, stepping from reset
+    fn trace(source: &str, pokes: &[(u16, u8)], count: usize) -> Vec<String>
+    {
+        let mut nes = NES::new();
+        nes.load_cart(harness::nrom(source).unwrap());
+        nes.reset();
+        for &(addr, data) in pokes
+        {
+            assert!(nes.poke(addr, data));
+        }
+        (0..count).map(|_|
+        {
+            let line = nes.trace_line();
+            nes.step().unwrap();
+            line
+        }).collect()
+    }
+
+    #[test]
+    fn nestest_start()
+    {
+        // the code nestest starts with, at the same addresses
+        let lines = trace("
+                .org $c000
+        reset:  jmp $c5f5
+                .org $c5f5
+                ldx #$00
+                stx $00
+                stx $10
+                stx $11
+                jsr $c72d
+                .org $c72d
+                nop
+                sec
+                bcs $c735
+                .org $c735
+                nop
+                .org $fffc
+                .word reset
+        ", &[], 10);
+
+        // the first lines of nestest.log
+        assert_eq!(lines, [
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+            "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",
+            "C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15",
+            "C5FB  86 11     STX $11 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18",
+            "C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21",
+            "C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 81 CYC:27",
+            "C72E  38        SEC                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 87 CYC:29",
+            "C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 93 CYC:31",
+            "C735  EA        NOP                             A:00 X:00 Y:00 P:27 SP:FB PPU:  0,102 CYC:34",
+        ]);
+    }
+
+    #[test]
+    fn annotations()
+    {
+        // $10 and $FF/$00 point at $0300, $02FF/$0200 at $8020
+        let pokes = [(0x00, 0x03), (0xff, 0x00), (0x10, 0x00), (0x11, 0x03), (0x12, 0x02),
+                     (0x0300, 0x11), (0x0303, 0x44), (0x02ff, 0x20), (0x0200, 0x80)];
+        let lines = trace("
+                .org $8000
+        reset:  ldx #$02
+                ldy #$03
+                lda $10
+                lda $0e,x
+                lda $fe,x
+                ldx $0f,y
+                lda $02fe,x
+                lda $02fd,y
+                lda ($0e,x)
+                lda ($10),y
+                lda ($ff),y
+                sta $0400
+                lax $10
+                jmp ($02ff)
+                .org $8020
+        done:   jmp done
+                .org $fffc
+                .word reset
+        ", &pokes, 16);
+
+        // up to where the registers start
+        let text: Vec<&str> = lines.iter().map(|line| line[..48].trim_end()).collect();
+        assert_eq!(text, [
+            "8000  A2 02     LDX #$02",
+            "8002  A0 03     LDY #$03",
+            "8004  A5 10     LDA $10 = 00",
+            "8006  B5 0E     LDA $0E,X @ 10 = 00",
+            "8008  B5 FE     LDA $FE,X @ 00 = 03",
+            "800A  B6 0F     LDX $0F,Y @ 12 = 02",
+            "800C  BD FE 02  LDA $02FE,X @ 0300 = 11",
+            "800F  B9 FD 02  LDA $02FD,Y @ 0300 = 11",
+            "8012  A1 0E     LDA ($0E,X) @ 10 = 0300 = 11",
+            "8014  B1 10     LDA ($10),Y = 0300 @ 0303 = 44",
+            "8016  B1 FF     LDA ($FF),Y = 0300 @ 0303 = 44",
+            "8018  8D 00 04  STA $0400 = 00",
+            "801B  A7 10    *LAX $10 = 00",
+            "801D  6C FF 02  JMP ($02FF) = 8020",
+            "8020  4C 20 80  JMP $8020",
+            "8020  4C 20 80  JMP $8020",
+        ]);
+    }
+
+    #[test]
+    fn ppu_column_goes_down_the_lines()
+    {
+        // three dots a cycle from 0 at power on, 341 to a line
+        let lines = trace("
+                .org $8000
+        reset:  nop
+                jmp reset
+                .org $fffc
+                .word reset
+        ", &[], 200);
+        for line in lines
+        {
+            let cycles: u64 = line.split("CYC:").nth(1).unwrap().parse().unwrap();
+            let ppu = format!("PPU:{:3},{:3}", cycles * 3 / 341, cycles * 3 % 341);
+            assert!(line.contains(&ppu), "{} in {}", ppu, line);
+        }
+    }
+}